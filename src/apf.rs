@@ -1,13 +1,15 @@
-use crate::pages::page_alloc_overcommit;
-use crate::defines::{parse_usize};
+use crate::pages::{page_alloc_overcommit, page_free_overcommit};
+use crate::defines::{page_ceiling, parse_usize};
 use core::{mem::size_of, ptr::null_mut};
 use core::cmp::{min, max};
 use crate::{log_debug, PAGE};
 use c2rust_bitfields::BitfieldStruct;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 const RS_CHUNK: usize = (1 as usize) << 15;
 const RS_SIZE: usize = RS_CHUNK * size_of::<Reuse>();
 const BOOST_LENGTH: u32 = 20000;
+// compile time defaults, R3MALLOC_CONF can override them at startup
 // default target apf is 1000
 const TARGET_APF: u32 = match option_env!("TARGET_APF") {
 	Some(apf) => parse_usize(apf) as u32,
@@ -22,6 +24,37 @@ const NUM_FREE_INTERVALS: u32 = match option_env!("NUM_FREE_INTERVALS") {
 	None => 250,
 };
 
+// target apf given to size classes of threads that initialize after it is changed
+static DEFAULT_TARGET_APF: AtomicU32 = AtomicU32::new(TARGET_APF);
+static COMPUTE_INTERVAL: AtomicU32 = AtomicU32::new(REUSE_COMPUTE_INTERVAL);
+// fixed for each thread when its size classes are initialized, like the reuse table size
+static FREE_INTERVALS: AtomicU32 = AtomicU32::new(NUM_FREE_INTERVALS);
+
+pub fn get_default_target_apf() -> u32 {
+	DEFAULT_TARGET_APF.load(Ordering::Relaxed)
+}
+
+pub fn set_default_target_apf(apf: u32) {
+	DEFAULT_TARGET_APF.store(apf, Ordering::Relaxed)
+}
+
+pub fn get_reuse_compute_interval() -> u32 {
+	COMPUTE_INTERVAL.load(Ordering::Relaxed)
+}
+
+pub fn set_reuse_compute_interval(interval: u32) {
+	COMPUTE_INTERVAL.store(interval, Ordering::Relaxed)
+}
+
+pub fn get_num_free_intervals() -> u32 {
+	FREE_INTERVALS.load(Ordering::Relaxed)
+}
+
+// must not be 0
+pub fn set_num_free_intervals(num: u32) {
+	FREE_INTERVALS.store(num, Ordering::Relaxed)
+}
+
 #[derive(Debug, Clone, Copy, BitfieldStruct)]
 pub struct Xyz {
 	#[bitfield(name = "init", ty = "bool", bits = "0..=0")]
@@ -47,6 +80,9 @@ struct Reuse {
 	is_hibernating: bool,
 	num_frees: u32,
 	num_events: u32,
+	num_free_intervals: u32,
+	// entries in all_reuses
+	table_len: u32,
 }
 
 impl Reuse {
@@ -60,13 +96,18 @@ impl Reuse {
 			is_hibernating: false,
 			num_frees: 0,
 			num_events: 0,
+			num_free_intervals: NUM_FREE_INTERVALS,
+			table_len: TARGET_APF,
 		}
 	}
 
 	pub fn init(&mut self) {
+		self.num_free_intervals = get_num_free_intervals();
+		self.table_len = get_default_target_apf();
+
 		unsafe {
 			//self.free_intervals = page_alloc_overcommit::<(usize, usize)>(RS_SIZE);
-			let f_sz = (NUM_FREE_INTERVALS as usize * size_of::<(u32, u32)>()) as f64 / (PAGE as f64);
+			let f_sz = (self.num_free_intervals as usize * size_of::<(u32, u32)>()) as f64 / (PAGE as f64);
 			// dumb replacement for f64 ceil(), which the rust linker does not like for some reason!
 			if (f_sz as u64) as f64 == f_sz {
 				self.free_intervals = page_alloc_overcommit::<(u32, u32)>(f_sz as usize * PAGE);
@@ -74,7 +115,7 @@ impl Reuse {
 				self.free_intervals = page_alloc_overcommit::<(u32, u32)>((f_sz as usize + 1) * PAGE);
 			}
 
-			let r_sz = (TARGET_APF as usize * size_of::<Xyz>()) as f64 / (PAGE as f64);
+			let r_sz = (self.table_len as usize * size_of::<Xyz>()) as f64 / (PAGE as f64);
 			// dumb replacement for f64 ceil(), which the rust linker does not like for some reason!
 			if (r_sz as u64) as f64 == r_sz {
 				self.all_reuses = page_alloc_overcommit::<Xyz>(r_sz as usize * PAGE);
@@ -84,6 +125,23 @@ impl Reuse {
 		}
 	}
 
+	// unmaps the tables, init has to run again before the next use
+	pub fn release(&mut self) {
+		unsafe {
+			if !self.free_intervals.is_null() {
+				let f_sz = page_ceiling(self.num_free_intervals as usize * size_of::<(u32, u32)>());
+				page_free_overcommit(self.free_intervals as *mut u8, f_sz);
+				self.free_intervals = null_mut();
+			}
+
+			if !self.all_reuses.is_null() {
+				let r_sz = page_ceiling(self.table_len as usize * size_of::<Xyz>());
+				page_free_overcommit(self.all_reuses as *mut u8, r_sz);
+				self.all_reuses = null_mut();
+			}
+		}
+	}
+
 	pub fn get_time(&self) -> u32 {
 		self.current_time
 	}
@@ -98,11 +156,11 @@ impl Reuse {
 
 			if (*interval).0 != 0 {
 				(*interval).1 = self.current_time;
-				self.num_intervals = (self.num_intervals + 1) % NUM_FREE_INTERVALS;
+				self.num_intervals = (self.num_intervals + 1) % self.num_free_intervals;
 			}
 		}
 
-		self.num_events = (self.num_events + 1) % NUM_FREE_INTERVALS;
+		self.num_events = (self.num_events + 1) % self.num_free_intervals;
 	}
 
 	pub fn on_free(&mut self) {
@@ -112,9 +170,9 @@ impl Reuse {
 
 		log_debug!("num_frees", self.num_frees);
 		unsafe { (*self.free_intervals.add(self.num_frees as usize)).0 = self.current_time; }
-		self.num_frees = (self.num_frees + 1) % NUM_FREE_INTERVALS;
+		self.num_frees = (self.num_frees + 1) % self.num_free_intervals;
 
-		self.num_events = (self.num_events + 1) % NUM_FREE_INTERVALS;
+		self.num_events = (self.num_events + 1) % self.num_free_intervals;
 	}
 
 	pub fn inc_timer(&mut self) {
@@ -133,7 +191,7 @@ impl Reuse {
 			self.current_time = 0;
 
 			unsafe {
-				for i in 0..(NUM_FREE_INTERVALS as usize + 1) {
+				for i in 0..(self.num_free_intervals as usize + 1) {
 					*self.free_intervals.add(i) = (0, 0);
 				}
 			}
@@ -152,15 +210,13 @@ impl Reuse {
 		for i in (0..self.num_intervals as usize).rev() {
 			let interval = unsafe { *self.free_intervals.add(i) };
 			if interval.1 >= interval.0 && interval.1 - interval.0 < wl {
-				unsafe {
-					x = x.unchecked_add(min(self.num_events as i64 - wl as i64, interval.0 as i64) as u64);
-					y = y.unchecked_add(max(wl, interval.1) as u64);
-					z = z.unchecked_add(wl as u64);
-				}
+				x = x.wrapping_add(min(self.num_events as i64 - wl as i64, interval.0 as i64) as u64);
+				y = y.wrapping_add(max(wl, interval.1) as u64);
+				z = z.wrapping_add(wl as u64);
 			}
 		}
 
-		if wl < TARGET_APF {
+		if wl < self.table_len {
 			let mut xyz = Xyz::new();
 			xyz.set_init(true); xyz.set_x(x as u32); xyz.set_y(y as u32); xyz.set_z(z as u32);
 			unsafe { *self.all_reuses.add(wl as usize) = xyz; }
@@ -171,10 +227,11 @@ impl Reuse {
 
 	#[inline(always)]
 	fn compute_fast(&mut self, wl: u32) -> f64 {
-		let lower_bound = if wl <= REUSE_COMPUTE_INTERVAL {
+		let interval = get_reuse_compute_interval();
+		let lower_bound = if wl <= interval {
 			0
 		} else {
-			wl - REUSE_COMPUTE_INTERVAL
+			wl - interval
 		};
 		let mut reuse = Xyz::new();
 		let mut lowest_computed = lower_bound;
@@ -218,23 +275,21 @@ impl Reuse {
 				for i in 0..self.num_intervals as usize {
 					let interval = unsafe { *self.free_intervals.add(i) };
 
-					unsafe {
-						if interval.1 >= interval.0 && interval.1 - interval.0 + 1 == r {
-							x = x.unchecked_add(min((self.num_events as i32 - r as i32) as u32, interval.0) as u64);
-							y = y.unchecked_add(max(r, interval.1) as u64);
-							z = z.unchecked_add(r as u64);
-						}
-
-						if interval.0 as i64 >= self.num_events as i64 - (r as i64 - 1) {
-							x = x.unchecked_add(1);
-						}
-						if interval.1 <= r - 1 {
-							y = y.unchecked_add(1);
-							}
-
-						if interval.1 >= interval.0 && interval.1 - interval.0 < r - 1 {
-							z = z.unchecked_add(1);
-						}
+					if interval.1 >= interval.0 && interval.1 - interval.0 + 1 == r {
+						x = x.wrapping_add(min((self.num_events as i32 - r as i32) as u32, interval.0) as u64);
+						y = y.wrapping_add(max(r, interval.1) as u64);
+						z = z.wrapping_add(r as u64);
+					}
+
+					if interval.0 as i64 >= self.num_events as i64 - (r as i64 - 1) {
+						x = x.wrapping_add(1);
+					}
+					if interval.1 <= r - 1 {
+						y = y.wrapping_add(1);
+					}
+
+					if interval.1 >= interval.0 && interval.1 - interval.0 < r - 1 {
+						z = z.wrapping_add(1);
 					}
 				}
 			}
@@ -251,7 +306,7 @@ impl Reuse {
 	}
 
 	pub fn compute(&mut self, wl: u32) -> f64 {
-		if wl >= TARGET_APF {
+		if wl >= self.table_len {
 			self.compute_slow(wl)
 		} else {
 			self.compute_fast(wl)
@@ -274,6 +329,11 @@ impl Apf {
 
 	pub fn init(&mut self) {
 		self.reuse.init();
+		self.target_apf = get_default_target_apf();
+	}
+
+	pub fn release(&mut self) {
+		self.reuse.release();
 	}
 
 	pub fn on_allocation(&mut self) {
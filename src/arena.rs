@@ -0,0 +1,103 @@
+use crate::defines::page_ceiling;
+use crate::heap::ProcHeap;
+use crate::lock::SpinLock;
+use crate::numa;
+use crate::pages::page_alloc;
+use crate::r3malloc::{flush_thread_cache, HEAPS};
+use crate::size_classes::MAX_SZ_IDX;
+use core::mem::size_of;
+use core::ptr::{addr_of, null_mut};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// An arena is a separate set of heaps, so threads using different arenas never share
+// partial superblocks. Arena 0 is HEAPS, the others are mapped when created and live
+// as long as the process. Large blocks and the page heap are shared by all arenas.
+pub const MAX_ARENAS: usize = 256;
+
+const ARENA_SZ: usize = page_ceiling(size_of::<[ProcHeap; MAX_SZ_IDX]>());
+
+static ARENAS: [AtomicPtr<ProcHeap>; MAX_ARENAS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_ARENAS];
+static NARENAS: AtomicUsize = AtomicUsize::new(1);
+pub static CREATE_LOCK: SpinLock = SpinLock::new();
+
+// arena the calling thread was bound to, otherwise it follows its NUMA node
+const UNBOUND: usize = usize::MAX;
+
+#[thread_local]
+static mut THREAD_ARENA: usize = UNBOUND;
+
+pub fn narenas() -> usize {
+    NARENAS.load(Ordering::Acquire)
+}
+
+pub fn is_valid(arena: usize) -> bool {
+    arena < narenas()
+}
+
+// Returns the index of the new arena, None when there are MAX_ARENAS already.
+pub fn create() -> Option<usize> {
+    let _guard = CREATE_LOCK.lock();
+
+    let arena = NARENAS.load(Ordering::Relaxed);
+    if arena == MAX_ARENAS {
+        return None;
+    }
+
+    let heaps = unsafe { page_alloc::<ProcHeap>(ARENA_SZ) };
+    if heaps.is_null() {
+        return None;
+    }
+
+    for sc_idx in 0..MAX_SZ_IDX {
+        unsafe {
+            let heap = heaps.add(sc_idx);
+            heap.write(ProcHeap::const_new(sc_idx));
+            (*heap).set_arena_idx(arena);
+        }
+    }
+
+    ARENAS[arena].store(heaps, Ordering::Release);
+    NARENAS.store(arena + 1, Ordering::Release);
+    Some(arena)
+}
+
+// `arena` must be valid
+#[inline(always)]
+pub fn heap(arena: usize, sc_idx: usize) -> &'static ProcHeap<'static> {
+    if arena == 0 {
+        return unsafe { &(*addr_of!(HEAPS))[sc_idx] };
+    }
+
+    unsafe { &*ARENAS[arena].load(Ordering::Acquire).add(sc_idx) }
+}
+
+// arena that fills the calling thread's cache
+#[inline(always)]
+pub fn thread_arena() -> usize {
+    unsafe {
+        if THREAD_ARENA == UNBOUND {
+            return numa::thread_arena();
+        }
+        THREAD_ARENA
+    }
+}
+
+// true once the calling thread was bound to an arena
+#[inline(always)]
+pub fn is_bound() -> bool {
+    unsafe { THREAD_ARENA != UNBOUND }
+}
+
+// The thread cache holds blocks of the old arena, it is flushed so they go back there.
+pub fn set_thread_arena(arena: usize) -> bool {
+    if !is_valid(arena) {
+        return false;
+    }
+
+    if arena != thread_arena() {
+        flush_thread_cache();
+    }
+    unsafe { THREAD_ARENA = arena };
+    true
+}
@@ -0,0 +1,65 @@
+use crate::defines::PAGE_MASK;
+use crate::heap::Descriptor;
+use crate::pagemap::{PageInfo, SPAGEMAP};
+use core::ptr::addr_of;
+use libc_print::libc_eprintln;
+
+// Checks for frees of pointers that aren't live blocks. Going on after one would corrupt
+// a superblock's anchor or free list, so each is reported and aborts. Descriptors of small
+// superblocks keep a bitmap of the blocks handed out to catch double frees.
+
+fn abort() -> ! {
+    unsafe { libc::abort() }
+}
+
+// rejects pointers without a descriptor and large ones other than the block start
+pub fn check_page(ptr: *mut u8, info: PageInfo) {
+    let desc = info.get_desc();
+    if desc.is_null() {
+        libc_eprintln!("r3malloc: free of {:p}: not allocated by r3malloc or already freed", ptr);
+        abort();
+    }
+
+    // over-aligned large blocks start on a page registered for them
+    let superblock = unsafe { (*desc).get_superblock() };
+    if info.get_sc_idx() == 0 && ptr != superblock && ptr as usize & PAGE_MASK != 0 {
+        libc_eprintln!("r3malloc: free of {:p}: not the start of large block {:p}", ptr, superblock);
+        abort();
+    }
+}
+
+// descriptor of the small block holding ptr, the block's index and ptr's offset into it
+fn locate(ptr: *mut u8) -> (&'static Descriptor<'static>, usize, usize) {
+    let desc = unsafe { &*(*addr_of!(SPAGEMAP)).get_page_info(ptr).get_desc() };
+    let block_size = desc.get_block_size() as usize;
+    let pos = ptr as usize - desc.get_superblock() as usize;
+
+    (desc, pos / block_size, pos % block_size)
+}
+
+pub fn on_alloc(block: *mut u8) {
+    let (desc, idx, _) = locate(block);
+    let was_allocated = desc.mark_allocated(idx, true);
+    debug_assert!(!was_allocated, "block {:p} handed out twice", block);
+}
+
+pub fn on_free(ptr: *mut u8, sc_idx: usize) {
+    let (desc, idx, _offset) = locate(ptr);
+
+    // with redzones ptr is past the block start, and redzone::disarm checks by how much
+    #[cfg(not(feature = "redzone"))]
+    if _offset != 0 {
+        libc_eprintln!(
+            "r3malloc: free of {:p}: not the start of a block (size class {}, block size {})",
+            ptr,
+            sc_idx,
+            desc.get_block_size()
+        );
+        abort();
+    }
+
+    if !desc.mark_allocated(idx, false) {
+        libc_eprintln!("r3malloc: double free of {:p} (size class {})", ptr, sc_idx);
+        abort();
+    }
+}
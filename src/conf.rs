@@ -0,0 +1,221 @@
+use crate::apf::{
+    get_default_target_apf, get_num_free_intervals, get_reuse_compute_interval,
+    set_default_target_apf, set_num_free_intervals, set_reuse_compute_interval,
+};
+use crate::log::{
+    log_enabled, save_log_enabled, save_period, set_log_enabled, set_save_log_enabled,
+    set_save_period,
+};
+use crate::pages::{
+    get_huge_pages, get_huge_superblocks, get_huge_threshold, set_huge_pages,
+    set_huge_superblocks, set_huge_threshold, HugePages,
+};
+use crate::junk::{self, Junk};
+use crate::leak;
+use crate::numa;
+use crate::percpu;
+#[cfg(feature = "profiling")]
+use crate::prof;
+use crate::purge::{self, PurgeMode};
+use crate::quarantine;
+use core::ffi::CStr;
+use core::fmt::Write;
+use libc::c_char;
+use libc_print::libc_eprintln;
+
+// Startup options, jemalloc MALLOC_CONF style: "key:value,key:value". They are read once by
+// init_malloc, first from a `r3malloc_conf` string the application may define and then from
+// the R3MALLOC_CONF environment variable, so the environment wins. The allocator isn't usable
+// yet, so nothing here allocates.
+const CONF_ENV: &[u8] = b"R3MALLOC_CONF\0";
+
+extern "C" {
+    // null unless the application defines it
+    #[linkage = "extern_weak"]
+    static r3malloc_conf: *const *const c_char;
+}
+
+enum ConfError {
+    UnknownKey,
+    BadValue,
+}
+
+fn parse_num(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+
+    let mut out: usize = 0;
+    for &b in s {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        out = out.checked_mul(10)?.checked_add((b - b'0') as usize)?;
+    }
+
+    Some(out)
+}
+
+fn parse_u32(s: &[u8]) -> Option<u32> {
+    parse_num(s).and_then(|n| u32::try_from(n).ok())
+}
+
+fn parse_isize(s: &[u8]) -> Option<isize> {
+    match s {
+        [b'-', rest @ ..] => parse_num(rest).and_then(|n| isize::try_from(n).ok()).map(|n| -n),
+        _ => parse_num(s).and_then(|n| isize::try_from(n).ok()),
+    }
+}
+
+fn parse_bool(s: &[u8]) -> Option<bool> {
+    match s {
+        b"true" => Some(true),
+        b"false" => Some(false),
+        _ => None,
+    }
+}
+
+fn set(key: &[u8], value: &[u8]) -> Result<(), ConfError> {
+    let ok = match key {
+        b"target_apf" => parse_u32(value).map(set_default_target_apf).is_some(),
+        b"reuse_compute_interval" => parse_u32(value).map(set_reuse_compute_interval).is_some(),
+        b"num_free_intervals" => match parse_u32(value) {
+            Some(num) if num > 0 => {
+                set_num_free_intervals(num);
+                true
+            }
+            _ => false,
+        },
+        b"log" => parse_bool(value).map(set_log_enabled).is_some(),
+        b"save_log" => parse_bool(value).map(set_save_log_enabled).is_some(),
+        b"save_period" => parse_num(value).map(set_save_period).is_some(),
+        b"huge_mode" => HugePages::from_name(value).map(set_huge_pages).is_some(),
+        b"huge_threshold" => parse_num(value).map(set_huge_threshold).is_some(),
+        // no superblock exists yet, so this can't be refused
+        b"huge_superblocks" => parse_bool(value).map(set_huge_superblocks).is_some(),
+        b"purge_decay_ms" => parse_isize(value).map(purge::set_decay_ms).is_some(),
+        b"purge_mode" => PurgeMode::from_name(value).map(purge::set_mode).is_some(),
+        b"purge_max_retained" => parse_num(value).map(purge::set_max_retained).is_some(),
+        // the thread starts at the first free of a large block
+        b"purge_background" => parse_bool(value).map(purge::set_background).is_some(),
+        b"junk" => Junk::from_name(value).map(junk::set).is_some(),
+        b"quarantine" => parse_num(value).map(quarantine::set_max_bytes).is_some(),
+        b"leak_report" => parse_bool(value).map(leak::set_enabled).is_some(),
+        b"numa" => parse_bool(value).map(numa::set_enabled).is_some(),
+        b"numa_nodes" => parse_num(value).map(numa::set_nodes_opt).is_some(),
+        b"percpu_cache" => parse_bool(value).map(percpu::set_enabled).is_some(),
+        #[cfg(feature = "profiling")]
+        b"prof_active" => parse_bool(value).map(prof::set_active).is_some(),
+        #[cfg(feature = "profiling")]
+        b"prof_final" => parse_bool(value).map(prof::set_final).is_some(),
+        #[cfg(feature = "profiling")]
+        b"prof_sample_interval" => parse_num(value).map(prof::set_interval).is_some(),
+        _ => return Err(ConfError::UnknownKey),
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(ConfError::BadValue)
+    }
+}
+
+fn show(s: &[u8]) -> &str {
+    core::str::from_utf8(s).unwrap_or("?")
+}
+
+// bad entries are reported and skipped, the rest still apply
+fn apply(conf: &[u8], source: &str) {
+    for opt in conf.split(|&b| b == b',') {
+        if opt.is_empty() {
+            continue;
+        }
+
+        let Some(sep) = opt.iter().position(|&b| b == b':') else {
+            libc_eprintln!("r3malloc: {}: missing value for \"{}\"", source, show(opt));
+            continue;
+        };
+
+        let (key, value) = (&opt[..sep], &opt[sep + 1..]);
+        match set(key, value) {
+            Ok(()) => (),
+            Err(ConfError::UnknownKey) => {
+                libc_eprintln!("r3malloc: {}: unknown option \"{}\"", source, show(key));
+            }
+            Err(ConfError::BadValue) => {
+                libc_eprintln!(
+                    "r3malloc: {}: invalid value \"{}\" for \"{}\"",
+                    source,
+                    show(value),
+                    show(key)
+                );
+            }
+        }
+    }
+}
+
+pub fn init() {
+    unsafe {
+        let app_conf = r3malloc_conf;
+        if !app_conf.is_null() && !(*app_conf).is_null() {
+            apply(CStr::from_ptr(*app_conf).to_bytes(), "r3malloc_conf");
+        }
+
+        let env = libc::getenv(CONF_ENV.as_ptr() as *const c_char);
+        if !env.is_null() {
+            apply(CStr::from_ptr(env).to_bytes(), "R3MALLOC_CONF");
+        }
+    }
+}
+
+// the effective options, in the same syntax R3MALLOC_CONF takes
+pub fn write_opts<W: Write>(w: &mut W) -> core::fmt::Result {
+    write!(
+        w,
+        "target_apf:{},reuse_compute_interval:{},num_free_intervals:{},",
+        get_default_target_apf(),
+        get_reuse_compute_interval(),
+        get_num_free_intervals()
+    )?;
+    write!(w, "log:{},save_log:{},save_period:{},", log_enabled(), save_log_enabled(), save_period())?;
+    write!(
+        w,
+        "huge_mode:{},huge_threshold:{},huge_superblocks:{},",
+        get_huge_pages().name().trim_end_matches('\0'),
+        get_huge_threshold(),
+        get_huge_superblocks()
+    )?;
+    write!(
+        w,
+        "purge_decay_ms:{},purge_mode:{},purge_max_retained:{},purge_background:{},",
+        purge::get_decay_ms(),
+        purge::get_mode().name().trim_end_matches('\0'),
+        purge::get_max_retained(),
+        purge::get_background()
+    )?;
+    write!(
+        w,
+        "junk:{},quarantine:{},leak_report:{},",
+        junk::get().name().trim_end_matches('\0'),
+        quarantine::get_max_bytes(),
+        leak::get_enabled()
+    )?;
+    write!(
+        w,
+        "numa:{},numa_nodes:{},percpu_cache:{}",
+        numa::get_enabled(),
+        numa::get_nodes_opt(),
+        percpu::get_enabled()
+    )?;
+
+    #[cfg(feature = "profiling")]
+    write!(
+        w,
+        ",prof_active:{},prof_final:{},prof_sample_interval:{}",
+        prof::get_active(),
+        prof::get_final(),
+        prof::get_interval()
+    )?;
+
+    Ok(())
+}
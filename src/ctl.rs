@@ -0,0 +1,617 @@
+use crate::apf::{
+    get_default_target_apf, get_num_free_intervals, get_reuse_compute_interval,
+    set_default_target_apf,
+};
+use crate::arena;
+use crate::junk;
+use crate::leak;
+use crate::log;
+use crate::numa;
+use crate::percpu;
+use crate::pages::{
+    get_huge_pages, get_huge_superblocks, get_huge_threshold, set_huge_pages,
+    set_huge_superblocks, set_huge_threshold, HugePages,
+};
+#[cfg(feature = "profiling")]
+use crate::prof;
+use crate::purge::{self, PurgeMode};
+use crate::quarantine;
+use crate::r3malloc::{
+    ensure_init, flush_thread_cache, flush_thread_cache_bin, set_thread_cache_enabled,
+    thread_cache_enabled,
+};
+use crate::size_classes::{SizeClassData, MAX_SZ, MAX_SZ_IDX, SIZE_CLASSES};
+use crate::stats;
+use crate::tcache::TCACHE;
+use core::ffi::CStr;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::null_mut;
+use libc::{c_char, c_void, EAGAIN, EINVAL, ENOENT, EPERM};
+#[cfg(feature = "profiling")]
+use libc::EFAULT;
+
+// deepest name we understand, e.g. "sc.12.apf.target"
+const CTL_MAX_DEPTH: usize = 6;
+
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+
+// one mallctl-style request: old value goes to oldp/oldlenp, new value comes from newp/newlen
+struct CtlReq {
+    oldp: *mut c_void,
+    oldlenp: *mut usize,
+    newp: *mut c_void,
+    newlen: usize,
+}
+
+impl CtlReq {
+    unsafe fn read<T: Copy>(&self, value: T) -> i32 {
+        if self.oldp.is_null() {
+            return 0;
+        }
+
+        if self.oldlenp.is_null() || *self.oldlenp != size_of::<T>() {
+            return EINVAL;
+        }
+
+        (self.oldp as *mut T).write_unaligned(value);
+        0
+    }
+
+    unsafe fn write<T: Copy>(&self) -> Result<Option<T>, i32> {
+        if self.newp.is_null() {
+            return Ok(None);
+        }
+
+        if self.newlen != size_of::<T>() {
+            return Err(EINVAL);
+        }
+
+        Ok(Some((self.newp as *const T).read_unaligned()))
+    }
+
+    // C callers may pass any byte, only 0 and 1 are bools
+    unsafe fn write_bool(&self) -> Result<Option<bool>, i32> {
+        match self.write::<u8>()? {
+            Some(byte @ 0..=1) => Ok(Some(byte == 1)),
+            Some(_) => Err(EINVAL),
+            None => Ok(None),
+        }
+    }
+
+    unsafe fn read_only<T: Copy>(&self, value: T) -> i32 {
+        if !self.newp.is_null() {
+            return EPERM;
+        }
+
+        self.read(value)
+    }
+
+    // commands such as "thread.tcache.flush" take and return nothing
+    fn void(&self) -> Result<(), i32> {
+        if !self.oldp.is_null() || !self.newp.is_null() {
+            return Err(EINVAL);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_idx(s: &str) -> Option<usize> {
+    if s.is_empty() || s.len() > 9 {
+        return None;
+    }
+
+    let mut out = 0;
+    for b in s.bytes() {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        out = out * 10 + (b - b'0') as usize;
+    }
+
+    Some(out)
+}
+
+fn parse_sc_idx(s: &str) -> Option<usize> {
+    match parse_idx(s) {
+        Some(sc_idx) if sc_idx > 0 && sc_idx < MAX_SZ_IDX => Some(sc_idx),
+        _ => None,
+    }
+}
+
+fn size_class(sc_idx: usize) -> &'static mut SizeClassData {
+    unsafe { &mut SIZE_CLASSES[sc_idx] }
+}
+
+unsafe fn ctl_sc(sc_idx: usize, node: &[&str], req: &CtlReq) -> i32 {
+    let sc = size_class(sc_idx);
+
+    match node {
+        ["block_size"] => req.read_only(sc.get_block_size()),
+        ["sb_size"] => req.read_only(sc.get_sb_size()),
+        ["block_num"] => req.read_only(sc.get_block_num()),
+        ["cache_block_num"] => req.read_only(sc.get_cache_block_num()),
+        ["apf", "target"] => {
+            let ret = req.read(sc.get_apf().get_target_apf());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<u32>() {
+                Ok(Some(apf)) => sc.get_apf().set_target_apf(apf),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        ["tcache", "nblocks"] => req.read_only(TCACHE[sc_idx].get_block_num()),
+        ["tcache", "flush"] => match req.void() {
+            Ok(()) => {
+                flush_thread_cache_bin(sc_idx);
+                0
+            }
+            Err(err) => err,
+        },
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_stats_sc(sc_idx: usize, node: &[&str], req: &CtlReq) -> i32 {
+    let s = stats::sc_stats(sc_idx);
+
+    match node {
+        ["nmalloc"] => req.read_only(s.nmalloc),
+        ["nfree"] => req.read_only(s.nfree),
+        ["nfill"] => req.read_only(s.nfill),
+        ["nflush"] => req.read_only(s.nflush),
+        ["ncut"] => req.read_only(s.ncut),
+        ["npartial"] => req.read_only(s.npartial),
+        ["nnew_sb"] => req.read_only(s.nnew_sb),
+        ["superblocks"] => req.read_only(s.superblocks),
+        ["allocated"] => req.read_only(s.allocated),
+        ["cached"] => req.read_only(s.cached),
+        ["free"] => req.read_only(s.free),
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_stats_arena(arena: usize, node: &[&str], req: &CtlReq) -> i32 {
+    let s = stats::arena_stats(arena);
+
+    match node {
+        ["superblocks"] => req.read_only(s.superblocks),
+        ["superblock_bytes"] => req.read_only(s.sb_bytes),
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_stats(node: &[&str], req: &CtlReq) -> i32 {
+    if let ["sc", idx, rest @ ..] = node {
+        return match parse_sc_idx(idx) {
+            Some(sc_idx) => ctl_stats_sc(sc_idx, rest, req),
+            None => ENOENT,
+        };
+    }
+
+    if let ["arenas", idx, rest @ ..] = node {
+        return match parse_idx(idx) {
+            Some(arena) if arena::is_valid(arena) => ctl_stats_arena(arena, rest, req),
+            _ => ENOENT,
+        };
+    }
+
+    let t = stats::totals();
+
+    match node {
+        ["allocated"] => req.read_only(t.allocated),
+        ["mapped"] => req.read_only(t.mapped),
+        ["reserved"] => req.read_only(t.reserved),
+        ["tcache_bytes"] => req.read_only(t.tcache_bytes),
+        ["superblock_bytes"] => req.read_only(t.sb_bytes),
+        ["large", "live"] => req.read_only(t.large_live),
+        ["large", "bytes"] => req.read_only(t.large_bytes),
+        ["large", "nmalloc"] => req.read_only(t.large_nmalloc),
+        ["large", "nfree"] => req.read_only(t.large_nfree),
+        ["descriptors", "bytes"] => req.read_only(t.desc_bytes),
+        ["descriptors", "live"] => req.read_only(t.desc_live),
+        ["descriptors", "free"] => req.read_only(t.desc_free),
+        ["retained"] => req.read_only(t.retained),
+        ["dirty"] => req.read_only(t.dirty),
+        ["npurge"] => req.read_only(t.npurge),
+        _ => ENOENT,
+    }
+}
+
+#[cfg(feature = "profiling")]
+unsafe fn ctl_prof(node: &[&str], req: &CtlReq) -> i32 {
+    match node {
+        ["active"] => {
+            let ret = req.read(prof::get_active());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write_bool() {
+                Ok(Some(active)) => prof::set_active(active),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        ["final"] => {
+            let ret = req.read(prof::get_final());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write_bool() {
+                Ok(Some(dump_at_exit)) => prof::set_final(dump_at_exit),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // takes effect as each thread draws its next sample distance
+        ["sample_interval"] => {
+            let ret = req.read(prof::get_interval());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<usize>() {
+                Ok(Some(interval)) => prof::set_interval(interval),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // write-only, takes a file name or null for the default one
+        ["dump"] => {
+            if !req.oldp.is_null() {
+                return EPERM;
+            }
+
+            match req.write::<*const c_char>() {
+                Ok(Some(filename)) if !filename.is_null() => {
+                    if prof::dump(Some(CStr::from_ptr(filename))) {
+                        0
+                    } else {
+                        EFAULT
+                    }
+                }
+                Ok(_) => {
+                    if prof::dump(None) {
+                        0
+                    } else {
+                        EFAULT
+                    }
+                }
+                Err(err) => err,
+            }
+        }
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_huge(node: &[&str], req: &CtlReq) -> i32 {
+    match node {
+        // "off", "thp" or "hugetlb"
+        ["mode"] => {
+            let ret = req.read(get_huge_pages().name().as_ptr() as *const c_char);
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<*const c_char>() {
+                Ok(Some(name)) if !name.is_null() => {
+                    match HugePages::from_name(CStr::from_ptr(name).to_bytes()) {
+                        Some(mode) => set_huge_pages(mode),
+                        None => return EINVAL,
+                    }
+                }
+                Ok(Some(_)) => return EINVAL,
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        ["threshold"] => {
+            let ret = req.read(get_huge_threshold());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<usize>() {
+                Ok(Some(threshold)) => set_huge_threshold(threshold),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // fixed once the first superblock is allocated
+        ["superblocks"] => {
+            let ret = req.read(get_huge_superblocks());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write_bool() {
+                Ok(Some(enable)) if !set_huge_superblocks(enable) => EPERM,
+                Ok(_) => 0,
+                Err(err) => err,
+            }
+        }
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_purge(node: &[&str], req: &CtlReq) -> i32 {
+    match node {
+        // -1 never purges. Decay is checked when the allocator is called, or by the
+        // background thread.
+        ["decay_ms"] => {
+            let ret = req.read(purge::get_decay_ms());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<isize>() {
+                Ok(Some(decay_ms)) if decay_ms < -1 => return EINVAL,
+                Ok(Some(decay_ms)) => purge::set_decay_ms(decay_ms),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // "dontneed" or "free"
+        ["mode"] => {
+            let ret = req.read(purge::get_mode().name().as_ptr() as *const c_char);
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<*const c_char>() {
+                Ok(Some(name)) if !name.is_null() => {
+                    match PurgeMode::from_name(CStr::from_ptr(name).to_bytes()) {
+                        Some(mode) => purge::set_mode(mode),
+                        None => return EINVAL,
+                    }
+                }
+                Ok(Some(_)) => return EINVAL,
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        ["max_retained"] => {
+            let ret = req.read(purge::get_max_retained());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<usize>() {
+                Ok(Some(max_retained)) => purge::set_max_retained(max_retained),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // starts or stops the thread that purges while the allocator isn't called
+        ["background"] => {
+            let ret = req.read(purge::get_background());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write_bool() {
+                Ok(Some(background)) => {
+                    purge::set_background(background);
+                    purge::maybe_start_background();
+                }
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        ["now"] => match req.void() {
+            Ok(()) => {
+                purge::purge_all();
+                0
+            }
+            Err(err) => err,
+        },
+        _ => ENOENT,
+    }
+}
+
+unsafe fn ctl_node(node: &[&str], req: &CtlReq) -> i32 {
+    match node {
+        ["version"] => req.read_only(VERSION.as_ptr() as *const c_char),
+        ["sc", "count"] => req.read_only(MAX_SZ_IDX),
+        ["sc", "max_size"] => req.read_only(MAX_SZ),
+        ["sc", idx, rest @ ..] => match parse_sc_idx(idx) {
+            Some(sc_idx) => ctl_sc(sc_idx, rest, req),
+            None => ENOENT,
+        },
+        ["apf", "target"] => {
+            let ret = req.read(get_default_target_apf());
+            if ret != 0 {
+                return ret;
+            }
+
+            // the new default also applies to the calling thread right away
+            match req.write::<u32>() {
+                Ok(Some(apf)) => {
+                    set_default_target_apf(apf);
+                    for sc_idx in 1..MAX_SZ_IDX {
+                        size_class(sc_idx).get_apf().set_target_apf(apf);
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        // read-only startup options, the rest are under apf, huge, purge and prof
+        ["opt", "reuse_compute_interval"] => req.read_only(get_reuse_compute_interval()),
+        ["opt", "num_free_intervals"] => req.read_only(get_num_free_intervals()),
+        ["opt", "log"] => req.read_only(log::log_enabled()),
+        ["opt", "save_log"] => req.read_only(log::save_log_enabled()),
+        ["opt", "save_period"] => req.read_only(log::save_period()),
+        ["opt", "junk"] => req.read_only(junk::get().name().as_ptr() as *const c_char),
+        ["opt", "quarantine"] => req.read_only(quarantine::get_max_bytes()),
+        ["opt", "leak_report"] => req.read_only(leak::get_enabled()),
+        ["opt", "numa"] => req.read_only(numa::get_enabled()),
+        ["opt", "numa_nodes"] => req.read_only(numa::get_nodes_opt()),
+        ["numa", "nodes"] => req.read_only(numa::nodes() as u32),
+        ["opt", "percpu_cache"] => req.read_only(percpu::get_enabled()),
+        ["percpu", "ncpus"] => req.read_only(percpu::ncpus() as u32),
+        #[cfg(feature = "profiling")]
+        ["prof", rest @ ..] => ctl_prof(rest, req),
+        ["huge", rest @ ..] => ctl_huge(rest, req),
+        ["purge", rest @ ..] => ctl_purge(rest, req),
+        ["stats", rest @ ..] => ctl_stats(rest, req),
+        ["arenas", "narenas"] => req.read_only(arena::narenas() as u32),
+        // reading creates a new arena and returns its index
+        ["arenas", "create"] => {
+            if !req.newp.is_null() {
+                return EPERM;
+            }
+
+            match arena::create() {
+                Some(idx) => req.read(idx as u32),
+                None => EAGAIN,
+            }
+        }
+        ["thread", "arena"] => {
+            let ret = req.read(arena::thread_arena() as u32);
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write::<u32>() {
+                Ok(Some(idx)) if !arena::set_thread_arena(idx as usize) => EINVAL,
+                Ok(_) => 0,
+                Err(err) => err,
+            }
+        }
+        ["thread", "tcache", "flush"] => match req.void() {
+            Ok(()) => {
+                flush_thread_cache();
+                0
+            }
+            Err(err) => err,
+        },
+        // turning it off flushes it
+        ["thread", "tcache", "enabled"] => {
+            let ret = req.read(thread_cache_enabled());
+            if ret != 0 {
+                return ret;
+            }
+
+            match req.write_bool() {
+                Ok(Some(enabled)) => set_thread_cache_enabled(enabled),
+                Ok(None) => (),
+                Err(err) => return err,
+            }
+            0
+        }
+        _ => ENOENT,
+    }
+}
+
+/// Reads and/or writes the value named by a dot separated path such as "sc.12.block_size".
+/// Follows mallctl conventions: the old value is copied to oldp when it is non-null
+/// (*oldlenp must match the value size), a new value is taken from newp when it is non-null.
+/// Returns 0, ENOENT for unknown names, EINVAL for size mismatches, EPERM for writes
+/// to read-only values, EAGAIN when no more arenas can be created and EFAULT when a
+/// profile dump fails.
+///
+/// # Safety
+///
+/// Non-null `oldp`, `oldlenp` and `newp` must be valid for the size of the named value.
+pub unsafe fn ctl(
+    name: &str,
+    oldp: *mut c_void,
+    oldlenp: *mut usize,
+    newp: *mut c_void,
+    newlen: usize,
+) -> i32 {
+    let mut parts: [&str; CTL_MAX_DEPTH] = [""; CTL_MAX_DEPTH];
+    let mut depth = 0;
+
+    for part in name.split('.') {
+        if depth == CTL_MAX_DEPTH {
+            return ENOENT;
+        }
+        parts[depth] = part;
+        depth += 1;
+    }
+
+    ensure_init();
+
+    let req = CtlReq { oldp, oldlenp, newp, newlen };
+    ctl_node(&parts[..depth], &req)
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Types [`ctl_read`] and [`ctl_write`] can move through the ctl interface. Only integers
+/// and `bool` are covered, read pointers and write strings through [`ctl`].
+pub trait CtlValue: Copy + sealed::Sealed {
+    #[doc(hidden)]
+    fn is_valid(_value: &MaybeUninit<Self>) -> bool {
+        true
+    }
+}
+
+macro_rules! ctl_values {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl CtlValue for $t {}
+        )*
+    };
+}
+
+ctl_values!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl sealed::Sealed for bool {}
+impl CtlValue for bool {
+    // a one byte integer value may be any byte
+    fn is_valid(value: &MaybeUninit<Self>) -> bool {
+        unsafe { *(value.as_ptr() as *const u8) <= 1 }
+    }
+}
+
+/// Reads the value named `name`. Fails with EINVAL unless `T` has the size of the value.
+pub fn ctl_read<T: CtlValue>(name: &str) -> Result<T, i32> {
+    // entries that don't return anything leave it zeroed
+    let mut value = MaybeUninit::<T>::zeroed();
+    let mut len = size_of::<T>();
+
+    match unsafe { ctl(name, value.as_mut_ptr() as *mut c_void, &mut len, null_mut(), 0) } {
+        0 if T::is_valid(&value) => Ok(unsafe { value.assume_init() }),
+        0 => Err(EINVAL),
+        err => Err(err),
+    }
+}
+
+/// Writes the value named `name`. Fails with EINVAL unless `T` has the size of the value.
+pub fn ctl_write<T: CtlValue>(name: &str, mut value: T) -> Result<(), i32> {
+    let newp = &mut value as *mut T as *mut c_void;
+
+    match unsafe { ctl(name, null_mut(), null_mut(), newp, size_of::<T>()) } {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
+
+pub fn ctl_exec(name: &str) -> Result<(), i32> {
+    match unsafe { ctl(name, null_mut(), null_mut(), null_mut(), 0) } {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
@@ -1,6 +1,6 @@
 pub const LG_PAGE: usize = 12;
 const LG_CACHELINE: usize = 6;
-const LG_PTR: usize = core::mem::size_of::<*mut libc::c_void>();
+const LG_PTR: usize = core::mem::size_of::<*mut libc::c_void>().trailing_zeros() as usize;
 
 pub const PAGE: usize = (1 as usize) << LG_PAGE;
 pub const PAGE_MASK: usize = PAGE - 1;
@@ -12,7 +12,7 @@ pub const PTR_MASK: usize = PTR_SZ - 1;
 
 // return smallest page size multiple that is >= s
 #[inline(always)]
-pub fn page_ceiling(s: usize) -> usize {
+pub const fn page_ceiling(s: usize) -> usize {
     (s + (PAGE - 1)) & !(PAGE - 1)
 }
 
@@ -27,6 +27,34 @@ pub fn align_val(val: usize, align: usize) -> usize {
     (val + (align - 1)) & (!align + 1)
 }
 
+// one more than the highest id in a sysfs list like "0-1,3", 1 when it can't be read
+pub fn sysfs_list_len(path: &[u8]) -> usize {
+    let fd = unsafe { libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDONLY) };
+    if fd < 0 {
+        return 1;
+    }
+
+    let mut buf = [0u8; 256];
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    unsafe { libc::close(fd) };
+    if n <= 0 {
+        return 1;
+    }
+
+    let mut max = 0;
+    let mut curr = 0;
+    for &b in &buf[..n as usize] {
+        if b.is_ascii_digit() {
+            curr = curr * 10 + (b - b'0') as usize;
+        } else {
+            max = core::cmp::max(max, curr);
+            curr = 0;
+        }
+    }
+
+    core::cmp::max(max, curr) + 1
+}
+
 pub const fn parse_usize(s: &str) -> usize {
     let mut out = 0;
     let mut i = 0;
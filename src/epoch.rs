@@ -0,0 +1,157 @@
+use crate::defines::PAGE;
+use crate::pages::page_alloc;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use likely_stable::unlikely;
+
+// Epoch based reclamation for memory that lock-free readers may still be looking at, like
+// descriptor chunks whose descriptors are reached through the free and partial lists.
+// Readers pin the global epoch while they hold such pointers. Memory unlinked while the
+// epoch was e can be freed once the epoch reached e + 2: the epoch only moves on when every
+// pinned thread has seen the current one, so by then nobody pinned before the unlinking is
+// left.
+//
+// Records are carved out of pages and never unmapped. A thread that finishes gives its
+// record back and the next one reuses it, like the stats slots.
+#[repr(C, align(64))]
+struct Record {
+    // epoch << 1 | 1 while pinned, 0 otherwise
+    state: AtomicU64,
+    in_use: AtomicBool,
+    next: *mut Record,
+}
+
+const RECORDS_PER_PAGE: usize = PAGE / size_of::<Record>();
+
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+static RECORDS: AtomicPtr<Record> = AtomicPtr::new(null_mut());
+
+#[thread_local]
+static mut RECORD: *mut Record = null_mut();
+// nested pins of this thread
+#[thread_local]
+static mut DEPTH: u32 = 0;
+
+fn claim_record() -> *mut Record {
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let rec = unsafe { &*curr };
+        if !rec.in_use.load(Ordering::Relaxed)
+            && rec
+                .in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            return curr;
+        }
+        curr = rec.next;
+    }
+
+    // fresh pages are zeroed, all of their records are unpinned and free
+    let recs = unsafe { page_alloc::<Record>(PAGE) };
+    assert!(!recs.is_null());
+    unsafe {
+        (*recs).in_use.store(true, Ordering::Relaxed);
+        for i in 0..RECORDS_PER_PAGE - 1 {
+            (*recs.add(i)).next = recs.add(i + 1);
+        }
+    }
+
+    let last = unsafe { recs.add(RECORDS_PER_PAGE - 1) };
+    loop {
+        let head = RECORDS.load(Ordering::Relaxed);
+        unsafe { (*last).next = head };
+        if RECORDS
+            .compare_exchange_weak(head, recs, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return recs;
+        }
+    }
+}
+
+pub struct Pin;
+
+// Keeps memory reachable from the shared lists mapped until the pin is dropped.
+#[inline(always)]
+pub fn pin() -> Pin {
+    unsafe {
+        if DEPTH == 0 {
+            if unlikely(RECORD.is_null()) {
+                RECORD = claim_record();
+            }
+            // a sequentially consistent store orders the loads of the pinned section after it
+            let epoch = EPOCH.load(Ordering::Relaxed);
+            (*RECORD).state.store(epoch << 1 | 1, Ordering::SeqCst);
+        }
+        DEPTH += 1;
+    }
+    Pin
+}
+
+impl Drop for Pin {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            DEPTH -= 1;
+            if DEPTH == 0 {
+                (*RECORD).state.store(0, Ordering::Release);
+            }
+        }
+    }
+}
+
+// the epoch to tag memory with, read after unlinking it
+pub fn current() -> u64 {
+    EPOCH.load(Ordering::SeqCst)
+}
+
+// Moves the epoch on if every pinned thread has seen it and returns the epoch now.
+pub fn try_advance() -> u64 {
+    let epoch = EPOCH.load(Ordering::SeqCst);
+
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let rec = unsafe { &*curr };
+        let state = rec.state.load(Ordering::SeqCst);
+        if state & 1 != 0 && state >> 1 != epoch {
+            return epoch;
+        }
+        curr = rec.next;
+    }
+
+    match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => epoch + 1,
+        Err(now) => now,
+    }
+}
+
+// whether memory unlinked in `epoch` can't be reached by anyone anymore
+pub fn is_safe(epoch: u64, now: u64) -> bool {
+    now >= epoch + 2
+}
+
+// called when a thread is done with the allocator
+pub fn release_thread() {
+    unsafe {
+        if !RECORD.is_null() {
+            (*RECORD).in_use.store(false, Ordering::Release);
+            RECORD = null_mut();
+        }
+    }
+}
+
+// The other threads are gone in a forked child, and so are their pins.
+pub fn postfork_child() {
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        unsafe {
+            if curr != RECORD {
+                (*curr).state.store(0, Ordering::Relaxed);
+                (*curr).in_use.store(false, Ordering::Release);
+            }
+            curr = (*curr).next;
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::arena;
+use crate::epoch;
+use crate::freeze;
+use crate::heap;
+use crate::page_heap;
+use crate::pages;
+#[cfg(feature = "profiling")]
+use crate::prof;
+use crate::purge;
+use crate::stats;
+use libc_print::libc_eprintln;
+use likely_stable::unlikely;
+
+// fork() only copies the calling thread, so the child must not inherit shared state another
+// thread was in the middle of changing. The prepare handler freezes the heap, which waits for
+// every CAS loop on descriptors, superblocks and the page map to finish, then takes the
+// locks. None of them is held while allocating, so their holders finish even though the
+// heap is frozen. Both the parent and the child release everything again.
+//
+// The caches and quarantines of the other threads don't exist in the child. Their blocks
+// stay allocated there, and the child's stats keep counting them as cached.
+extern "C" fn prefork() {
+    freeze::prefork();
+
+    arena::CREATE_LOCK.raw_lock();
+    #[cfg(feature = "profiling")]
+    prof::TABLE_LOCK.raw_lock();
+    purge::CACHE_LOCK.raw_lock();
+    heap::RECLAIM_LOCK.raw_lock();
+    page_heap::PAGE_HEAP_LOCK.raw_lock();
+    pages::SB_LOCK.raw_lock();
+}
+
+fn unlock() {
+    unsafe {
+        pages::SB_LOCK.raw_unlock();
+        page_heap::PAGE_HEAP_LOCK.raw_unlock();
+        heap::RECLAIM_LOCK.raw_unlock();
+        purge::CACHE_LOCK.raw_unlock();
+        #[cfg(feature = "profiling")]
+        prof::TABLE_LOCK.raw_unlock();
+        arena::CREATE_LOCK.raw_unlock();
+    }
+}
+
+extern "C" fn postfork_parent() {
+    unlock();
+    freeze::postfork_parent();
+}
+
+extern "C" fn postfork_child() {
+    unlock();
+    freeze::postfork_child();
+    stats::postfork_child();
+    epoch::postfork_child();
+    purge::postfork_child();
+}
+
+// Called last by init_malloc. Prepare handlers run in the reverse order of registration, so
+// those registered later, which may still allocate, run before the heap is frozen.
+pub fn init() {
+    let ret = unsafe { libc::pthread_atfork(Some(prefork), Some(postfork_parent), Some(postfork_child)) };
+    if unlikely(ret != 0) {
+        libc_eprintln!("r3malloc: pthread_atfork failed, fork is not safe");
+    }
+}
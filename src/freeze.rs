@@ -0,0 +1,217 @@
+use crate::defines::PAGE;
+use crate::pages::page_alloc;
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use likely_stable::{likely, unlikely};
+
+// malloc_disable stops every other thread from changing the shared heap state: superblock
+// free lists, descriptors, the page map and large blocks. Everything that does so runs
+// inside a guard, which waits while the heap is frozen. Thread caches are private and stay
+// usable, so allocations and frees served by them go on; the heap walk counts blocks cached
+// by other threads as allocated anyway.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+// A guard only flags its own thread's record, so entering one doesn't touch a cache line
+// shared with other threads; disable scans the records. They are carved out of pages and
+// reused like the epoch records.
+#[repr(C, align(64))]
+struct Record {
+    active: AtomicBool,
+    in_use: AtomicBool,
+    next: *mut Record,
+}
+
+const RECORDS_PER_PAGE: usize = PAGE / size_of::<Record>();
+
+static RECORDS: AtomicPtr<Record> = AtomicPtr::new(null_mut());
+
+#[thread_local]
+static mut RECORD: *mut Record = null_mut();
+
+// spins this many times before giving the CPU away
+const SPIN_LIMIT: u32 = 100;
+
+// set on the forking thread while the fork handlers keep the heap frozen
+#[thread_local]
+static mut FORKING: bool = false;
+
+// nested guards of this thread
+#[thread_local]
+static mut DEPTH: u32 = 0;
+// set on the thread that froze the heap, which keeps going
+#[thread_local]
+static mut HOLDER: bool = false;
+
+fn wait_while(cond: impl Fn() -> bool) {
+    let mut spins = 0;
+    while cond() {
+        if spins < SPIN_LIMIT {
+            spin_loop();
+            spins += 1;
+        } else {
+            unsafe { libc::sched_yield() };
+        }
+    }
+}
+
+fn claim_record() -> *mut Record {
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let rec = unsafe { &*curr };
+        if !rec.in_use.load(Ordering::Relaxed)
+            && rec
+                .in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            return curr;
+        }
+        curr = rec.next;
+    }
+
+    // fresh pages are zeroed, all of their records are inactive and free
+    let recs = unsafe { page_alloc::<Record>(PAGE) };
+    assert!(!recs.is_null());
+    unsafe {
+        (*recs).in_use.store(true, Ordering::Relaxed);
+        for i in 0..RECORDS_PER_PAGE - 1 {
+            (*recs.add(i)).next = recs.add(i + 1);
+        }
+    }
+
+    let last = unsafe { recs.add(RECORDS_PER_PAGE - 1) };
+    loop {
+        let head = RECORDS.load(Ordering::Relaxed);
+        unsafe { (*last).next = head };
+        if RECORDS
+            .compare_exchange_weak(head, recs, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return recs;
+        }
+    }
+}
+
+// whether a thread other than the caller is inside a guard
+fn any_active() -> bool {
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let rec = unsafe { &*curr };
+        if curr != unsafe { RECORD } && rec.active.load(Ordering::SeqCst) {
+            return true;
+        }
+        curr = rec.next;
+    }
+    false
+}
+
+pub struct Guard;
+
+#[inline(always)]
+pub fn guard() -> Guard {
+    unsafe {
+        if DEPTH == 0 && !HOLDER {
+            enter();
+        }
+        DEPTH += 1;
+    }
+    Guard
+}
+
+fn enter() {
+    unsafe {
+        if unlikely(RECORD.is_null()) {
+            RECORD = claim_record();
+        }
+    }
+    let rec = unsafe { &*RECORD };
+
+    loop {
+        // pairs with disable, which sets FROZEN before it looks at the records
+        rec.active.store(true, Ordering::SeqCst);
+        if likely(!FROZEN.load(Ordering::SeqCst)) {
+            return;
+        }
+
+        rec.active.store(false, Ordering::Release);
+        wait_while(|| FROZEN.load(Ordering::Relaxed));
+    }
+}
+
+impl Drop for Guard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            DEPTH -= 1;
+            if DEPTH == 0 && !HOLDER {
+                (*RECORD).active.store(false, Ordering::Release);
+            }
+        }
+    }
+}
+
+// Returns once no other thread is changing the heap. Another call waits for enable, so a
+// thread must not freeze the heap twice.
+pub fn disable() {
+    while unlikely(
+        FROZEN
+            .compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err(),
+    ) {
+        wait_while(|| FROZEN.load(Ordering::Relaxed));
+    }
+
+    wait_while(any_active);
+    unsafe { HOLDER = true };
+}
+
+pub fn enable() {
+    unsafe { HOLDER = false };
+    FROZEN.store(false, Ordering::Release);
+}
+
+// The heap stays frozen across fork, unless the forking thread froze it itself.
+pub fn prefork() {
+    unsafe {
+        if !HOLDER {
+            disable();
+            FORKING = true;
+        }
+    }
+}
+
+pub fn postfork_parent() {
+    unsafe {
+        if FORKING {
+            FORKING = false;
+            enable();
+        }
+    }
+}
+
+// called when a thread is done with the allocator
+pub fn release_thread() {
+    unsafe {
+        if !RECORD.is_null() {
+            (*RECORD).in_use.store(false, Ordering::Release);
+            RECORD = null_mut();
+        }
+    }
+}
+
+// threads that were about to enter a guard when the parent forked don't exist in the child
+pub fn postfork_child() {
+    let mut curr = RECORDS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        unsafe {
+            if curr != RECORD {
+                (*curr).active.store(false, Ordering::Relaxed);
+                (*curr).in_use.store(false, Ordering::Release);
+            }
+            curr = (*curr).next;
+        }
+    }
+    postfork_parent();
+}
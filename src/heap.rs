@@ -1,8 +1,21 @@
-use crate::defines::{align_addr, CACHELINE, CACHELINE_MASK, PAGE};
-use crate::pages::page_alloc;
+use crate::defines::{align_addr, CACHELINE, PAGE};
+use crate::epoch;
+use crate::freeze;
+use crate::lock::SpinLock;
+use crate::pages::{page_alloc, page_free};
 use crate::size_classes::{SizeClassData, SIZE_CLASSES};
 use atomic::{Atomic, Ordering};
-use core::{mem::size_of, ptr::null_mut};
+use core::sync::atomic::{AtomicPtr, AtomicUsize};
+use likely_stable::unlikely;
+use core::cell::UnsafeCell;
+use core::{
+    mem::size_of,
+    ptr::{addr_of, null_mut},
+};
+#[cfg(feature = "profiling")]
+use core::sync::atomic::AtomicU32;
+#[cfg(feature = "check_free")]
+use core::sync::atomic::AtomicU64;
 use c2rust_bitfields::BitfieldStruct;
 
 pub const LG_MAX_BLOCK_NUM: u32 = 31;
@@ -10,6 +23,9 @@ pub const MAX_BLOCK_NUM: u64 = (2 as u64) << LG_MAX_BLOCK_NUM;
 
 pub const DESCRIPTOR_BLOCK_SZ: usize = 16 * PAGE;
 
+// the 8 byte blocks of a 64 KiB superblock, the most any size class has
+pub const MAX_SB_BLOCKS: usize = 16 * PAGE / 8;
+
 #[derive(PartialEq, Debug)]
 pub enum SbState {
     Full = 0,
@@ -32,55 +48,419 @@ impl Anchor {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+// Head of a descriptor list. The counter goes up with every push and is swapped together
+// with the pointer, so a head that was popped and pushed back never compares equal to what a
+// stalled pop read before, however many pushes happened in between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C, align(16))]
 pub struct DescriptorNode<'a> {
     desc: *mut Descriptor<'a>,
+    counter: u64,
 }
 
-// * DescriptorNode needs to always have a valid (non-null) pointer to a Descriptor
 impl<'a> DescriptorNode<'a> {
-    pub fn new(desc: *mut Descriptor<'a>) -> Self {
-        // todo: make sure desc is cacheline aligned
-        DescriptorNode { desc: desc }
+    pub const fn new(desc: *mut Descriptor<'a>) -> Self {
+        DescriptorNode { desc, counter: 0 }
     }
 
-    pub fn set_desc(&mut self, desc: *mut Descriptor<'a>, counter: usize) {
-        assert_eq!((desc as usize) & CACHELINE_MASK, 0);
-
-        self.desc = ((desc as usize) | (counter & CACHELINE_MASK)) as *mut Descriptor
+    pub fn set_desc(&mut self, desc: *mut Descriptor<'a>, counter: u64) {
+        self.desc = desc;
+        self.counter = counter;
     }
 
     pub fn get_desc(&self) -> *mut Descriptor<'a> {
-        ((self.desc as usize) & !CACHELINE_MASK) as *mut Descriptor
+        self.desc
+    }
+
+    pub fn get_counter(&self) -> u64 {
+        self.counter
+    }
+}
+
+// A DescriptorNode changed with 16 byte compare-and-swaps. x86-64 has cmpxchg16b; other
+// targets go through the atomic crate, which falls back to a lock for 16 byte values.
+#[cfg(target_arch = "x86_64")]
+pub struct AtomicDescriptorNode<'a> {
+    node: UnsafeCell<DescriptorNode<'a>>,
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub struct AtomicDescriptorNode<'a> {
+    node: Atomic<DescriptorNode<'a>>,
+}
+
+// Swaps in `new` if `dst` holds `old`, returning what `dst` held. rbx is reserved by LLVM,
+// so the low half of `new` is swapped into it around the instruction.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn cmpxchg16b<'a>(
+    dst: *mut DescriptorNode<'a>,
+    old: DescriptorNode<'a>,
+    new: DescriptorNode<'a>,
+) -> DescriptorNode<'a> {
+    let desc: usize;
+    let counter: u64;
+    core::arch::asm!(
+        "xchg {new_lo}, rbx",
+        "lock cmpxchg16b xmmword ptr [{dst}]",
+        "mov rbx, {new_lo}",
+        dst = in(reg) dst,
+        new_lo = inout(reg) new.desc as usize => _,
+        in("rcx") new.counter,
+        inout("rax") old.desc as usize => desc,
+        inout("rdx") old.counter => counter,
+        options(nostack),
+    );
+    DescriptorNode { desc: desc as *mut Descriptor, counter }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<'a> AtomicDescriptorNode<'a> {
+    pub const fn new(node: DescriptorNode<'a>) -> Self {
+        AtomicDescriptorNode { node: UnsafeCell::new(node) }
+    }
+
+    // a compare-and-swap against a null head stores back what it finds, if anything
+    pub fn load(&self, _order: Ordering) -> DescriptorNode<'a> {
+        let null = DescriptorNode::new(null_mut());
+        unsafe { cmpxchg16b(self.node.get(), null, null) }
+    }
+
+    pub fn store(&self, node: DescriptorNode<'a>, order: Ordering) {
+        let mut curr = self.load(order);
+        while let Err(found) = self.compare_exchange_weak(curr, node, order, order) {
+            curr = found;
+        }
+    }
+
+    pub fn compare_exchange_weak(
+        &self,
+        current: DescriptorNode<'a>,
+        new: DescriptorNode<'a>,
+        _success: Ordering,
+        _failure: Ordering,
+    ) -> Result<DescriptorNode<'a>, DescriptorNode<'a>> {
+        let found = unsafe { cmpxchg16b(self.node.get(), current, new) };
+        if found == current {
+            Ok(found)
+        } else {
+            Err(found)
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl<'a> AtomicDescriptorNode<'a> {
+    pub const fn new(node: DescriptorNode<'a>) -> Self {
+        AtomicDescriptorNode { node: Atomic::new(node) }
+    }
+
+    pub fn load(&self, order: Ordering) -> DescriptorNode<'a> {
+        self.node.load(order)
+    }
+
+    pub fn store(&self, node: DescriptorNode<'a>, order: Ordering) {
+        self.node.store(node, order)
+    }
+
+    pub fn compare_exchange_weak(
+        &self,
+        current: DescriptorNode<'a>,
+        new: DescriptorNode<'a>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<DescriptorNode<'a>, DescriptorNode<'a>> {
+        self.node.compare_exchange_weak(current, new, success, failure)
     }
+}
 
-    pub fn get_counter(&self) -> usize {
-        (self.desc as usize) & CACHELINE_MASK
+impl<'a> core::fmt::Debug for AtomicDescriptorNode<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("AtomicDescriptorNode")
+            .field(&self.load(Ordering::SeqCst))
+            .finish()
     }
 }
 
 #[derive(Debug)]
 pub struct Descriptor<'a> {
     // used in free descriptor list
-    next_free: Atomic<DescriptorNode<'a>>,
+    next_free: AtomicPtr<Descriptor<'a>>,
     // used in partial descriptor list
-    next_partial: Atomic<DescriptorNode<'a>>,
+    next_partial: AtomicPtr<Descriptor<'a>>,
 
     anchor: Atomic<Anchor>,
     superblock: *mut u8,
     heap: *mut ProcHeap<'a>,
     block_size: u32,
     maxcount: u32,
+    // the chunk the descriptor was carved out of
+    chunk: *mut ChunkHeader,
+    // number of blocks of this superblock in the profiler's sample table
+    #[cfg(feature = "profiling")]
+    nsampled: AtomicU32,
+    // blocks handed out and not freed yet, to catch double frees
+    #[cfg(feature = "check_free")]
+    allocated: [AtomicU64; MAX_SB_BLOCKS / 64],
+}
+
+// Start of every chunk of descriptors. The chunks are linked in DESC_CHUNKS, which lets the
+// leak report walk all descriptors handed out. A chunk whose descriptors are all free is
+// unlinked by reclaim and unmapped once no pinned thread can still be reading it.
+#[repr(C)]
+struct ChunkHeader {
+    next: AtomicPtr<ChunkHeader>,
+    // descriptors carved out of the chunk
+    ndescs: usize,
+    // the rest is only touched by reclaim, under RECLAIM_LOCK
+    nfree: usize,
+    unlinked: u64,
+    next_limbo: *mut ChunkHeader,
+}
+
+// free descriptors that start a reclaim pass, at least
+const RECLAIM_MIN_FREE: usize = 4096;
+
+static mut AVAIL_DESC: AtomicDescriptorNode = AtomicDescriptorNode::new(DescriptorNode::new(null_mut()));
+static DESC_CHUNKS: AtomicPtr<ChunkHeader> = AtomicPtr::new(null_mut());
+// descriptors in the linked chunks and how many of them are free
+static DESCS: AtomicUsize = AtomicUsize::new(0);
+static FREE_DESCS: AtomicUsize = AtomicUsize::new(0);
+// mapped chunks, including unlinked ones waiting to be unmapped
+static CHUNKS: AtomicUsize = AtomicUsize::new(0);
+static RECLAIM_AT: AtomicUsize = AtomicUsize::new(RECLAIM_MIN_FREE);
+
+pub static RECLAIM_LOCK: SpinLock = SpinLock::new();
+// unlinked chunks, oldest last
+static mut LIMBO: *mut ChunkHeader = null_mut();
+static LIMBO_CHUNKS: AtomicUsize = AtomicUsize::new(0);
+
+fn first_desc(chunk: *mut u8) -> *mut u8 {
+    align_addr(unsafe { chunk.add(size_of::<ChunkHeader>()) }, CACHELINE)
+}
+
+fn next_desc(curr: *mut u8) -> *mut u8 {
+    align_addr(unsafe { curr.add(size_of::<Descriptor>()) }, CACHELINE)
+}
+
+// whether a descriptor at `curr` fits in the chunk
+fn fits(chunk: *mut u8, curr: *mut u8) -> bool {
+    unsafe {
+        curr.add(size_of::<Descriptor>())
+            .offset_from(chunk.add(DESCRIPTOR_BLOCK_SZ))
+            < 0
+    }
+}
+
+fn register_chunk(chunk: *mut ChunkHeader) {
+    loop {
+        let head = DESC_CHUNKS.load(Ordering::Relaxed);
+        unsafe { (*chunk).next.store(head, Ordering::Relaxed) };
+        if DESC_CHUNKS
+            .compare_exchange_weak(head, chunk, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+    }
+}
+
+// Takes `chunk` out of DESC_CHUNKS, `prev` being the linked chunk before it. Only reclaim
+// unlinks, new chunks are only pushed in front.
+unsafe fn unlink_chunk(prev: *mut ChunkHeader, chunk: *mut ChunkHeader) {
+    let next = (*chunk).next.load(Ordering::Acquire);
+    if !prev.is_null() {
+        (*prev).next.store(next, Ordering::Release);
+        return;
+    }
+    if DESC_CHUNKS
+        .compare_exchange(chunk, next, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        return;
+    }
+
+    // chunks were pushed in front of it meanwhile
+    let mut prev = DESC_CHUNKS.load(Ordering::Acquire);
+    while (*prev).next.load(Ordering::Acquire) != chunk {
+        prev = (*prev).next.load(Ordering::Acquire);
+    }
+    (*prev).next.store(next, Ordering::Release);
+}
+
+// Unmaps the unlinked chunks nobody can reach anymore. Expects RECLAIM_LOCK.
+fn free_limbo_locked() {
+    if LIMBO_CHUNKS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    // a chunk unlinked in the current epoch is safe after two more
+    epoch::try_advance();
+    let now = epoch::try_advance();
+
+    let mut prev: *mut ChunkHeader = null_mut();
+    let mut chunk = unsafe { LIMBO };
+    while !chunk.is_null() {
+        let next = unsafe { (*chunk).next_limbo };
+        if epoch::is_safe(unsafe { (*chunk).unlinked }, now) {
+            if prev.is_null() {
+                unsafe { LIMBO = next };
+            } else {
+                unsafe { (*prev).next_limbo = next };
+            }
+            unsafe { page_free(chunk as *mut u8, DESCRIPTOR_BLOCK_SZ) };
+            LIMBO_CHUNKS.fetch_sub(1, Ordering::Relaxed);
+            CHUNKS.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            prev = chunk;
+        }
+        chunk = next;
+    }
 }
 
-static mut AVAIL_DESC: Atomic<DescriptorNode> = Atomic::new(DescriptorNode { desc: null_mut() });
+fn free_limbo() {
+    let _guard = freeze::guard();
+    if let Some(_lock) = RECLAIM_LOCK.try_lock() {
+        free_limbo_locked();
+    }
+}
+
+// Takes the whole free list, unlinks the chunks all of whose descriptors are on it and puts
+// the other descriptors back. Pops that read the list before keep their pointers into the
+// unlinked chunks, which stay mapped until those threads unpinned. A pass that is already
+// running is left to do the work.
+pub fn reclaim() {
+    let _guard = freeze::guard();
+    let Some(_lock) = RECLAIM_LOCK.try_lock() else {
+        return;
+    };
+
+    let avail = unsafe { &*addr_of!(AVAIL_DESC) };
+    let mut old_head = avail.load(Ordering::SeqCst);
+    loop {
+        let mut new_head = DescriptorNode::new(null_mut());
+        new_head.set_desc(null_mut(), old_head.get_counter() + 1);
+        match avail.compare_exchange_weak(old_head, new_head, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(found) => old_head = found,
+        }
+    }
+    let list = old_head.get_desc();
+
+    let mut curr = list;
+    while !curr.is_null() {
+        unsafe {
+            (*(*curr).chunk).nfree += 1;
+            curr = (*curr).get_next_free().load(Ordering::Relaxed);
+        }
+    }
+
+    // chunks pushed meanwhile have no descriptor on the list and nfree 0
+    let mut unlinked: *mut ChunkHeader = null_mut();
+    let mut removed = 0;
+    let mut prev: *mut ChunkHeader = null_mut();
+    let mut chunk = DESC_CHUNKS.load(Ordering::Acquire);
+    while !chunk.is_null() {
+        let next = unsafe { (*chunk).next.load(Ordering::Acquire) };
+        unsafe {
+            if (*chunk).nfree == (*chunk).ndescs {
+                unlink_chunk(prev, chunk);
+                (*chunk).next_limbo = unlinked;
+                unlinked = chunk;
+                removed += (*chunk).ndescs;
+            } else {
+                (*chunk).nfree = 0;
+                prev = chunk;
+            }
+        }
+        chunk = next;
+    }
+
+    // what's left goes back as one chain
+    let mut first: *mut Descriptor = null_mut();
+    let mut last: *mut Descriptor = null_mut();
+    let mut kept = 0;
+    curr = list;
+    while !curr.is_null() {
+        unsafe {
+            let next = (*curr).get_next_free().load(Ordering::Relaxed);
+            let chunk = (*curr).chunk;
+            if (*chunk).nfree != (*chunk).ndescs {
+                if last.is_null() {
+                    first = curr;
+                } else {
+                    (*last).get_next_free().store(curr, Ordering::Relaxed);
+                }
+                last = curr;
+                kept += 1;
+            }
+            curr = next;
+        }
+    }
+
+    if !first.is_null() {
+        let mut new_head = DescriptorNode::new(null_mut());
+        loop {
+            let old_head = avail.load(Ordering::SeqCst);
+            unsafe { (*last).get_next_free().store(old_head.get_desc(), Ordering::SeqCst) };
+            new_head.set_desc(first, old_head.get_counter() + 1);
+            if avail
+                .compare_exchange_weak(old_head, new_head, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+    DESCS.fetch_sub(removed, Ordering::Relaxed);
+    FREE_DESCS.fetch_sub(removed, Ordering::Relaxed);
+    RECLAIM_AT.store(core::cmp::max(RECLAIM_MIN_FREE, 2 * kept), Ordering::Relaxed);
+
+    if !unlinked.is_null() {
+        let epoch = epoch::current();
+        let mut chunk = unlinked;
+        let mut count = 0;
+        loop {
+            unsafe { (*chunk).unlinked = epoch };
+            count += 1;
+            let next = unsafe { (*chunk).next_limbo };
+            if next.is_null() {
+                break;
+            }
+            chunk = next;
+        }
+        unsafe {
+            (*chunk).next_limbo = LIMBO;
+            LIMBO = unlinked;
+        }
+        LIMBO_CHUNKS.fetch_add(count, Ordering::Relaxed);
+    }
+
+    free_limbo_locked();
+}
+
+// mapped descriptor memory
+pub fn desc_bytes() -> usize {
+    CHUNKS.load(Ordering::Relaxed) * DESCRIPTOR_BLOCK_SZ
+}
+
+// descriptors of superblocks and large blocks
+pub fn desc_live() -> usize {
+    DESCS
+        .load(Ordering::Relaxed)
+        .saturating_sub(FREE_DESCS.load(Ordering::Relaxed))
+}
+
+pub fn desc_free() -> usize {
+    FREE_DESCS.load(Ordering::Relaxed)
+}
 
 impl<'a> Descriptor<'a> {
-    pub fn get_next_free(&self) -> &Atomic<DescriptorNode<'a>> {
+    pub fn get_next_free(&self) -> &AtomicPtr<Descriptor<'a>> {
         &self.next_free
     }
 
-    pub fn get_next_partial(&self) -> &Atomic<DescriptorNode<'a>> {
+    pub fn get_next_partial(&self) -> &AtomicPtr<Descriptor<'a>> {
         &self.next_partial
     }
 
@@ -120,13 +500,57 @@ impl<'a> Descriptor<'a> {
         self.superblock = superblock
     }
 
+    #[cfg(feature = "profiling")]
+    pub fn get_nsampled(&self) -> &AtomicU32 {
+        &self.nsampled
+    }
+
+    // sets whether block `idx` is handed out and returns whether it was
+    #[cfg(feature = "check_free")]
+    pub fn mark_allocated(&self, idx: usize, allocated: bool) -> bool {
+        let word = &self.allocated[idx / 64];
+        let bit = 1 << (idx % 64);
+        let old = if allocated {
+            word.fetch_or(bit, Ordering::AcqRel)
+        } else {
+            word.fetch_and(!bit, Ordering::AcqRel)
+        };
+        old & bit != 0
+    }
+
+    // Calls `f` with the index of every block on the superblock's free list, following the
+    // anchor's avail chain the way flush_cache links it. A full superblock has no list and
+    // its avail means nothing.
+    pub fn for_each_free_block<F: FnMut(usize)>(&self, mut f: F) {
+        let anchor = self.anchor.load(Ordering::SeqCst);
+        if anchor.state() != SbState::Partial as u32 {
+            return;
+        }
+
+        let block_size = self.block_size as usize;
+        let sb_end = self.superblock as usize + self.maxcount as usize * block_size;
+        let mut block = self.superblock as usize + anchor.avail() as usize * block_size;
+        for _ in 0..anchor.count() {
+            if block < self.superblock as usize || block >= sb_end {
+                break;
+            }
+            f((block - self.superblock as usize) / block_size);
+            block = unsafe { *(block as *const usize) };
+        }
+    }
+
     pub fn alloc() -> &'static mut Self {
         loop {
+            // the head may be retired and its chunk reclaimed before we read its next
+            let _pin = epoch::pin();
             let old_head = unsafe { AVAIL_DESC.load(Ordering::SeqCst) };
             let desc: *mut Descriptor = old_head.get_desc();
             if !desc.is_null() {
-                let mut new_head = unsafe { (*desc).get_next_free().load(Ordering::SeqCst) };
-                new_head.set_desc(new_head.get_desc(), old_head.get_counter());
+                let mut new_head = DescriptorNode::new(null_mut());
+                new_head.set_desc(
+                    unsafe { (*desc).get_next_free().load(Ordering::SeqCst) },
+                    old_head.get_counter(),
+                );
 
                 match unsafe {
                     AVAIL_DESC.compare_exchange_weak(
@@ -137,6 +561,7 @@ impl<'a> Descriptor<'a> {
                     )
                 } {
                     Ok(_) => {
+                        FREE_DESCS.fetch_sub(1, Ordering::Relaxed);
                         assert_eq!(unsafe { (*desc).get_block_size() }, 0);
                         return unsafe { &mut *desc };
                     }
@@ -144,43 +569,47 @@ impl<'a> Descriptor<'a> {
                 }
             } else {
                 let ptr = unsafe { page_alloc::<u8>(DESCRIPTOR_BLOCK_SZ) };
-                let ret = ptr as *mut Descriptor;
+                let chunk = ptr as *mut ChunkHeader;
+                let ret = first_desc(ptr) as *mut Descriptor;
+                unsafe { (*ret).chunk = chunk };
 
-                let mut curr_ptr: *mut u8 = unsafe { ptr.offset(size_of::<Descriptor>() as isize) };
-                curr_ptr = align_addr(curr_ptr, CACHELINE);
+                let mut curr_ptr: *mut u8 = next_desc(ret as *mut u8);
                 let first: *mut Descriptor = curr_ptr as *mut Descriptor;
                 let mut prev: *mut Descriptor = null_mut();
+                let mut ndescs = 1;
 
-                while unsafe {
-                    (curr_ptr.offset(size_of::<Descriptor>() as isize))
-                        .offset_from(ptr.offset(DESCRIPTOR_BLOCK_SZ as isize))
-                        < 0
-                } {
+                while fits(ptr, curr_ptr) {
                     let curr = curr_ptr as *mut Descriptor;
+                    unsafe { (*curr).chunk = chunk };
+                    ndescs += 1;
                     if !prev.is_null() {
                         unsafe {
                             (*prev)
                                 .get_next_free()
-                                .store(DescriptorNode::new(&mut *curr), Ordering::SeqCst)
+                                .store(curr, Ordering::SeqCst)
                         };
                     }
 
                     prev = curr;
-                    curr_ptr = unsafe { curr_ptr.offset(size_of::<Descriptor>() as isize) };
-                    curr_ptr = align_addr(curr_ptr, CACHELINE);
+                    curr_ptr = next_desc(curr_ptr);
                 }
 
                 unsafe {
                     (*prev)
                         .get_next_free()
-                        .store(DescriptorNode::new(null_mut()), Ordering::SeqCst)
+                        .store(null_mut(), Ordering::SeqCst)
                 };
+                unsafe { (*chunk).ndescs = ndescs };
+                CHUNKS.fetch_add(1, Ordering::Relaxed);
+                DESCS.fetch_add(ndescs, Ordering::Relaxed);
+                FREE_DESCS.fetch_add(ndescs - 1, Ordering::Relaxed);
+                register_chunk(chunk);
 
                 let mut new_head: DescriptorNode = DescriptorNode::new(null_mut());
                 loop {
                     let old_head = unsafe { AVAIL_DESC.load(Ordering::SeqCst) };
                     unsafe {
-                        (*prev).get_next_free().store(old_head, Ordering::SeqCst);
+                        (*prev).get_next_free().store(old_head.get_desc(), Ordering::SeqCst);
                         new_head.set_desc(first, old_head.get_counter() + 1);
 
                         match AVAIL_DESC.compare_exchange_weak(
@@ -202,12 +631,32 @@ impl<'a> Descriptor<'a> {
         }
     }
 
+    // Calls `f` with every descriptor in use, i.e. with a superblock or a large block.
+    // Descriptors of superblocks that just went empty are included.
+    pub fn for_each_live<F: FnMut(&Descriptor)>(mut f: F) {
+        let _pin = epoch::pin();
+        let mut chunk = DESC_CHUNKS.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            let mut curr = first_desc(chunk as *mut u8);
+            while fits(chunk as *mut u8, curr) {
+                let desc = unsafe { &*(curr as *const Descriptor) };
+                if desc.get_block_size() != 0 {
+                    f(desc);
+                }
+                curr = next_desc(curr);
+            }
+            chunk = unsafe { (*chunk).next.load(Ordering::Acquire) };
+        }
+    }
+
     pub fn retire(&'static mut self) {
         self.block_size = 0;
+        // counted first, a pop may take it right after the push
+        let free = FREE_DESCS.fetch_add(1, Ordering::Relaxed) + 1;
         let mut new_head: DescriptorNode = DescriptorNode::new(null_mut());
         loop {
             let old_head = unsafe { AVAIL_DESC.load(Ordering::SeqCst) };
-            self.get_next_free().store(old_head, Ordering::SeqCst);
+            self.get_next_free().store(old_head.get_desc(), Ordering::SeqCst);
             new_head.set_desc(self, old_head.get_counter() + 1);
 
             unsafe {
@@ -224,20 +673,28 @@ impl<'a> Descriptor<'a> {
                 }
             }
         }
+
+        if unlikely(free >= RECLAIM_AT.load(Ordering::Relaxed)) {
+            reclaim();
+        } else if unlikely(LIMBO_CHUNKS.load(Ordering::Relaxed) != 0) {
+            free_limbo();
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct ProcHeap<'a> {
-    partial_list: Atomic<DescriptorNode<'a>>,
+    partial_list: AtomicDescriptorNode<'a>,
     sc_idx: usize,
+    arena_idx: usize,
 }
 
 impl<'a> ProcHeap<'a> {
     pub const fn const_new(sc_idx: usize) -> Self {
         ProcHeap {
-            partial_list: Atomic::new(DescriptorNode { desc: null_mut() }),
+            partial_list: AtomicDescriptorNode::new(DescriptorNode::new(null_mut())),
             sc_idx,
+            arena_idx: 0,
         }
     }
 
@@ -249,7 +706,15 @@ impl<'a> ProcHeap<'a> {
         self.sc_idx
     }
 
-    pub fn get_partial_list(&self) -> &Atomic<DescriptorNode<'a>> {
+    pub fn set_arena_idx(&mut self, arena_idx: usize) {
+        self.arena_idx = arena_idx;
+    }
+
+    pub fn get_arena_idx(&self) -> usize {
+        self.arena_idx
+    }
+
+    pub fn get_partial_list(&self) -> &AtomicDescriptorNode<'a> {
         &self.partial_list
     }
 
@@ -0,0 +1,78 @@
+use crate::heap::{Descriptor, SbState, MAX_SB_BLOCKS};
+use crate::quarantine;
+#[cfg(feature = "redzone")]
+use crate::redzone;
+use crate::tcache::TCACHE;
+use atomic::Ordering;
+use core::ptr::addr_of;
+
+// Walks live allocations for malloc_iterate. Superblocks and large blocks are found through
+// their descriptors. A small block is free when it is on its superblock's free list, in the
+// calling thread's cache or in its quarantine. Other threads' caches and the per-CPU caches
+// can't be seen, so the blocks in them are reported as allocated. Large blocks are reported
+// from the start of their mapping, which comes before the pointer handed out when they are
+// aligned to more than a page.
+
+#[cfg(feature = "redzone")]
+fn user_range(block: *mut u8, block_size: usize) -> (*mut u8, usize) {
+    unsafe { redzone::user_range(block, block_size) }
+}
+
+#[cfg(not(feature = "redzone"))]
+fn user_range(block: *mut u8, block_size: usize) -> (*mut u8, usize) {
+    (block, block_size)
+}
+
+// calls `f` with the pointer and size of every live allocation starting in [base, base + size)
+pub fn iterate<F: FnMut(*mut u8, usize)>(base: usize, size: usize, mut f: F) {
+    let end = base.saturating_add(size);
+    let in_range = |ptr: *mut u8| ptr as usize >= base && (ptr as usize) < end;
+
+    Descriptor::for_each_live(|desc| {
+        let superblock = desc.get_superblock();
+        let heap = desc.get_heap();
+        if heap.is_null() {
+            if in_range(superblock) {
+                f(superblock, desc.get_block_size() as usize);
+            }
+            return;
+        }
+
+        let block_size = desc.get_block_size() as usize;
+        let maxcount = desc.get_maxcount() as usize;
+        let sb_start = superblock as usize;
+        let sb_end = sb_start + maxcount * block_size;
+        if sb_end <= base || sb_start >= end {
+            return;
+        }
+
+        if desc.get_anchor().load(Ordering::SeqCst).state() == SbState::Empty as u32 {
+            return;
+        }
+
+        let mut free = [0u64; MAX_SB_BLOCKS / 64];
+        let mut mark = |idx: usize| free[idx / 64] |= 1 << (idx % 64);
+        desc.for_each_free_block(&mut mark);
+
+        let mut mark_own = |block: *mut u8| {
+            let addr = block as usize;
+            if addr >= sb_start && addr < sb_end {
+                mark((addr - sb_start) / block_size);
+            }
+        };
+        let sc_idx = unsafe { (*heap).get_sc_idx() };
+        unsafe { (*addr_of!(TCACHE))[sc_idx].for_each_block(&mut mark_own) };
+        quarantine::for_each_held(&mut mark_own);
+
+        for idx in 0..maxcount {
+            if free[idx / 64] & (1 << (idx % 64)) != 0 {
+                continue;
+            }
+
+            let (ptr, size) = user_range(unsafe { superblock.add(idx * block_size) }, block_size);
+            if in_range(ptr) {
+                f(ptr, size);
+            }
+        }
+    });
+}
@@ -0,0 +1,89 @@
+use crate::defines::PTR_SZ;
+use core::sync::atomic::{AtomicU8, Ordering};
+use likely_stable::unlikely;
+
+// Optional filling of blocks as they are handed out and given back, so that reads of
+// uninitialized or freed memory turn up recognizable bytes. Zeroed allocations are never
+// filled.
+const JUNK_ALLOC: u8 = 0xa5;
+const JUNK_FREE: u8 = 0x5a;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Junk {
+    Off = 0,
+    Alloc = 1,
+    Free = 2,
+    // both
+    On = 3,
+}
+
+impl Junk {
+    // same names as jemalloc's junk option
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"false" => Some(Junk::Off),
+            b"alloc" => Some(Junk::Alloc),
+            b"free" => Some(Junk::Free),
+            b"true" => Some(Junk::On),
+            _ => None,
+        }
+    }
+
+    // NUL-terminated, so it can be handed out through ctl
+    pub fn name(self) -> &'static str {
+        match self {
+            Junk::Off => "false\0",
+            Junk::Alloc => "alloc\0",
+            Junk::Free => "free\0",
+            Junk::On => "true\0",
+        }
+    }
+}
+
+static JUNK: AtomicU8 = AtomicU8::new(Junk::Off as u8);
+
+pub fn get() -> Junk {
+    match JUNK.load(Ordering::Relaxed) {
+        1 => Junk::Alloc,
+        2 => Junk::Free,
+        3 => Junk::On,
+        _ => Junk::Off,
+    }
+}
+
+pub fn set(junk: Junk) {
+    JUNK.store(junk as u8, Ordering::Relaxed)
+}
+
+#[inline(always)]
+pub fn on_alloc(ptr: *mut u8, size: usize) {
+    if unlikely(JUNK.load(Ordering::Relaxed) & Junk::Alloc as u8 != 0) {
+        unsafe { ptr.write_bytes(JUNK_ALLOC, size) };
+    }
+}
+
+#[inline(always)]
+pub fn on_large_free(ptr: *mut u8, size: usize) {
+    if unlikely(JUNK.load(Ordering::Relaxed) & Junk::Free as u8 != 0) {
+        unsafe { ptr.write_bytes(JUNK_FREE, size) };
+    }
+}
+
+// leaves the first word alone, it links the block into a thread cache or superblock
+#[inline(always)]
+pub fn on_small_free(block: *mut u8, block_size: usize) {
+    if unlikely(JUNK.load(Ordering::Relaxed) & Junk::Free as u8 != 0) {
+        unsafe { block.add(PTR_SZ).write_bytes(JUNK_FREE, block_size - PTR_SZ) };
+    }
+}
+
+// first byte of a freed small block changed since on_small_free, if blocks are filled
+pub fn overwritten_after_free(block: *mut u8, block_size: usize) -> Option<*mut u8> {
+    if JUNK.load(Ordering::Relaxed) & Junk::Free as u8 == 0 {
+        return None;
+    }
+
+    (PTR_SZ..block_size)
+        .map(|i| unsafe { block.add(i) })
+        .find(|&addr| unsafe { *addr } != JUNK_FREE)
+}
@@ -0,0 +1,114 @@
+use crate::heap::{Descriptor, SbState};
+#[cfg(feature = "profiling")]
+use crate::prof;
+use crate::quarantine;
+use crate::r3malloc::ensure_init;
+use crate::size_classes::{MAX_SZ_IDX, SIZE_CLASSES};
+use crate::stats::{self, StatsWriter};
+use atomic::Ordering;
+use core::fmt::Write;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use likely_stable::unlikely;
+
+// Optional report of the blocks still allocated when the process exits. Every descriptor in
+// use is found through the descriptor chunks. A small block doesn't count as leaked when it
+// is on its superblock's free list, in a thread cache or in a quarantine. Thread caches and
+// quarantines are only known by their counts, so leaks are reported per size class.
+static LEAK_REPORT: AtomicBool = AtomicBool::new(false);
+
+pub fn get_enabled() -> bool {
+    LEAK_REPORT.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    LEAK_REPORT.store(enabled, Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Default)]
+struct Leaked {
+    count: u64,
+    bytes: u64,
+}
+
+fn block_size(sc_idx: usize) -> u64 {
+    unsafe { SIZE_CLASSES[sc_idx].get_block_size() as u64 }
+}
+
+// writes nothing when every block was freed
+fn report<W: Write>(w: &mut W) -> core::fmt::Result {
+    let mut small = [Leaked::default(); MAX_SZ_IDX];
+    let mut large = Leaked::default();
+
+    Descriptor::for_each_live(|desc| {
+        let heap = desc.get_heap();
+        if heap.is_null() {
+            large.count += 1;
+            large.bytes += desc.get_block_size() as u64;
+            return;
+        }
+
+        // the superblock is already gone, only the descriptor is left to retire
+        if desc.get_anchor().load(Ordering::SeqCst).state() == SbState::Empty as u32 {
+            return;
+        }
+
+        let sc_idx = unsafe { (*heap).get_sc_idx() };
+        let mut free = 0;
+        desc.for_each_free_block(|_| free += 1);
+        small[sc_idx].count += desc.get_maxcount() as u64 - free;
+    });
+
+    let mut total = large;
+    for (sc_idx, leaked) in small.iter_mut().enumerate().skip(1) {
+        // the rest of the blocks taken off the superblocks are in thread caches or quarantines
+        let freed = stats::sc_stats(sc_idx).cached + quarantine::held(sc_idx) as u64;
+        leaked.count = leaked.count.saturating_sub(freed);
+        leaked.bytes = leaked.count * block_size(sc_idx);
+        total.count += leaked.count;
+        total.bytes += leaked.bytes;
+    }
+
+    if total.count == 0 {
+        return Ok(());
+    }
+
+    writeln!(
+        w,
+        "r3malloc: leak report: {} blocks, {} bytes still allocated at exit",
+        total.count, total.bytes
+    )?;
+    for (sc_idx, leaked) in small.iter().enumerate().skip(1) {
+        if leaked.count > 0 {
+            writeln!(
+                w,
+                "r3malloc:   size class {} ({} byte blocks): {} leaked, {} bytes",
+                sc_idx,
+                block_size(sc_idx),
+                leaked.count,
+                leaked.bytes
+            )?;
+        }
+    }
+    if large.count > 0 {
+        writeln!(w, "r3malloc:   large blocks: {} leaked, {} bytes", large.count, large.bytes)?;
+    }
+
+    #[cfg(feature = "profiling")]
+    prof::write_leak_sites(w)?;
+
+    Ok(())
+}
+
+extern "C" fn leak_atexit() {
+    ensure_init();
+    let mut w = StatsWriter::new(None, null_mut());
+    let _ = report(&mut w);
+}
+
+// registers the report; atexit may allocate, so the allocator must be usable by now
+pub fn init() {
+    if get_enabled() && unlikely(unsafe { libc::atexit(leak_atexit) } != 0) {
+        set_enabled(false);
+    }
+}
@@ -5,32 +5,56 @@
 #![feature(lang_items)]
 #![feature(const_mut_refs)]
 #![feature(unchecked_math)]
+#![feature(linkage)]
 
 //#[lang = "eh_personality"]
 //extern "C" fn eh_personality() {}
 
 mod apf;
+mod arena;
+#[cfg(feature = "check_free")]
+mod check;
+mod conf;
+mod ctl;
 mod defines;
+mod epoch;
+mod fork;
+mod freeze;
 mod heap;
+mod iterate;
+mod junk;
+mod leak;
+mod lock;
 mod log;
+mod numa;
+mod page_heap;
 mod pagemap;
 mod pages;
+mod percpu;
+#[cfg(feature = "profiling")]
+mod prof;
+mod purge;
+mod quarantine;
 mod r3malloc;
+#[cfg(feature = "redzone")]
+mod redzone;
 mod size_classes;
+mod stats;
 mod tcache;
+mod thread_exit;
 
 use heap::Anchor;
 use libc_print::libc_println;
 use likely_stable::{likely, unlikely};
 use core::ptr::{null_mut, copy};
-use core::slice;
 use pagemap::SPAGEMAP;
-use size_classes::SIZE_CLASSES;
 use defines::{PTR_MASK, PAGE};
 use core::alloc::{GlobalAlloc, Layout};
 
 extern crate libc;
 
+pub use ctl::{ctl, ctl_exec, ctl_read, ctl_write, CtlValue};
+
 // FIXME: Dummy code as a POC (see tests/dummy.c)
 #[no_mangle]
 pub extern "C" fn test() -> u32 {
@@ -75,6 +99,41 @@ pub extern "C" fn free(ptr: *mut libc::c_void) {
     r3malloc::do_free(ptr as *mut u8)
 }
 
+// C23 sized deallocation, `size` must be the one passed to malloc, calloc or realloc
+#[no_mangle]
+pub extern "C" fn free_sized(ptr: *mut libc::c_void, size: usize) {
+    r3malloc::do_free_sized(ptr as *mut u8, size)
+}
+
+// `alignment` and `size` must be the ones passed to aligned_alloc
+#[no_mangle]
+pub extern "C" fn free_aligned_sized(ptr: *mut libc::c_void, alignment: usize, size: usize) {
+    r3malloc::do_free_aligned_sized(ptr as *mut u8, alignment, size)
+}
+
+// C++14 sized operator delete and delete[], the default operator new calls malloc
+#[no_mangle]
+pub extern "C" fn _ZdlPvm(ptr: *mut libc::c_void, size: usize) {
+    r3malloc::do_free_sized(ptr as *mut u8, size)
+}
+
+#[no_mangle]
+pub extern "C" fn _ZdaPvm(ptr: *mut libc::c_void, size: usize) {
+    r3malloc::do_free_sized(ptr as *mut u8, size)
+}
+
+// C++17 aligned sized operator delete and delete[], the aligned operator new rounds the
+// size up to the alignment and calls aligned_alloc
+#[no_mangle]
+pub extern "C" fn _ZdlPvmSt11align_val_t(ptr: *mut libc::c_void, size: usize, alignment: usize) {
+    r3malloc::do_free_aligned_sized(ptr as *mut u8, alignment, size)
+}
+
+#[no_mangle]
+pub extern "C" fn _ZdaPvmSt11align_val_t(ptr: *mut libc::c_void, size: usize, alignment: usize) {
+    r3malloc::do_free_aligned_sized(ptr as *mut u8, alignment, size)
+}
+
 #[no_mangle]
 pub extern "C" fn calloc(n: usize, size: usize) -> *mut libc::c_void {
     let alloc_size = n * size;
@@ -85,42 +144,38 @@ pub extern "C" fn calloc(n: usize, size: usize) -> *mut libc::c_void {
         return null_mut();
     }
 
-    let ptr = r3malloc::do_malloc(alloc_size);
-
-    // calloc returns zero-filled memory
-    // @todo: optimize, memory may be already zero-filled
-    //  if coming directly from OS
-    if likely(ptr != null_mut()) {
-        unsafe { slice::from_raw_parts_mut(ptr, alloc_size).fill(0x0); }
-    }
-
-    ptr as *mut libc::c_void
+    r3malloc::do_calloc(alloc_size) as *mut libc::c_void
 }
 
 #[no_mangle]
 pub extern "C" fn realloc(ptr: *mut libc::c_void, size: usize) -> *mut libc::c_void {
-    let mut block_size = 0;
+    let mut old_size = 0;
 
     if likely(!ptr.is_null()) {
         let info = unsafe { SPAGEMAP.get_page_info(ptr as *mut u8) };
         let desc = info.get_desc();
         assert!(!desc.is_null());
 
-        block_size = unsafe { (& *desc).get_block_size() };
+        let block_size = unsafe { (& *desc).get_block_size() };
+        old_size = r3malloc::usable_size(ptr as *mut u8);
 
         if unlikely(size == 0) {
             r3malloc::do_free(ptr as *mut u8);
             return null_mut();
         }
 
-        if unlikely(size <= block_size as usize) {
+        // stay in place only while the block keeps its size class, so that
+        // free_sized with the new size finds the right one
+        let sc_idx = info.get_sc_idx();
+        if unlikely(sc_idx == r3malloc::alloc_size_class(0, size) && size <= block_size as usize) {
+            r3malloc::resize_small_in_place(ptr as *mut u8, sc_idx, size, false);
             return ptr;
         }
     }
 
     let new_ptr = r3malloc::do_malloc(size) as *mut libc::c_void;
     if likely(!ptr.is_null() && !new_ptr.is_null()) {
-        unsafe { copy(ptr, new_ptr, block_size as usize) };
+        unsafe { copy(ptr as *const u8, new_ptr as *mut u8, core::cmp::min(old_size, size)) };
         r3malloc::do_free(ptr as *mut u8);
     }
 
@@ -133,18 +188,7 @@ pub extern "C" fn malloc_usable_size(ptr: *mut libc::c_void) -> usize {
         return 0
     }
 
-    let info = unsafe { SPAGEMAP.get_page_info(ptr as *mut u8) };
-
-    let sc_idx = info.get_sc_idx();
-    // large allocation case
-    if unlikely(sc_idx == 0) {
-        let desc = info.get_desc();
-        assert!(!desc.is_null());
-        return unsafe { (&*desc).get_block_size() as usize };
-    }
-
-    let sc = unsafe { &SIZE_CLASSES[sc_idx] };
-    sc.get_block_size() as usize
+    r3malloc::usable_size(ptr as *mut u8)
 }
 
 #[inline(always)]
@@ -192,11 +236,118 @@ pub extern "C" fn pvalloc(size: usize) -> *mut libc::c_void {
     r3malloc::do_aligned_alloc(PAGE, size) as *mut libc::c_void
 }
 
+/// Returns the calling thread's cached blocks and releases its per-thread state. Threads do
+/// this on their own when they exit.
 #[no_mangle]
 pub extern "C" fn r3malloc_thread_finalize() {
     r3malloc::thread_finalize()
 }
 
+/// Returns the blocks in the calling thread's cache to their superblocks.
+#[no_mangle]
+pub extern "C" fn r3malloc_thread_tcache_flush() {
+    r3malloc::ensure_init();
+    r3malloc::flush_thread_cache()
+}
+
+/// Like `r3malloc_thread_tcache_flush`, but only for the size class serving `size`.
+/// Returns 0, or EINVAL when blocks of that size are not cached.
+#[no_mangle]
+pub extern "C" fn r3malloc_thread_tcache_flush_size(size: usize) -> i32 {
+    r3malloc::ensure_init();
+
+    let sc_idx = r3malloc::alloc_size_class(0, size);
+    if sc_idx == 0 {
+        return libc::EINVAL;
+    }
+
+    r3malloc::flush_thread_cache_bin(sc_idx);
+    0
+}
+
+/// Turns the calling thread's cache on or off and returns whether it was on. While it is
+/// off, small allocations and frees go straight to the shared heaps of the thread's arena.
+#[no_mangle]
+pub extern "C" fn r3malloc_thread_tcache_set_enabled(enabled: bool) -> bool {
+    r3malloc::ensure_init();
+
+    let was_enabled = r3malloc::thread_cache_enabled();
+    r3malloc::set_thread_cache_enabled(enabled);
+    was_enabled
+}
+
+/// Creates an arena, a separate set of heaps for small allocations. Returns its index,
+/// or -1 with errno set to EAGAIN when the maximum number of arenas exists already.
+#[no_mangle]
+pub extern "C" fn r3malloc_arena_create() -> i32 {
+    r3malloc::ensure_init();
+
+    match arena::create() {
+        Some(idx) => idx as i32,
+        None => {
+            unsafe { *libc::__errno_location() = libc::EAGAIN };
+            -1
+        }
+    }
+}
+
+/// Makes the calling thread allocate from `arena`, flushing its thread cache when the
+/// arena changes. Returns 0, or EINVAL when the arena doesn't exist.
+#[no_mangle]
+pub extern "C" fn r3malloc_thread_arena_set(arena: u32) -> i32 {
+    r3malloc::ensure_init();
+
+    if arena::set_thread_arena(arena as usize) {
+        0
+    } else {
+        libc::EINVAL
+    }
+}
+
+/// Allocates from `arena` regardless of the calling thread's arena, bypassing the thread
+/// cache. Returns NULL with errno set to EINVAL when the arena doesn't exist.
+#[no_mangle]
+pub extern "C" fn r3malloc_arena_malloc(arena: u32, size: usize) -> *mut libc::c_void {
+    if unlikely(!arena::is_valid(arena as usize)) {
+        unsafe { *libc::__errno_location() = libc::EINVAL };
+        return null_mut();
+    }
+
+    r3malloc::do_arena_alloc(arena as usize, 0, size, false) as *mut libc::c_void
+}
+
+/// Calls `callback(ptr, size, arg)` for every live allocation starting in
+/// `[base, base + size)`. Blocks cached by other threads are reported as live. The heap
+/// should be frozen with `malloc_disable`, and the callback must not allocate or free.
+/// Returns -1 when `callback` is NULL.
+#[no_mangle]
+pub extern "C" fn malloc_iterate(
+    base: usize,
+    size: usize,
+    callback: Option<extern "C" fn(usize, usize, *mut libc::c_void)>,
+    arg: *mut libc::c_void,
+) -> i32 {
+    let Some(callback) = callback else {
+        return -1;
+    };
+
+    r3malloc::ensure_init();
+    iterate::iterate(base, size, |ptr, size| callback(ptr as usize, size, arg));
+    0
+}
+
+/// Stops other threads from changing the heap until `malloc_enable`. Their allocations
+/// and frees wait unless the thread cache can serve them.
+#[no_mangle]
+pub extern "C" fn malloc_disable() {
+    freeze::disable()
+}
+
+#[no_mangle]
+pub extern "C" fn malloc_enable() {
+    freeze::enable()
+}
+
 // Rust representation or r3malloc
 pub struct R3Malloc {
 
@@ -209,16 +360,272 @@ unsafe impl GlobalAlloc for R3Malloc {
         r3malloc::do_aligned_alloc(layout.align(), layout.size())
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        r3malloc::do_free(ptr)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        r3malloc::do_free_aligned_sized(ptr, layout.align(), layout.size())
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        calloc(layout.size(), 1) as *mut u8
+        r3malloc::do_aligned_calloc(layout.align(), layout.size())
     }
 
-    unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
-        realloc(ptr as *mut libc::c_void, new_size) as *mut u8
+    // blocks always come from do_aligned_alloc, so dealloc can derive their size class
+    // from the layout
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let sc_idx = r3malloc::alloc_size_class(layout.align(), layout.size());
+        if sc_idx != 0 && sc_idx == r3malloc::alloc_size_class(layout.align(), new_size) {
+            r3malloc::resize_small_in_place(ptr, sc_idx, new_size, false);
+            return ptr;
+        }
+
+        let new_ptr = r3malloc::do_aligned_alloc(layout.align(), new_size);
+        if likely(!new_ptr.is_null()) {
+            copy(ptr, new_ptr, core::cmp::min(layout.size(), new_size));
+            r3malloc::do_free_aligned_sized(ptr, layout.align(), layout.size());
+        }
+
+        new_ptr
+    }
+}
+
+// flags of the *allocx functions, encoded like jemalloc's MALLOCX_* macros
+const MALLOCX_LG_ALIGN_MASK: i32 = 0x3f;
+const MALLOCX_ZERO: i32 = 0x40;
+const MALLOCX_TCACHE_SHIFT: i32 = 8;
+const MALLOCX_TCACHE_MASK: i32 = 0xfff;
+// MALLOCX_TCACHE_NONE; explicit tcaches don't exist, MALLOCX_TCACHE(tc) uses the thread's
+const MALLOCX_TCACHE_NONE: i32 = 1;
+const MALLOCX_ARENA_SHIFT: i32 = 20;
+
+#[derive(Clone, Copy)]
+struct AllocxFlags {
+    // 0 when no alignment was asked for
+    alignment: usize,
+    zero: bool,
+    tcache: bool,
+    arena: Option<usize>,
+}
+
+impl AllocxFlags {
+    fn new(flags: i32) -> Self {
+        let lg_align = flags & MALLOCX_LG_ALIGN_MASK;
+        let arena = (flags as u32 >> MALLOCX_ARENA_SHIFT) as usize;
+
+        AllocxFlags {
+            alignment: if lg_align == 0 { 0 } else { 1 << lg_align },
+            zero: flags & MALLOCX_ZERO != 0,
+            tcache: (flags >> MALLOCX_TCACHE_SHIFT) & MALLOCX_TCACHE_MASK != MALLOCX_TCACHE_NONE,
+            arena: arena.checked_sub(1),
+        }
+    }
+
+    // an explicit arena is used without the thread cache, it only caches the thread's arena
+    fn alloc(self, size: usize) -> *mut u8 {
+        match self.arena {
+            Some(arena) if !arena::is_valid(arena) => null_mut(),
+            Some(arena) => r3malloc::do_arena_alloc(arena, self.alignment, size, self.zero),
+            None if !self.tcache => {
+                r3malloc::ensure_init();
+                r3malloc::do_arena_alloc(arena::thread_arena(), self.alignment, size, self.zero)
+            }
+            None => match (self.alignment, self.zero) {
+                (0, false) => r3malloc::do_malloc(size),
+                (0, true) => r3malloc::do_calloc(size),
+                (alignment, false) => r3malloc::do_aligned_alloc(alignment, size),
+                (alignment, true) => r3malloc::do_aligned_calloc(alignment, size),
+            },
+        }
+    }
+
+    fn free(self, ptr: *mut u8) {
+        if self.tcache {
+            r3malloc::do_free(ptr)
+        } else {
+            r3malloc::do_free_direct(ptr)
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mallocx(size: usize, flags: i32) -> *mut libc::c_void {
+    AllocxFlags::new(flags).alloc(size) as *mut libc::c_void
+}
+
+#[no_mangle]
+pub extern "C" fn rallocx(ptr: *mut libc::c_void, size: usize, flags: i32) -> *mut libc::c_void {
+    let f = AllocxFlags::new(flags);
+    let ptr = ptr as *mut u8;
+
+    // stay in place while the block keeps its size class and alignment, like realloc
+    let old_size = r3malloc::usable_size(ptr);
+    let sc_idx = unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) }.get_sc_idx();
+    if (f.alignment == 0 || ptr as usize & (f.alignment - 1) == 0)
+        && sc_idx == r3malloc::alloc_size_class(f.alignment, size)
+        && (sc_idx != 0 || r3malloc::do_resize_in_place(ptr, size, 0, f.alignment, f.zero) >= size)
+    {
+        if sc_idx != 0 {
+            r3malloc::resize_small_in_place(ptr, sc_idx, size, f.zero);
+        }
+        return ptr as *mut libc::c_void;
+    }
+
+    let new_ptr = f.alloc(size);
+    if likely(!new_ptr.is_null()) {
+        unsafe { copy(ptr, new_ptr, core::cmp::min(old_size, size)) };
+        f.free(ptr);
+    }
+
+    new_ptr as *mut libc::c_void
+}
+
+/// Resizes in place to at least `size` and at most `size + extra` bytes where possible and
+/// returns the usable size, which is the old one when the block could not be resized.
+#[no_mangle]
+pub extern "C" fn xallocx(ptr: *mut libc::c_void, size: usize, extra: usize, flags: i32) -> usize {
+    let f = AllocxFlags::new(flags);
+    r3malloc::do_resize_in_place(ptr as *mut u8, size, extra, f.alignment, f.zero)
+}
+
+#[no_mangle]
+pub extern "C" fn sallocx(ptr: *const libc::c_void, _flags: i32) -> usize {
+    r3malloc::usable_size(ptr as *mut u8)
+}
+
+#[no_mangle]
+pub extern "C" fn dallocx(ptr: *mut libc::c_void, flags: i32) {
+    AllocxFlags::new(flags).free(ptr as *mut u8)
+}
+
+#[no_mangle]
+pub extern "C" fn sdallocx(ptr: *mut libc::c_void, size: usize, flags: i32) {
+    let f = AllocxFlags::new(flags);
+    if f.tcache {
+        r3malloc::do_free_aligned_sized(ptr as *mut u8, f.alignment, size)
+    } else {
+        r3malloc::do_free_direct(ptr as *mut u8)
+    }
+}
+
+/// Returns the usable size mallocx would give for `size` and `flags` without allocating,
+/// or 0 when the size is too large.
+#[no_mangle]
+pub extern "C" fn nallocx(size: usize, flags: i32) -> usize {
+    if unlikely(size > isize::MAX as usize) {
+        return 0;
+    }
+
+    r3malloc::alloc_usable_size(AllocxFlags::new(flags).alignment, size)
+}
+
+/// C entry point of [`ctl`].
+///
+/// # Safety
+///
+/// `name` must be null or a NUL-terminated string; see [`ctl`] for the other arguments.
+#[no_mangle]
+pub unsafe extern "C" fn r3malloc_ctl(
+    name: *const libc::c_char,
+    oldp: *mut libc::c_void,
+    oldlenp: *mut usize,
+    newp: *mut libc::c_void,
+    newlen: usize,
+) -> i32 {
+    if unlikely(name.is_null()) {
+        return libc::EINVAL;
+    }
+
+    match core::ffi::CStr::from_ptr(name).to_str() {
+        Ok(name) => ctl::ctl(name, oldp, oldlenp, newp, newlen),
+        Err(_) => libc::ENOENT,
+    }
+}
+
+/// Writes human readable allocator statistics through `write_cb`, or to stderr when it is
+/// null, like jemalloc's `malloc_stats_print`. An `opts` string containing 'b' leaves out
+/// the per size class table.
+///
+/// # Safety
+///
+/// `opts` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn r3malloc_stats_print(
+    write_cb: Option<stats::WriteCb>,
+    cbopaque: *mut libc::c_void,
+    opts: *const libc::c_char,
+) {
+    r3malloc::ensure_init();
+
+    let opts = if opts.is_null() {
+        &[]
+    } else {
+        core::ffi::CStr::from_ptr(opts).to_bytes()
+    };
+
+    let mut w = stats::StatsWriter::new(write_cb, cbopaque);
+    let _ = stats::print(&mut w, opts);
+}
+
+#[cfg(target_env = "gnu")]
+#[no_mangle]
+pub extern "C" fn mallinfo2() -> libc::mallinfo2 {
+    r3malloc::ensure_init();
+    stats::mallinfo2()
+}
+
+#[no_mangle]
+pub extern "C" fn malloc_stats() {
+    r3malloc::ensure_init();
+
+    let mut w = stats::StatsWriter::new(None, null_mut());
+    let _ = stats::print_malloc_stats(&mut w);
+}
+
+extern "C" fn write_to_file(fp: *mut libc::c_void, s: *const libc::c_char) {
+    unsafe { libc::fputs(s, fp as *mut libc::FILE) };
+}
+
+/// glibc-compatible `malloc_info`: writes the XML description of the heap to `fp`.
+///
+/// # Safety
+///
+/// `fp` must be an open stdio stream.
+#[no_mangle]
+pub unsafe extern "C" fn malloc_info(options: i32, fp: *mut libc::FILE) -> i32 {
+    // "options ... must be specified as zero"
+    if unlikely(options != 0) {
+        *libc::__errno_location() = libc::EINVAL;
+        return -1;
+    }
+
+    r3malloc::ensure_init();
+
+    let mut w = stats::StatsWriter::new(Some(write_to_file), fp as *mut libc::c_void);
+    match stats::print_malloc_info(&mut w) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Writes the live heap samples to `filename` in the text format understood by pprof,
+/// or to "r3malloc.<pid>.<seq>.heap" when it is null. Returns 0 on success, -1 otherwise.
+///
+/// # Safety
+///
+/// `filename` must be null or a NUL-terminated string.
+#[cfg(feature = "profiling")]
+#[no_mangle]
+pub unsafe extern "C" fn r3malloc_prof_dump(filename: *const libc::c_char) -> i32 {
+    r3malloc::ensure_init();
+
+    let path = if filename.is_null() {
+        None
+    } else {
+        Some(core::ffi::CStr::from_ptr(filename))
+    };
+
+    if prof::dump(path) {
+        0
+    } else {
+        -1
     }
 }
 
@@ -0,0 +1,70 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// spins this many times before giving the CPU away
+const SPIN_LIMIT: u32 = 100;
+
+// Minimal test-and-test-and-set lock for the few places that cannot be lock-free.
+// It never allocates, so it is safe to take from inside malloc.
+pub struct SpinLock {
+    locked: AtomicBool,
+}
+
+pub struct SpinLockGuard<'a> {
+    lock: &'a SpinLock,
+}
+
+impl SpinLock {
+    pub const fn new() -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_> {
+        let mut spins = 0;
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                if spins < SPIN_LIMIT {
+                    spin_loop();
+                    spins += 1;
+                } else {
+                    unsafe { libc::sched_yield() };
+                }
+            }
+        }
+
+        SpinLockGuard { lock: self }
+    }
+
+    // for a lock held across calls, like the fork handlers do
+    pub fn raw_lock(&self) {
+        core::mem::forget(self.lock());
+    }
+
+    // only for a lock taken with raw_lock
+    pub unsafe fn raw_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_>> {
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => Some(SpinLockGuard { lock: self }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Drop for SpinLockGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
@@ -1,25 +1,55 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use libc::c_char;
 use crate::defines::parse_usize;
 
+// compile time defaults, R3MALLOC_CONF can override them at startup. Logging code is only
+// compiled in with LOG, so the log option can't turn it on without it.
 // Credit to https://stackoverflow.com/questions/38088067/equivalent-of-func-or-function-in-rust
 pub const LOG: bool = match option_env!("LOG") {
     Some(_) => true,
     None => false,
 };
-pub const SAVE_LOG: bool = match option_env!("SAVE_LOG") {
+const SAVE_LOG: bool = match option_env!("SAVE_LOG") {
     Some(_) => true,
     None => false,
 };
 // by default, save on every invocation of save_log
-pub const SAVE_PERIOD: usize = match option_env!("SAVE_PERIOD") {
+const SAVE_PERIOD: usize = match option_env!("SAVE_PERIOD") {
     Some(sp) => parse_usize(sp),
     None => 0,
 };
+static LOG_ENABLED: AtomicBool = AtomicBool::new(LOG);
+static SAVE_LOG_ENABLED: AtomicBool = AtomicBool::new(SAVE_LOG);
+static SAVE_LOG_PERIOD: AtomicUsize = AtomicUsize::new(SAVE_PERIOD);
 // would be nice to make it compile-time inputted (as above)
 // but I cannot figure out how to null-terminate the input &str
 pub const FILE_PATH: &str = "log.txt\0";
 static mut CURR_PERIOD: usize = 0;
 
+pub fn log_enabled() -> bool {
+    LOG && LOG_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_log_enabled(on: bool) {
+    LOG_ENABLED.store(on, Ordering::Relaxed)
+}
+
+pub fn save_log_enabled() -> bool {
+    SAVE_LOG_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_save_log_enabled(on: bool) {
+    SAVE_LOG_ENABLED.store(on, Ordering::Relaxed)
+}
+
+pub fn save_period() -> usize {
+    SAVE_LOG_PERIOD.load(Ordering::Relaxed)
+}
+
+pub fn set_save_period(period: usize) {
+    SAVE_LOG_PERIOD.store(period, Ordering::Relaxed)
+}
+
 #[macro_export]
 macro_rules! function {
     () => {{
@@ -36,8 +66,7 @@ macro_rules! function {
 #[macro_export]
 macro_rules! log_debug {
     ( $( $x: expr ), * ) => {{
-        use crate::log::LOG;
-        if LOG {
+        if crate::log::LOG && crate::log::log_enabled() {
             use libc_print::{libc_println, libc_print};
             libc_print!("{}: {} {}", core::file!(), core::line!(), crate::function!());
             $(
@@ -57,8 +86,7 @@ macro_rules! log_debug {
 #[macro_export]
 macro_rules! log_err {
     ( $( $x: expr ), * ) => {{
-        use crate::log::LOG;
-        if LOG {
+        if crate::log::LOG && crate::log::log_enabled() {
             use libc_print::{libc_println, libc_print};
             libc_eprint!("{}:{} {}", core::file!(), core::line!(), crate::function!());
             $(
@@ -71,13 +99,14 @@ macro_rules! log_err {
 
 #[inline(never)]
 pub fn save_log(log: &str) {
-    if !SAVE_LOG {
+    if !save_log_enabled() {
         return;
     }
 
     unsafe {
-        if SAVE_PERIOD != 0 {
-            if CURR_PERIOD != SAVE_PERIOD {
+        let period = save_period();
+        if period != 0 {
+            if CURR_PERIOD != period {
                 CURR_PERIOD += 1;
                 return;
             } else {
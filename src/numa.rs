@@ -0,0 +1,134 @@
+use crate::arena;
+use crate::defines::sysfs_list_len;
+use crate::pages::get_huge_superblocks;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use libc::{c_ulong, c_void};
+use likely_stable::likely;
+
+// Optional NUMA awareness. Node k allocates from arena k, arena 0 being HEAPS, and new
+// superblocks of those arenas prefer the memory of their node. Threads that aren't bound to
+// an arena use the one of the node they run on, looked up again each time they refill their
+// cache, so partial superblocks are only shared within a node.
+//
+// A `numa_nodes` larger than the machine's node count simulates the extra nodes. Threads are
+// then spread over all nodes round-robin, since there is no topology to follow, and
+// superblocks of nodes that don't exist get no memory policy.
+const MAX_NODES: usize = 64;
+const MPOL_PREFERRED: i32 = 1;
+const NODES_ONLINE: &[u8] = b"/sys/devices/system/node/online\0";
+
+static NUMA: AtomicBool = AtomicBool::new(false);
+// 0 uses the machine's node count
+static NUMA_NODES: AtomicUsize = AtomicUsize::new(0);
+
+// set once by init
+static NODES: AtomicUsize = AtomicUsize::new(1);
+static MACHINE_NODES: AtomicUsize = AtomicUsize::new(1);
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+// simulated node of the calling thread
+#[thread_local]
+static mut THREAD_NODE: usize = usize::MAX;
+
+pub fn get_enabled() -> bool {
+    NUMA.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    NUMA.store(enabled, Ordering::Relaxed)
+}
+
+pub fn get_nodes_opt() -> usize {
+    NUMA_NODES.load(Ordering::Relaxed)
+}
+
+pub fn set_nodes_opt(nodes: usize) {
+    NUMA_NODES.store(nodes, Ordering::Relaxed)
+}
+
+// nodes with their own arena, 1 when NUMA awareness is off
+pub fn nodes() -> usize {
+    NODES.load(Ordering::Relaxed)
+}
+
+fn machine_nodes() -> usize {
+    sysfs_list_len(NODES_ONLINE)
+}
+
+// Creates the arenas of nodes 1 and up. Called by init_malloc, before the application can
+// create arenas of its own, so node k gets arena k.
+pub fn init() {
+    if !get_enabled() {
+        return;
+    }
+
+    let machine = machine_nodes();
+    MACHINE_NODES.store(machine, Ordering::Relaxed);
+
+    let wanted = match get_nodes_opt() {
+        0 => machine,
+        nodes => nodes,
+    };
+    let mut nodes = 1;
+    while nodes < core::cmp::min(wanted, MAX_NODES) && arena::create() == Some(nodes) {
+        nodes += 1;
+    }
+    NODES.store(nodes, Ordering::Relaxed);
+}
+
+fn thread_node(nodes: usize) -> usize {
+    if nodes > MACHINE_NODES.load(Ordering::Relaxed) {
+        unsafe {
+            if THREAD_NODE == usize::MAX {
+                THREAD_NODE = NEXT_THREAD.fetch_add(1, Ordering::Relaxed) % nodes;
+            }
+            return THREAD_NODE;
+        }
+    }
+
+    let mut node: u32 = 0;
+    let ret = unsafe {
+        libc::syscall(libc::SYS_getcpu, null_mut::<u32>(), &mut node as *mut u32, null_mut::<c_void>())
+    };
+    if ret != 0 {
+        return 0;
+    }
+    node as usize % nodes
+}
+
+// arena of an unbound thread
+#[inline(always)]
+pub fn thread_arena() -> usize {
+    let nodes = nodes();
+    if likely(nodes == 1) {
+        return 0;
+    }
+    thread_node(nodes)
+}
+
+// Asks for the pages of a new superblock of `arena` to come from its node. Pages that were
+// touched before keep their place. Superblocks carved out of huge pages are left alone,
+// setting a policy on part of a huge page splits it.
+pub fn place(superblock: *mut u8, size: usize, arena: usize) {
+    let nodes = nodes();
+    if nodes == 1 || arena >= nodes || arena >= MACHINE_NODES.load(Ordering::Relaxed) {
+        return;
+    }
+    if get_huge_superblocks() {
+        return;
+    }
+
+    let mask: c_ulong = 1 << arena;
+    unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            superblock,
+            size,
+            MPOL_PREFERRED,
+            &mask as *const c_ulong,
+            c_ulong::BITS as c_ulong,
+            0,
+        )
+    };
+}
@@ -0,0 +1,536 @@
+use crate::defines::LG_PAGE;
+use crate::lock::SpinLock;
+use crate::pages::{page_alloc, page_free};
+use crate::purge::{self, PurgeMode};
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+// Superblocks and large blocks up to a region are carved out of 64 MiB regions as runs
+// of pages. Freed runs coalesce with free neighbours in the same state, so pages freed
+// by one size class serve any other without a syscall. Dirty runs are purged after the
+// decay time and a region is only unmapped once it is entirely free and more than
+// max_retained bytes are retained.
+const LG_REGION_SZ: usize = 26;
+pub const REGION_SZ: usize = 1 << LG_REGION_SZ;
+const LG_REGION_PAGES: usize = LG_REGION_SZ - LG_PAGE;
+const REGION_PAGES: usize = 1 << LG_REGION_PAGES;
+// 256 GiB, run ids must fit in a u32
+const MAX_REGIONS: usize = 4096;
+const REGION_META_SZ: usize = REGION_PAGES * size_of::<PageMeta>();
+
+// runs shorter than NUM_BINS pages have a bin of their own, longer ones share the last
+const NUM_BINS: usize = 64;
+const NUM_STATES: usize = 3;
+
+// run id = region index << LG_REGION_PAGES | page index
+const NIL: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum RunState {
+    Dirty = 0,
+    // never touched or purged with MADV_DONTNEED, reads as zero
+    Zero = 1,
+    // purged with MADV_FREE
+    Lazy = 2,
+}
+
+// Kept on the first and last page of every run, free or not, so that a freed run can
+// find its neighbours.
+#[derive(Clone, Copy)]
+struct PageMeta {
+    npages: u32,
+    free: bool,
+    state: RunState,
+    // bin list, first page of free runs only
+    prev: u32,
+    next: u32,
+    // dirty runs, oldest first, first page only
+    lru_prev: u32,
+    lru_next: u32,
+    freed_ms: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    base: *mut u8,
+    meta: *mut PageMeta,
+}
+
+const REGION_INITIALIZER: Region = Region {
+    base: null_mut(),
+    meta: null_mut(),
+};
+
+struct PageHeap {
+    regions: [Region; MAX_REGIONS],
+    // indices of the mapped regions sorted by base address
+    sorted: [u16; MAX_REGIONS],
+    nregions: usize,
+    bins: [[u32; NUM_BINS]; NUM_STATES],
+    lru_head: u32,
+    lru_tail: u32,
+}
+
+pub static PAGE_HEAP_LOCK: SpinLock = SpinLock::new();
+static mut PAGE_HEAP: PageHeap = PageHeap {
+    regions: [REGION_INITIALIZER; MAX_REGIONS],
+    sorted: [0; MAX_REGIONS],
+    nregions: 0,
+    bins: [[NIL; NUM_BINS]; NUM_STATES],
+    lru_head: NIL,
+    lru_tail: NIL,
+};
+
+fn bin_idx(npages: usize) -> usize {
+    core::cmp::min(npages, NUM_BINS - 1)
+}
+
+impl PageHeap {
+    fn meta(&self, id: u32) -> &PageMeta {
+        let region = &self.regions[id as usize >> LG_REGION_PAGES];
+        unsafe { &*region.meta.add(id as usize & (REGION_PAGES - 1)) }
+    }
+
+    fn meta_mut(&mut self, id: u32) -> &mut PageMeta {
+        let region = &self.regions[id as usize >> LG_REGION_PAGES];
+        unsafe { &mut *region.meta.add(id as usize & (REGION_PAGES - 1)) }
+    }
+
+    fn addr(&self, id: u32) -> *mut u8 {
+        let region = &self.regions[id as usize >> LG_REGION_PAGES];
+        unsafe { region.base.add((id as usize & (REGION_PAGES - 1)) << LG_PAGE) }
+    }
+
+    // position in `sorted` of the region holding addr, or where it would be inserted
+    fn search(&self, addr: usize) -> Result<usize, usize> {
+        self.sorted[..self.nregions].binary_search_by(|&idx| {
+            let base = self.regions[idx as usize].base as usize;
+            if addr < base {
+                core::cmp::Ordering::Greater
+            } else if addr >= base + REGION_SZ {
+                core::cmp::Ordering::Less
+            } else {
+                core::cmp::Ordering::Equal
+            }
+        })
+    }
+
+    fn run_id(&self, addr: *mut u8) -> Option<u32> {
+        let pos = self.search(addr as usize).ok()?;
+        let region_idx = self.sorted[pos] as usize;
+        let page = (addr as usize - self.regions[region_idx].base as usize) >> LG_PAGE;
+        Some(((region_idx << LG_REGION_PAGES) | page) as u32)
+    }
+
+    fn set_run(&mut self, id: u32, npages: usize, free: bool, state: RunState) {
+        for page in [id, id + npages as u32 - 1] {
+            let meta = self.meta_mut(page);
+            meta.npages = npages as u32;
+            meta.free = free;
+            meta.state = state;
+        }
+    }
+
+    fn bin_insert(&mut self, id: u32) {
+        let (state, npages) = {
+            let meta = self.meta(id);
+            (meta.state as usize, meta.npages as usize)
+        };
+        let head = self.bins[state][bin_idx(npages)];
+
+        let meta = self.meta_mut(id);
+        meta.prev = NIL;
+        meta.next = head;
+        if head != NIL {
+            self.meta_mut(head).prev = id;
+        }
+        self.bins[state][bin_idx(npages)] = id;
+    }
+
+    fn bin_remove(&mut self, id: u32) {
+        let (state, npages, prev, next) = {
+            let meta = self.meta(id);
+            (meta.state as usize, meta.npages as usize, meta.prev, meta.next)
+        };
+
+        if prev != NIL {
+            self.meta_mut(prev).next = next;
+        } else {
+            self.bins[state][bin_idx(npages)] = next;
+        }
+        if next != NIL {
+            self.meta_mut(next).prev = prev;
+        }
+    }
+
+    fn lru_push(&mut self, id: u32) {
+        let tail = self.lru_tail;
+        let meta = self.meta_mut(id);
+        meta.lru_prev = tail;
+        meta.lru_next = NIL;
+
+        if tail != NIL {
+            self.meta_mut(tail).lru_next = id;
+        } else {
+            self.lru_head = id;
+        }
+        self.lru_tail = id;
+    }
+
+    fn lru_remove(&mut self, id: u32) {
+        let (prev, next) = {
+            let meta = self.meta(id);
+            (meta.lru_prev, meta.lru_next)
+        };
+
+        if prev != NIL {
+            self.meta_mut(prev).lru_next = next;
+        } else {
+            self.lru_head = next;
+        }
+        if next != NIL {
+            self.meta_mut(next).lru_prev = prev;
+        } else {
+            self.lru_tail = prev;
+        }
+    }
+
+    // moves the LRU entry of `old` to `new` without changing its position
+    fn lru_replace(&mut self, old: u32, new: u32) {
+        let (prev, next, freed_ms) = {
+            let meta = self.meta(old);
+            (meta.lru_prev, meta.lru_next, meta.freed_ms)
+        };
+
+        let meta = self.meta_mut(new);
+        meta.lru_prev = prev;
+        meta.lru_next = next;
+        meta.freed_ms = freed_ms;
+
+        if prev != NIL {
+            self.meta_mut(prev).lru_next = new;
+        } else {
+            self.lru_head = new;
+        }
+        if next != NIL {
+            self.meta_mut(next).lru_prev = new;
+        } else {
+            self.lru_tail = new;
+        }
+    }
+
+    fn insert_free(&mut self, id: u32, npages: usize, state: RunState, freed_ms: u64) {
+        self.set_run(id, npages, true, state);
+        self.bin_insert(id);
+        if state == RunState::Dirty {
+            self.meta_mut(id).freed_ms = freed_ms;
+            self.lru_push(id);
+        }
+        purge::on_retain(npages << LG_PAGE, state == RunState::Dirty);
+    }
+
+    fn remove_free(&mut self, id: u32) -> (usize, RunState) {
+        let (npages, state) = {
+            let meta = self.meta(id);
+            (meta.npages as usize, meta.state)
+        };
+
+        self.bin_remove(id);
+        if state == RunState::Dirty {
+            self.lru_remove(id);
+        }
+        purge::on_reuse(npages << LG_PAGE, state == RunState::Dirty);
+        (npages, state)
+    }
+
+    // merges the run with free neighbours in the same state
+    fn coalesce(&mut self, mut id: u32, mut npages: usize, state: RunState) -> (u32, usize) {
+        let page = id as usize & (REGION_PAGES - 1);
+
+        if page > 0 {
+            let prev = self.meta(id - 1);
+            if prev.free && prev.state == state {
+                let prev_id = id - prev.npages;
+                npages += self.remove_free(prev_id).0;
+                id = prev_id;
+            }
+        }
+
+        let page = id as usize & (REGION_PAGES - 1);
+        if page + npages < REGION_PAGES {
+            let next = self.meta(id + npages as u32);
+            if next.free && next.state == state {
+                npages += self.remove_free(id + npages as u32).0;
+            }
+        }
+
+        (id, npages)
+    }
+
+    fn find(&self, npages: usize, state: RunState) -> u32 {
+        let bins = &self.bins[state as usize];
+
+        if let Some(&id) = bins[bin_idx(npages)..NUM_BINS - 1].iter().find(|&&id| id != NIL) {
+            return id;
+        }
+
+        // best fit among the long runs
+        let mut best = NIL;
+        let mut best_npages = usize::MAX;
+        let mut id = bins[NUM_BINS - 1];
+        while id != NIL {
+            let meta = self.meta(id);
+            let run_npages = meta.npages as usize;
+            if run_npages >= npages && run_npages < best_npages {
+                best = id;
+                best_npages = run_npages;
+            }
+            id = meta.next;
+        }
+        best
+    }
+
+    fn map_region(&mut self) -> bool {
+        if self.nregions == MAX_REGIONS {
+            return false;
+        }
+
+        let base = unsafe { page_alloc::<u8>(REGION_SZ) };
+        if base.is_null() {
+            return false;
+        }
+        let meta = unsafe { page_alloc::<PageMeta>(REGION_META_SZ) };
+        if meta.is_null() {
+            unsafe { page_free(base, REGION_SZ) };
+            return false;
+        }
+
+        let idx = self.regions.iter().position(|r| r.base.is_null()).unwrap();
+        self.regions[idx] = Region { base, meta };
+
+        let pos = self.search(base as usize).unwrap_err();
+        self.sorted.copy_within(pos..self.nregions, pos + 1);
+        self.sorted[pos] = idx as u16;
+        self.nregions += 1;
+
+        self.insert_free((idx << LG_REGION_PAGES) as u32, REGION_PAGES, RunState::Zero, 0);
+        true
+    }
+
+    // gives a region back to the OS once it is a single free run and too much is retained
+    fn maybe_unmap(&mut self, id: u32) {
+        let first = id & !(REGION_PAGES as u32 - 1);
+        let meta = self.meta(first);
+        if !meta.free
+            || meta.npages as usize != REGION_PAGES
+            || purge::retained() <= purge::get_max_retained()
+        {
+            return;
+        }
+
+        self.remove_free(first);
+
+        let idx = first as usize >> LG_REGION_PAGES;
+        let region = self.regions[idx];
+        let pos = self.search(region.base as usize).unwrap();
+        self.sorted.copy_within(pos + 1..self.nregions, pos);
+        self.nregions -= 1;
+        self.regions[idx] = REGION_INITIALIZER;
+
+        unsafe {
+            page_free(region.base, REGION_SZ);
+            page_free(region.meta as *mut u8, REGION_META_SZ);
+        }
+    }
+
+    // takes the first `npages` of a free run, the tail stays free and keeps its age
+    fn split_off(&mut self, id: u32, npages: usize) -> RunState {
+        let (run_npages, state) = {
+            let meta = self.meta(id);
+            (meta.npages as usize, meta.state)
+        };
+
+        self.bin_remove(id);
+        if run_npages > npages {
+            let rest = id + npages as u32;
+            if state == RunState::Dirty {
+                self.lru_replace(id, rest);
+            }
+            self.set_run(rest, run_npages - npages, true, state);
+            self.bin_insert(rest);
+        } else if state == RunState::Dirty {
+            self.lru_remove(id);
+        }
+
+        purge::on_reuse(npages << LG_PAGE, state == RunState::Dirty);
+        state
+    }
+
+    fn alloc(&mut self, npages: usize) -> Option<(*mut u8, bool)> {
+        // reusing dirty pages saves page faults, purged ones are only taken when needed
+        for state in [RunState::Dirty, RunState::Lazy, RunState::Zero] {
+            let id = self.find(npages, state);
+            if id == NIL {
+                continue;
+            }
+
+            self.split_off(id, npages);
+            self.set_run(id, npages, false, state);
+            return Some((self.addr(id), state == RunState::Zero));
+        }
+
+        if !self.map_region() {
+            return None;
+        }
+        self.alloc(npages)
+    }
+
+    // extends an allocated run into the free run after it
+    fn grow(&mut self, id: u32, npages: usize, new_npages: usize) -> Option<bool> {
+        let page = id as usize & (REGION_PAGES - 1);
+        if page + new_npages > REGION_PAGES {
+            return None;
+        }
+
+        let next_id = id + npages as u32;
+        let next = self.meta(next_id);
+        if !next.free || (next.npages as usize) < new_npages - npages {
+            return None;
+        }
+
+        let state = self.split_off(next_id, new_npages - npages);
+        self.set_run(id, new_npages, false, state);
+        Some(state == RunState::Zero)
+    }
+
+    fn shrink(&mut self, id: u32, npages: usize, new_npages: usize, now: u64) {
+        let state = self.meta(id).state;
+        self.set_run(id, new_npages, false, state);
+        self.free(id + new_npages as u32, npages - new_npages, now);
+    }
+
+    fn free(&mut self, id: u32, npages: usize, now: u64) {
+        let (id, npages) = self.coalesce(id, npages, RunState::Dirty);
+        self.insert_free(id, npages, RunState::Dirty, now);
+        self.maybe_unmap(id);
+    }
+
+    // a run whose pages can't be purged stays dirty
+    fn purge(&mut self, id: u32, mode: PurgeMode) -> bool {
+        let npages = self.meta(id).npages as usize;
+        if !purge::advise(self.addr(id), npages << LG_PAGE, mode) {
+            return false;
+        }
+        self.remove_free(id);
+
+        let state = match mode {
+            PurgeMode::DontNeed => RunState::Zero,
+            PurgeMode::Free => RunState::Lazy,
+        };
+        let (id, npages) = self.coalesce(id, npages, state);
+        self.insert_free(id, npages, state, 0);
+        self.maybe_unmap(id);
+        true
+    }
+
+    fn decay(&mut self, now: u64) {
+        let decay_ms = purge::get_decay_ms();
+        if decay_ms < 0 {
+            return;
+        }
+
+        let mode = purge::get_mode();
+        while self.lru_head != NIL {
+            let oldest = self.lru_head;
+            if self.meta(oldest).freed_ms + decay_ms as u64 > now {
+                break;
+            }
+            if !self.purge(oldest, mode) {
+                // tried again after another decay time
+                self.lru_remove(oldest);
+                self.meta_mut(oldest).freed_ms = now;
+                self.lru_push(oldest);
+                break;
+            }
+        }
+    }
+
+    fn purge_all(&mut self) {
+        let mode = purge::get_mode();
+        // purged runs only coalesce with purged neighbours, the next dirty run stays put
+        let mut id = self.lru_head;
+        while id != NIL {
+            let next = self.meta(id).lru_next;
+            self.purge(id, mode);
+            id = next;
+        }
+    }
+}
+
+fn page_heap() -> &'static mut PageHeap {
+    unsafe { &mut *core::ptr::addr_of_mut!(PAGE_HEAP) }
+}
+
+// Returns `size` bytes (a multiple of PAGE, at most REGION_SZ) and whether they are zero.
+pub fn alloc(size: usize) -> Option<(*mut u8, bool)> {
+    assert!(size <= REGION_SZ);
+    let now = purge::now_ms();
+
+    let _guard = PAGE_HEAP_LOCK.lock();
+    let heap = page_heap();
+    heap.decay(now);
+    heap.alloc(size >> LG_PAGE)
+}
+
+// Takes back memory from alloc, returns false if it did not come from the page heap.
+pub fn free(ptr: *mut u8, size: usize) -> bool {
+    let now = purge::now_ms();
+
+    let _guard = PAGE_HEAP_LOCK.lock();
+    let heap = page_heap();
+    let id = match heap.run_id(ptr) {
+        Some(id) => id,
+        None => return false,
+    };
+
+    heap.free(id, size >> LG_PAGE, now);
+    heap.decay(now);
+    true
+}
+
+// Extends memory from alloc to `new_size` bytes without moving it. Returns whether the
+// added pages are zero, None when the pages after it are not free.
+pub fn grow(ptr: *mut u8, size: usize, new_size: usize) -> Option<bool> {
+    let _guard = PAGE_HEAP_LOCK.lock();
+    let heap = page_heap();
+    let id = heap.run_id(ptr)?;
+    heap.grow(id, size >> LG_PAGE, new_size >> LG_PAGE)
+}
+
+// Frees the pages of memory from alloc past `new_size`, returns false if it did not
+// come from the page heap.
+pub fn shrink(ptr: *mut u8, size: usize, new_size: usize) -> bool {
+    let now = purge::now_ms();
+
+    let _guard = PAGE_HEAP_LOCK.lock();
+    let heap = page_heap();
+    let id = match heap.run_id(ptr) {
+        Some(id) => id,
+        None => return false,
+    };
+
+    heap.shrink(id, size >> LG_PAGE, new_size >> LG_PAGE, now);
+    heap.decay(now);
+    true
+}
+
+// purges the runs that have been free for the decay time, for the background thread
+pub fn decay(now: u64) {
+    let _guard = PAGE_HEAP_LOCK.lock();
+    page_heap().decay(now);
+}
+
+pub fn purge_all() {
+    let _guard = PAGE_HEAP_LOCK.lock();
+    page_heap().purge_all();
+}
@@ -1,6 +1,100 @@
-use crate::defines::PAGE_MASK;
+use crate::defines::{page_ceiling, PAGE_MASK};
+use crate::lock::SpinLock;
+use crate::page_heap::{self, REGION_SZ};
+use crate::purge;
+use crate::size_classes::MAX_SZ_IDX;
+use crate::stats;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use libc::*;
 
+pub const HUGE_PAGE: usize = 2 << 20;
+const HUGE_PAGE_MASK: usize = HUGE_PAGE - 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HugePages {
+    Off = 0,
+    // transparent huge pages, requested with madvise(MADV_HUGEPAGE)
+    Thp = 1,
+    // pages reserved in hugetlbfs, falls back to THP when none are left
+    Hugetlb = 2,
+}
+
+impl HugePages {
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"off" => Some(HugePages::Off),
+            b"thp" => Some(HugePages::Thp),
+            b"hugetlb" => Some(HugePages::Hugetlb),
+            _ => None,
+        }
+    }
+
+    // NUL-terminated, so it can be handed out through ctl
+    pub fn name(self) -> &'static str {
+        match self {
+            HugePages::Off => "off\0",
+            HugePages::Thp => "thp\0",
+            HugePages::Hugetlb => "hugetlb\0",
+        }
+    }
+
+    fn from_u8(mode: u8) -> Self {
+        match mode {
+            1 => HugePages::Thp,
+            2 => HugePages::Hugetlb,
+            _ => HugePages::Off,
+        }
+    }
+}
+
+static HUGE_PAGES: AtomicU8 = AtomicU8::new(HugePages::Off as u8);
+// large allocations below this stay on regular pages
+static HUGE_THRESHOLD: AtomicUsize = AtomicUsize::new(HUGE_PAGE);
+
+// Whether superblocks are carved out of huge regions. Carved superblocks can't be unmapped
+// one by one, so the setting is latched by the first superblock allocation.
+const SB_HUGE_ON: u8 = 1;
+const SB_HUGE_LATCHED: u8 = 2;
+static SB_HUGE: AtomicU8 = AtomicU8::new(0);
+
+pub fn get_huge_pages() -> HugePages {
+    HugePages::from_u8(HUGE_PAGES.load(Ordering::Relaxed))
+}
+
+pub fn set_huge_pages(mode: HugePages) {
+    HUGE_PAGES.store(mode as u8, Ordering::Relaxed)
+}
+
+pub fn get_huge_threshold() -> usize {
+    HUGE_THRESHOLD.load(Ordering::Relaxed)
+}
+
+pub fn set_huge_threshold(threshold: usize) {
+    HUGE_THRESHOLD.store(threshold, Ordering::Relaxed)
+}
+
+pub fn get_huge_superblocks() -> bool {
+    SB_HUGE.load(Ordering::Relaxed) & SB_HUGE_ON != 0
+}
+
+// fails once superblocks have been allocated
+pub fn set_huge_superblocks(enable: bool) -> bool {
+    let new = if enable { SB_HUGE_ON } else { 0 };
+    let mut old = SB_HUGE.load(Ordering::Relaxed);
+
+    loop {
+        if old & SB_HUGE_LATCHED != 0 {
+            return (old & SB_HUGE_ON != 0) == enable;
+        }
+
+        match SB_HUGE.compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return true,
+            Err(cur) => old = cur,
+        }
+    }
+}
+
 pub unsafe fn page_alloc<T>(size: usize) -> *mut T {
     core::assert_eq!(size & PAGE_MASK, 0);
 
@@ -16,6 +110,7 @@ pub unsafe fn page_alloc<T>(size: usize) -> *mut T {
         return core::ptr::null_mut();
     }
 
+    stats::on_map(size);
     ptr as *mut T
 }
 
@@ -34,6 +129,7 @@ pub unsafe fn page_alloc_overcommit<T>(size: usize) -> *mut T {
         return core::ptr::null_mut();
     }
 
+    stats::on_reserve(size);
     ptr as *mut T
 }
 
@@ -41,4 +137,214 @@ pub unsafe fn page_free(ptr: *mut u8, size: usize) {
     core::assert_eq!(size & PAGE_MASK, 0);
     let ret = munmap(ptr as *mut c_void, size);
     core::assert_eq!(ret, 0);
+    stats::on_unmap(size);
+}
+
+pub unsafe fn page_free_overcommit(ptr: *mut u8, size: usize) {
+    core::assert_eq!(size & PAGE_MASK, 0);
+    let ret = munmap(ptr as *mut c_void, size);
+    core::assert_eq!(ret, 0);
+    stats::on_unreserve(size);
+}
+
+unsafe fn map_huge_aligned(size: usize) -> *mut u8 {
+    assert_eq!(size & HUGE_PAGE_MASK, 0);
+
+    // map one huge page more than needed and trim both ends to the alignment
+    let ptr = mmap(
+        null_mut(),
+        size + HUGE_PAGE,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANON,
+        -1,
+        0,
+    );
+    if ptr == MAP_FAILED {
+        return null_mut();
+    }
+
+    let addr = ptr as usize;
+    let aligned = (addr + HUGE_PAGE_MASK) & !HUGE_PAGE_MASK;
+    if aligned > addr {
+        munmap(ptr, aligned - addr);
+    }
+    if aligned + size < addr + size + HUGE_PAGE {
+        munmap((aligned + size) as *mut c_void, addr + HUGE_PAGE - aligned);
+    }
+
+    // only a hint, THP may be disabled system wide
+    madvise(aligned as *mut c_void, size, MADV_HUGEPAGE);
+    aligned as *mut u8
+}
+
+// Maps `size` bytes (a multiple of HUGE_PAGE) of huge pages as configured.
+unsafe fn huge_alloc(mode: HugePages, size: usize) -> *mut u8 {
+    if mode == HugePages::Hugetlb {
+        let ptr = mmap(
+            null_mut(),
+            size,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANON | MAP_HUGETLB,
+            -1,
+            0,
+        );
+        if ptr != MAP_FAILED {
+            return ptr as *mut u8;
+        }
+    }
+
+    map_huge_aligned(size)
+}
+
+fn is_huge(size: usize) -> bool {
+    get_huge_pages() != HugePages::Off && size >= get_huge_threshold()
+}
+
+// What large_alloc maps for `size` bytes, unless it has to fall back from huge pages.
+pub fn large_size(size: usize) -> usize {
+    let size = page_ceiling(size);
+    if is_huge(size) {
+        (size + HUGE_PAGE_MASK) & !HUGE_PAGE_MASK
+    } else {
+        size
+    }
+}
+
+// Allocates a large block, on huge pages when they are enabled and the block is big enough.
+// Returns the block, the size that was mapped, which is what large_free needs back,
+// and whether the block is known to be zero.
+pub unsafe fn large_alloc(size: usize) -> (*mut u8, usize, bool) {
+    let size = page_ceiling(size);
+    let mode = get_huge_pages();
+    let huge = is_huge(size);
+
+    if !huge && size <= REGION_SZ {
+        if let Some((ptr, zeroed)) = page_heap::alloc(size) {
+            return (ptr, size, zeroed);
+        }
+        return (page_alloc::<u8>(size), size, true);
+    }
+
+    let mapped = large_size(size);
+    if let Some((ptr, zeroed)) = purge::reuse(mapped) {
+        return (ptr, mapped, zeroed);
+    }
+
+    if !huge {
+        return (page_alloc::<u8>(size), size, true);
+    }
+
+    let ptr = huge_alloc(mode, mapped);
+    if ptr.is_null() {
+        return (page_alloc::<u8>(size), size, true);
+    }
+
+    stats::on_map(mapped);
+    (ptr, mapped, true)
+}
+
+pub unsafe fn large_free(ptr: *mut u8, size: usize) {
+    if !page_heap::free(ptr, size) {
+        purge::release(ptr, size)
+    }
+}
+
+// Superblocks are handed out from a huge region; freed ones go to a free list per size
+// since parts of a huge mapping can't be returned without splitting it.
+struct SbFreeList {
+    size: usize,
+    head: *mut u8,
+}
+
+struct SbRegions {
+    cur: *mut u8,
+    end: *mut u8,
+    free: [SbFreeList; MAX_SZ_IDX],
+}
+
+const SB_FREE_LIST_INITIALIZER: SbFreeList = SbFreeList {
+    size: 0,
+    head: null_mut(),
+};
+
+pub static SB_LOCK: SpinLock = SpinLock::new();
+static mut SB_REGIONS: SbRegions = SbRegions {
+    cur: null_mut(),
+    end: null_mut(),
+    free: [SB_FREE_LIST_INITIALIZER; MAX_SZ_IDX],
+};
+
+impl SbRegions {
+    fn free_list(&mut self, size: usize) -> Option<&mut SbFreeList> {
+        let idx = self.free.iter().position(|l| l.size == size || l.size == 0)?;
+        let list = &mut self.free[idx];
+        list.size = size;
+        Some(list)
+    }
+
+    unsafe fn alloc(&mut self, size: usize) -> (*mut u8, bool) {
+        if let Some(list) = self.free_list(size) {
+            let sb = list.head;
+            if !sb.is_null() {
+                list.head = *(sb as *mut *mut u8);
+                return (sb, false);
+            }
+        }
+
+        // the tail of the current region is wasted when the superblock doesn't fit
+        if self.cur.is_null() || (self.end as usize - self.cur as usize) < size {
+            // huge pages may have been switched off since, the region is still used
+            let region = match get_huge_pages() {
+                HugePages::Off => page_alloc::<u8>(HUGE_PAGE),
+                mode => {
+                    let region = huge_alloc(mode, HUGE_PAGE);
+                    if !region.is_null() {
+                        stats::on_map(HUGE_PAGE);
+                    }
+                    region
+                }
+            };
+            if region.is_null() {
+                return (null_mut(), false);
+            }
+
+            self.cur = region;
+            self.end = region.add(HUGE_PAGE);
+        }
+
+        let sb = self.cur;
+        self.cur = sb.add(size);
+        (sb, true)
+    }
+
+    unsafe fn free(&mut self, sb: *mut u8, size: usize) {
+        let list = self.free_list(size).expect("more superblock sizes than size classes");
+        *(sb as *mut *mut u8) = list.head;
+        list.head = sb;
+    }
+}
+
+// Returns a superblock and whether it is known to be zero.
+pub unsafe fn sb_alloc(size: usize) -> (*mut u8, bool) {
+    let sb_huge = SB_HUGE.fetch_or(SB_HUGE_LATCHED, Ordering::Relaxed) & SB_HUGE_ON != 0;
+    if !sb_huge {
+        if let Some(sb) = page_heap::alloc(size) {
+            return sb;
+        }
+        return (page_alloc::<u8>(size), true);
+    }
+
+    let _guard = SB_LOCK.lock();
+    (*core::ptr::addr_of_mut!(SB_REGIONS)).alloc(size)
+}
+
+pub unsafe fn sb_free(sb: *mut u8, size: usize) {
+    // latched, so every superblock came from the same place
+    if !get_huge_superblocks() {
+        large_free(sb, size);
+        return;
+    }
+
+    let _guard = SB_LOCK.lock();
+    (*core::ptr::addr_of_mut!(SB_REGIONS)).free(sb, size);
 }
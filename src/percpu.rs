@@ -0,0 +1,279 @@
+use crate::arena;
+use crate::defines::{page_ceiling, sysfs_list_len};
+use crate::pages::page_alloc;
+use crate::size_classes::MAX_SZ_IDX;
+use core::mem::size_of;
+use core::ptr::{addr_of_mut, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use likely_stable::unlikely;
+
+// Optional per-CPU caches. Instead of a cache per thread, every CPU gets one bin per size
+// class, so cached memory follows the number of CPUs rather than the number of threads.
+// A bin is a stack of block pointers changed only inside restartable sequences: the kernel
+// restarts a sequence that gets preempted, migrated or interrupted by a signal before its
+// single committing store, so the bins of a CPU need no locking.
+//
+// Threads that can't register rseq, and threads bound to an arena, keep using their thread
+// cache. Neither cache runs APF in this mode; bins have a fixed number of slots, and half of
+// a full bin goes back to the heap.
+pub const CPU_BIN_SLOTS: usize = 64;
+const POSSIBLE_CPUS: &[u8] = b"/sys/devices/system/cpu/possible\0";
+
+#[repr(C)]
+struct CpuBin {
+    len: usize,
+    slots: [*mut u8; CPU_BIN_SLOTS],
+}
+
+const BIN_SZ: usize = size_of::<CpuBin>();
+// each CPU's bins start on a page of their own
+const CPU_SZ: usize = page_ceiling(BIN_SZ * MAX_SZ_IDX);
+
+// struct rseq of the kernel ABI, the original 32 byte layout
+#[repr(C, align(32))]
+pub struct Rseq {
+    cpu_id_start: u32,
+    cpu_id: u32,
+    rseq_cs: u64,
+    flags: u32,
+    pad: [u32; 3],
+}
+
+// the one glibc registers with, which other registrations have to use as well
+const RSEQ_SIG: u32 = 0x53053053;
+#[cfg(target_arch = "x86_64")]
+const SYS_RSEQ: libc::c_long = 334;
+
+extern "C" {
+    // set by glibc 2.35 and later, which registers an rseq area for every thread
+    #[linkage = "extern_weak"]
+    static __rseq_offset: *const isize;
+    #[linkage = "extern_weak"]
+    static __rseq_size: *const u32;
+}
+
+static PERCPU_CACHE: AtomicBool = AtomicBool::new(false);
+
+// set once by init, 0 when the per-CPU caches are off
+static NCPUS: AtomicUsize = AtomicUsize::new(0);
+static BINS: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+
+// rseq area of the calling thread, null when it can't have one
+#[thread_local]
+static mut THREAD_RSEQ: *mut Rseq = null_mut();
+#[thread_local]
+static mut CHECKED: bool = false;
+// registered by us when glibc doesn't
+#[thread_local]
+static mut OWN_RSEQ: Rseq = Rseq {
+    cpu_id_start: 0,
+    cpu_id: 0,
+    rseq_cs: 0,
+    flags: 0,
+    pad: [0; 3],
+};
+
+pub fn get_enabled() -> bool {
+    PERCPU_CACHE.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    PERCPU_CACHE.store(enabled, Ordering::Relaxed)
+}
+
+// CPUs with bins, 0 when the per-CPU caches are off
+#[inline(always)]
+pub fn ncpus() -> usize {
+    NCPUS.load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+pub fn active() -> bool {
+    ncpus() != 0
+}
+
+#[cfg(target_arch = "x86_64")]
+fn thread_pointer() -> *mut u8 {
+    let tp: *mut u8;
+    unsafe {
+        core::arch::asm!("mov {}, qword ptr fs:[0]", out(reg) tp, options(nostack, readonly, preserves_flags))
+    };
+    tp
+}
+
+#[cfg(target_arch = "x86_64")]
+fn register() -> *mut Rseq {
+    unsafe {
+        if !__rseq_size.is_null() && !__rseq_offset.is_null() && *__rseq_size != 0 {
+            return thread_pointer().offset(*__rseq_offset) as *mut Rseq;
+        }
+
+        let area = addr_of_mut!(OWN_RSEQ);
+        let ret = libc::syscall(SYS_RSEQ, area, size_of::<Rseq>() as u32, 0, RSEQ_SIG);
+        if ret != 0 {
+            return null_mut();
+        }
+        area
+    }
+}
+
+// only x86-64 has the critical sections below
+#[cfg(not(target_arch = "x86_64"))]
+fn register() -> *mut Rseq {
+    null_mut()
+}
+
+// Maps the bins of every possible CPU. Called by init_malloc, the calling thread has to be
+// able to use rseq or everything stays in thread caches.
+pub fn init() {
+    if !get_enabled() || thread_rseq().is_null() {
+        return;
+    }
+
+    let ncpus = sysfs_list_len(POSSIBLE_CPUS);
+    let bins = unsafe { page_alloc::<u8>(ncpus * CPU_SZ) };
+    if bins.is_null() {
+        return;
+    }
+
+    BINS.store(bins, Ordering::Relaxed);
+    NCPUS.store(ncpus, Ordering::Release);
+}
+
+// rseq area to reach the calling thread's CPU cache with, null when it uses its thread cache
+#[inline(always)]
+pub fn thread_rseq() -> *mut Rseq {
+    unsafe {
+        if unlikely(!CHECKED) {
+            THREAD_RSEQ = register();
+            CHECKED = true;
+        }
+        if arena::is_bound() {
+            return null_mut();
+        }
+        THREAD_RSEQ
+    }
+}
+
+#[inline(always)]
+fn bins(sc_idx: usize) -> *mut u8 {
+    unsafe { BINS.load(Ordering::Relaxed).add(sc_idx * BIN_SZ) }
+}
+
+// Both sequences below find the bin of the CPU the thread runs on and change it with a
+// single store of its length. The abort handler, preceded by the signature the kernel
+// checks, starts over, which also sets rseq_cs again after the kernel cleared it.
+
+// takes a block from the current CPU's bin, null when it is empty
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn pop(rseq: *mut Rseq, sc_idx: usize) -> *mut u8 {
+    let block: *mut u8;
+    unsafe {
+        core::arch::asm!(
+            ".pushsection __rseq_cs, \"aw\"",
+            ".balign 32",
+            "3:",
+            ".long 0, 0",
+            ".quad 7f, 8f - 7f, 4f",
+            ".popsection",
+            "6:",
+            "lea {tmp}, [rip + 3b]",
+            "mov qword ptr [{rseq} + 8], {tmp}",
+            "7:",
+            "xor {block:e}, {block:e}",
+            "mov {bin:e}, dword ptr [{rseq} + 4]",
+            "cmp {bin}, {ncpus}",
+            "jae 8f",
+            "imul {bin}, {bin}, {cpu_sz}",
+            "add {bin}, {bins}",
+            "mov {tmp}, qword ptr [{bin}]",
+            "test {tmp}, {tmp}",
+            "jz 8f",
+            "mov {block}, qword ptr [{bin} + 8*{tmp}]",
+            "dec {tmp}",
+            "mov qword ptr [{bin}], {tmp}",
+            "8:",
+            "jmp 5f",
+            ".byte 0x0f, 0xb9, 0x3d",
+            ".long {sig}",
+            "4:",
+            "jmp 6b",
+            "5:",
+            rseq = in(reg) rseq,
+            bins = in(reg) bins(sc_idx),
+            ncpus = in(reg) ncpus(),
+            cpu_sz = const CPU_SZ,
+            sig = const RSEQ_SIG,
+            block = out(reg) block,
+            bin = out(reg) _,
+            tmp = out(reg) _,
+            options(nostack),
+        );
+    }
+    block
+}
+
+// gives a block to the current CPU's bin, false when it is full
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+pub fn push(rseq: *mut Rseq, sc_idx: usize, block: *mut u8) -> bool {
+    let pushed: usize;
+    unsafe {
+        core::arch::asm!(
+            ".pushsection __rseq_cs, \"aw\"",
+            ".balign 32",
+            "3:",
+            ".long 0, 0",
+            ".quad 7f, 8f - 7f, 4f",
+            ".popsection",
+            "6:",
+            "lea {tmp}, [rip + 3b]",
+            "mov qword ptr [{rseq} + 8], {tmp}",
+            "7:",
+            "xor {pushed:e}, {pushed:e}",
+            "mov {bin:e}, dword ptr [{rseq} + 4]",
+            "cmp {bin}, {ncpus}",
+            "jae 8f",
+            "imul {bin}, {bin}, {cpu_sz}",
+            "add {bin}, {bins}",
+            "mov {tmp}, qword ptr [{bin}]",
+            "cmp {tmp}, {slots}",
+            "jae 8f",
+            // a slot above the length is free to write before the commit
+            "mov qword ptr [{bin} + 8*{tmp} + 8], {block}",
+            "inc {tmp}",
+            "mov {pushed:e}, 1",
+            "mov qword ptr [{bin}], {tmp}",
+            "8:",
+            "jmp 5f",
+            ".byte 0x0f, 0xb9, 0x3d",
+            ".long {sig}",
+            "4:",
+            "jmp 6b",
+            "5:",
+            rseq = in(reg) rseq,
+            bins = in(reg) bins(sc_idx),
+            ncpus = in(reg) ncpus(),
+            block = in(reg) block,
+            cpu_sz = const CPU_SZ,
+            slots = const CPU_BIN_SLOTS,
+            sig = const RSEQ_SIG,
+            pushed = out(reg) pushed,
+            bin = out(reg) _,
+            tmp = out(reg) _,
+            options(nostack),
+        );
+    }
+    pushed != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn pop(_rseq: *mut Rseq, _sc_idx: usize) -> *mut u8 {
+    null_mut()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn push(_rseq: *mut Rseq, _sc_idx: usize, _block: *mut u8) -> bool {
+    false
+}
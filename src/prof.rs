@@ -0,0 +1,490 @@
+use crate::heap::Descriptor;
+use crate::lock::SpinLock;
+use crate::pagemap::SPAGEMAP;
+use crate::pages::page_alloc_overcommit;
+use crate::stats::{StatsWriter, WriteCb};
+use core::ffi::CStr;
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use libc::{c_char, c_void};
+use likely_stable::{likely, unlikely};
+
+// 512 KiB on average between two samples, same default as jemalloc's lg_prof_sample
+const PROF_SAMPLE_INTERVAL: usize = 1 << 19;
+const PROF_MAX_FRAMES: usize = 32;
+// frame pointer chains that jump further than this are treated as corrupt
+const PROF_MAX_FRAME_SZ: usize = 1 << 20;
+const PROF_TABLE_LG_CAP: u32 = 16;
+const PROF_TABLE_CAP: usize = 1 << PROF_TABLE_LG_CAP;
+const PROF_TABLE_SZ: usize = PROF_TABLE_CAP * size_of::<Sample>();
+const PROF_PREFIX: &str = "r3malloc";
+// distinct call stacks listed by the leak report
+const PROF_LEAK_SITES: usize = 16;
+
+static PROF_ACTIVE: AtomicBool = AtomicBool::new(true);
+static PROF_FINAL: AtomicBool = AtomicBool::new(false);
+static PROF_INTERVAL: AtomicUsize = AtomicUsize::new(PROF_SAMPLE_INTERVAL);
+static PROF_DUMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[thread_local]
+static mut BYTES_UNTIL_SAMPLE: isize = 0;
+#[thread_local]
+static mut PRNG: u64 = 0;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    // 0 marks an empty slot
+    ptr: usize,
+    size: usize,
+    nframes: usize,
+    frames: [usize; PROF_MAX_FRAMES],
+}
+
+// open addressing with linear probing and backward shift deletion, so that long running
+// processes never fill up with tombstones
+struct SampleTable {
+    samples: *mut Sample,
+    len: usize,
+}
+
+pub static TABLE_LOCK: SpinLock = SpinLock::new();
+static mut TABLE: SampleTable = SampleTable {
+    samples: null_mut(),
+    len: 0,
+};
+
+impl SampleTable {
+    fn home(ptr: usize) -> usize {
+        (ptr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - PROF_TABLE_LG_CAP)
+    }
+
+    fn slot(&self, i: usize) -> &Sample {
+        unsafe { &*self.samples.add(i) }
+    }
+
+    fn slot_mut(&mut self, i: usize) -> &mut Sample {
+        unsafe { &mut *self.samples.add(i) }
+    }
+
+    fn insert(&mut self, sample: &Sample) -> bool {
+        if self.samples.is_null() {
+            self.samples = unsafe { page_alloc_overcommit::<Sample>(PROF_TABLE_SZ) };
+            if self.samples.is_null() {
+                return false;
+            }
+        }
+
+        // keep probe sequences short, losing a sample is harmless
+        if self.len >= PROF_TABLE_CAP / 4 * 3 {
+            return false;
+        }
+
+        let mut i = Self::home(sample.ptr);
+        while self.slot(i).ptr != 0 {
+            i = (i + 1) & (PROF_TABLE_CAP - 1);
+        }
+        *self.slot_mut(i) = *sample;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, ptr: usize) -> bool {
+        if self.samples.is_null() {
+            return false;
+        }
+
+        let mut i = Self::home(ptr);
+        loop {
+            let p = self.slot(i).ptr;
+            if p == ptr {
+                break;
+            }
+            if p == 0 {
+                return false;
+            }
+            i = (i + 1) & (PROF_TABLE_CAP - 1);
+        }
+
+        let mut j = i;
+        loop {
+            j = (j + 1) & (PROF_TABLE_CAP - 1);
+            let p = self.slot(j).ptr;
+            if p == 0 {
+                break;
+            }
+
+            // move the entry back if its home is not between the hole and its position
+            let k = Self::home(p);
+            let movable = if i <= j { k <= i || k > j } else { k <= i && k > j };
+            if movable {
+                *self.slot_mut(i) = *self.slot(j);
+                i = j;
+            }
+        }
+
+        self.slot_mut(i).ptr = 0;
+        self.len -= 1;
+        true
+    }
+
+    fn for_each<F: FnMut(&Sample)>(&self, mut f: F) {
+        if self.samples.is_null() {
+            return;
+        }
+
+        for i in 0..PROF_TABLE_CAP {
+            let sample = self.slot(i);
+            if sample.ptr != 0 {
+                f(sample);
+            }
+        }
+    }
+}
+
+pub fn get_active() -> bool {
+    PROF_ACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn set_active(active: bool) {
+    PROF_ACTIVE.store(active, Ordering::Relaxed)
+}
+
+pub fn get_final() -> bool {
+    PROF_FINAL.load(Ordering::Relaxed)
+}
+
+pub fn set_final(dump_at_exit: bool) {
+    PROF_FINAL.store(dump_at_exit, Ordering::Relaxed)
+}
+
+pub fn get_interval() -> usize {
+    PROF_INTERVAL.load(Ordering::Relaxed)
+}
+
+pub fn set_interval(interval: usize) {
+    PROF_INTERVAL.store(interval, Ordering::Relaxed)
+}
+
+// log2 of x in (0, 1], precise to about 1e-4 which is plenty for picking sample distances
+fn fast_log2(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64 - 1023;
+    let m = f64::from_bits((bits & ((1 << 52) - 1)) | (1023 << 52));
+
+    exponent as f64
+        + (-1.741_793_9 + (2.821_202_6 + (-1.469_956_8 + (0.447_179_55 - 0.056_570_851 * m) * m) * m) * m)
+}
+
+// Distance to the next sample, exponentially distributed with the configured mean,
+// which makes sampling a Poisson process over allocated bytes as pprof expects.
+fn next_interval() -> isize {
+    let mean = get_interval();
+    if mean == 0 {
+        return 0;
+    }
+
+    let r = unsafe {
+        // xorshift64*
+        PRNG ^= PRNG >> 12;
+        PRNG ^= PRNG << 25;
+        PRNG ^= PRNG >> 27;
+        PRNG.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    };
+    let u = ((r >> 11) + 1) as f64 / (1u64 << 53) as f64;
+
+    (-fast_log2(u) * core::f64::consts::LN_2 * mean as f64) as isize + 1
+}
+
+#[cold]
+fn sample_slow() -> bool {
+    unsafe {
+        if PRNG == 0 {
+            // the first crossing only arms the counter with a random distance
+            PRNG = (core::ptr::addr_of!(PRNG) as u64) ^ (libc::time(null_mut()) as u64) | 1;
+            BYTES_UNTIL_SAMPLE = next_interval();
+            return false;
+        }
+
+        BYTES_UNTIL_SAMPLE = next_interval();
+    }
+
+    get_active()
+}
+
+#[inline(always)]
+pub fn should_sample(size: usize) -> bool {
+    unsafe {
+        BYTES_UNTIL_SAMPLE -= size as isize;
+        if likely(BYTES_UNTIL_SAMPLE > 0) {
+            return false;
+        }
+    }
+
+    sample_slow()
+}
+
+// Walks the frame pointer chain. Code built without frame pointers (see README) ends the
+// walk early; every frame is checked to lie a little above the previous one on the stack.
+#[inline(never)]
+fn backtrace(frames: &mut [usize; PROF_MAX_FRAMES]) -> usize {
+    let mut fp: usize;
+    let sp: usize;
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        core::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        return 0;
+    }
+
+    let mut prev = sp;
+    let mut nframes = 0;
+
+    while nframes < PROF_MAX_FRAMES {
+        if fp < prev || fp - prev > PROF_MAX_FRAME_SZ || fp & (size_of::<usize>() - 1) != 0 {
+            break;
+        }
+
+        let ret = unsafe { *(fp as *const usize).add(1) };
+        if ret == 0 {
+            break;
+        }
+
+        frames[nframes] = ret;
+        nframes += 1;
+        prev = fp + 2 * size_of::<usize>();
+        fp = unsafe { *(fp as *const usize) };
+    }
+
+    nframes
+}
+
+#[cold]
+pub fn record(ptr: *mut u8, size: usize) {
+    let mut sample = Sample {
+        ptr: ptr as usize,
+        size,
+        nframes: 0,
+        frames: [0; PROF_MAX_FRAMES],
+    };
+    sample.nframes = backtrace(&mut sample.frames);
+
+    let desc = unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) }.get_desc();
+    assert!(!desc.is_null());
+
+    let _guard = TABLE_LOCK.lock();
+    if unsafe { (*core::ptr::addr_of_mut!(TABLE)).insert(&sample) } {
+        unsafe { (*desc).get_nsampled().fetch_add(1, Ordering::Relaxed) };
+    }
+}
+
+// descriptors count their sampled blocks, so frees of unsampled blocks never take the lock
+#[inline(always)]
+pub fn on_free(ptr: *mut u8, desc: *mut Descriptor) {
+    if likely(desc.is_null() || unsafe { (*desc).get_nsampled().load(Ordering::Relaxed) } == 0) {
+        return;
+    }
+
+    let _guard = TABLE_LOCK.lock();
+    if unsafe { (*core::ptr::addr_of_mut!(TABLE)).remove(ptr as usize) } {
+        unsafe { (*desc).get_nsampled().fetch_sub(1, Ordering::Relaxed) };
+    }
+}
+
+extern "C" fn write_to_fd(fd: *mut c_void, s: *const c_char) {
+    let len = unsafe { libc::strlen(s) };
+    unsafe { libc::write(fd as usize as i32, s as *const c_void, len) };
+}
+
+// "MAPPED_LIBRARIES:" lets pprof symbolize addresses of shared objects
+fn write_maps(fd: i32) {
+    let path = b"/proc/self/maps\0";
+    let maps = unsafe { libc::open(path.as_ptr() as *const c_char, libc::O_RDONLY) };
+    if maps < 0 {
+        return;
+    }
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = unsafe { libc::read(maps, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        unsafe { libc::write(fd, buf.as_ptr() as *const c_void, n as usize) };
+    }
+
+    unsafe { libc::close(maps) };
+}
+
+// Writes live samples in the legacy gperftools "heap_v2" text format that pprof reads.
+pub fn dump_to_fd(fd: i32) -> bool {
+    let _guard = TABLE_LOCK.lock();
+    let table = unsafe { &*core::ptr::addr_of!(TABLE) };
+
+    let mut count = 0;
+    let mut bytes = 0;
+    table.for_each(|s| {
+        count += 1;
+        bytes += s.size;
+    });
+
+    let mut w = StatsWriter::new(Some(write_to_fd as WriteCb), fd as usize as *mut c_void);
+    let mut ok = writeln!(
+        w,
+        "heap profile: {}: {} [{}: {}] @ heap_v2/{}",
+        count, bytes, count, bytes, get_interval()
+    )
+    .is_ok();
+
+    table.for_each(|s| {
+        ok &= write!(w, "1: {} [1: {}] @", s.size, s.size).is_ok();
+        for frame in &s.frames[..s.nframes] {
+            ok &= write!(w, " {:#x}", frame).is_ok();
+        }
+        ok &= writeln!(w).is_ok();
+    });
+
+    ok &= writeln!(w, "\nMAPPED_LIBRARIES:").is_ok();
+    w.flush();
+    write_maps(fd);
+
+    ok
+}
+
+#[derive(Clone, Copy)]
+struct LeakSite {
+    count: usize,
+    bytes: usize,
+    sample: Sample,
+}
+
+impl LeakSite {
+    fn same_stack(&self, s: &Sample) -> bool {
+        self.sample.frames[..self.sample.nframes] == s.frames[..s.nframes]
+    }
+}
+
+// Groups the samples still in the table by call stack for the leak report, largest first.
+// Stacks past the first PROF_LEAK_SITES are summed up on a last line.
+pub fn write_leak_sites<W: Write>(w: &mut W) -> core::fmt::Result {
+    let empty = LeakSite {
+        count: 0,
+        bytes: 0,
+        sample: Sample { ptr: 0, size: 0, nframes: 0, frames: [0; PROF_MAX_FRAMES] },
+    };
+    let mut sites = [empty; PROF_LEAK_SITES];
+    let mut nsites = 0;
+    let (mut other_count, mut other_bytes) = (0, 0);
+
+    {
+        let _guard = TABLE_LOCK.lock();
+        let table = unsafe { &*core::ptr::addr_of!(TABLE) };
+        table.for_each(|s| {
+            let site = match sites[..nsites].iter_mut().find(|site| site.same_stack(s)) {
+                Some(site) => site,
+                None if nsites < PROF_LEAK_SITES => {
+                    sites[nsites].sample = *s;
+                    nsites += 1;
+                    &mut sites[nsites - 1]
+                }
+                None => {
+                    other_count += 1;
+                    other_bytes += s.size;
+                    return;
+                }
+            };
+            site.count += 1;
+            site.bytes += s.size;
+        });
+    }
+
+    if nsites == 0 {
+        return Ok(());
+    }
+
+    sites[..nsites].sort_unstable_by_key(|site| core::cmp::Reverse(site.bytes));
+    writeln!(w, "r3malloc:   sampled allocation sites:")?;
+    for site in &sites[..nsites] {
+        write!(w, "r3malloc:     {} leaked, {} bytes at", site.count, site.bytes)?;
+        for frame in &site.sample.frames[..site.sample.nframes] {
+            write!(w, " {:#x}", frame)?;
+        }
+        writeln!(w)?;
+    }
+    if other_count > 0 {
+        writeln!(w, "r3malloc:     {} leaked, {} bytes at other sites", other_count, other_bytes)?;
+    }
+
+    Ok(())
+}
+
+// fixed size buffer for building file names without allocating
+struct PathBuf {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for PathBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // keep one byte for the terminating NUL
+        if self.len + s.len() >= self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+// Dumps to `path`, or to "r3malloc.<pid>.<seq>.heap" in the working directory when it is None.
+pub fn dump(path: Option<&CStr>) -> bool {
+    let mut name = PathBuf { buf: [0; 256], len: 0 };
+    let path = match path {
+        Some(path) => path.as_ptr(),
+        None => {
+            let seq = PROF_DUMP_SEQ.fetch_add(1, Ordering::Relaxed);
+            if write!(name, "{}.{}.{}.heap", PROF_PREFIX, unsafe { libc::getpid() }, seq).is_err() {
+                return false;
+            }
+            name.buf.as_ptr() as *const c_char
+        }
+    };
+
+    let fd = unsafe { libc::open(path, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, 0o644) };
+    if fd < 0 {
+        return false;
+    }
+
+    let ok = dump_to_fd(fd);
+    unsafe { libc::close(fd) };
+    ok
+}
+
+extern "C" fn prof_atexit() {
+    if !get_final() {
+        return;
+    }
+
+    let mut name = PathBuf { buf: [0; 256], len: 0 };
+    if write!(name, "{}.{}.f.heap", PROF_PREFIX, unsafe { libc::getpid() }).is_ok() {
+        let path = unsafe { CStr::from_ptr(name.buf.as_ptr() as *const c_char) };
+        dump(Some(path));
+    }
+}
+
+// registers the final dump; atexit may allocate, so the allocator must be usable by now
+pub fn init() {
+    if unlikely(unsafe { libc::atexit(prof_atexit) } != 0) {
+        set_final(false);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::freeze;
+use crate::heap;
+use crate::lock::SpinLock;
+use crate::page_heap;
+use crate::pages::page_free;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use libc::c_void;
+
+// Decay settings and counters shared with the page heap, plus a cache for the blocks it
+// doesn't serve (huge pages and blocks over a region). Cached extents are reused by later
+// allocations of the same size and purged with madvise once unused for the decay time;
+// they are only unmapped when the cache is full or over max_retained.
+//
+// Decay runs when memory is allocated, freed or shrunk, so memory freed before the process
+// goes idle stays dirty. The optional background thread runs it periodically instead.
+const EXTENT_CAP: usize = 512;
+
+const PURGE_DECAY_MS: isize = 10_000;
+const PURGE_MAX_RETAINED: usize = 1 << 30;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PurgeMode {
+    // pages read back as zero
+    DontNeed = 0,
+    // cheaper, the kernel only reclaims the pages under memory pressure
+    Free = 1,
+}
+
+impl PurgeMode {
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"dontneed" => Some(PurgeMode::DontNeed),
+            b"free" => Some(PurgeMode::Free),
+            _ => None,
+        }
+    }
+
+    // NUL-terminated, so it can be handed out through ctl
+    pub fn name(self) -> &'static str {
+        match self {
+            PurgeMode::DontNeed => "dontneed\0",
+            PurgeMode::Free => "free\0",
+        }
+    }
+
+    fn advice(self) -> i32 {
+        match self {
+            PurgeMode::DontNeed => libc::MADV_DONTNEED,
+            PurgeMode::Free => libc::MADV_FREE,
+        }
+    }
+}
+
+// -1 never purges, 0 purges as soon as memory is freed
+static DECAY_MS: AtomicIsize = AtomicIsize::new(PURGE_DECAY_MS);
+static MODE: AtomicU8 = AtomicU8::new(PurgeMode::DontNeed as u8);
+static MAX_RETAINED: AtomicUsize = AtomicUsize::new(PURGE_MAX_RETAINED);
+static BACKGROUND: AtomicBool = AtomicBool::new(false);
+// set while a background thread exists
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+static RETAINED: AtomicUsize = AtomicUsize::new(0);
+static DIRTY: AtomicUsize = AtomicUsize::new(0);
+static NPURGE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExtentState {
+    Dirty,
+    Purged(PurgeMode),
+}
+
+#[derive(Clone, Copy)]
+struct Extent {
+    addr: *mut u8,
+    size: usize,
+    freed_ms: u64,
+    state: ExtentState,
+}
+
+const EXTENT_INITIALIZER: Extent = Extent {
+    addr: core::ptr::null_mut(),
+    size: 0,
+    freed_ms: 0,
+    state: ExtentState::Dirty,
+};
+
+// oldest extent first
+struct ExtentCache {
+    extents: [Extent; EXTENT_CAP],
+    len: usize,
+}
+
+pub static CACHE_LOCK: SpinLock = SpinLock::new();
+static mut CACHE: ExtentCache = ExtentCache {
+    extents: [EXTENT_INITIALIZER; EXTENT_CAP],
+    len: 0,
+};
+
+pub fn now_ms() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+// Returns false when the pages keep their contents, e.g. in locked mappings or for
+// MADV_FREE on hugetlb.
+pub fn advise(addr: *mut u8, size: usize, mode: PurgeMode) -> bool {
+    if unsafe { libc::madvise(addr as *mut c_void, size, mode.advice()) } != 0 {
+        return false;
+    }
+    NPURGE.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+pub fn on_retain(size: usize, dirty: bool) {
+    RETAINED.fetch_add(size, Ordering::Relaxed);
+    if dirty {
+        DIRTY.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+pub fn on_reuse(size: usize, dirty: bool) {
+    RETAINED.fetch_sub(size, Ordering::Relaxed);
+    if dirty {
+        DIRTY.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl ExtentCache {
+    fn purge(extent: &mut Extent, mode: PurgeMode) -> bool {
+        if !advise(extent.addr, extent.size, mode) {
+            return false;
+        }
+        extent.state = ExtentState::Purged(mode);
+        DIRTY.fetch_sub(extent.size, Ordering::Relaxed);
+        true
+    }
+
+    fn decay(&mut self, now: u64) {
+        let decay_ms = get_decay_ms();
+        if decay_ms < 0 {
+            return;
+        }
+
+        let mode = get_mode();
+        let mut i = 0;
+        for _ in 0..self.len {
+            let extent = &mut self.extents[i];
+            if now < extent.freed_ms + decay_ms as u64 {
+                break;
+            }
+            if extent.state == ExtentState::Dirty && !Self::purge(extent, mode) {
+                // stays dirty and is tried again after another decay time, as the newest
+                extent.freed_ms = now;
+                self.extents[i..self.len].rotate_left(1);
+                continue;
+            }
+            i += 1;
+        }
+    }
+
+    fn purge_all(&mut self) {
+        let mode = get_mode();
+        for extent in &mut self.extents[..self.len] {
+            if extent.state == ExtentState::Dirty {
+                Self::purge(extent, mode);
+            }
+        }
+    }
+
+    fn remove(&mut self, i: usize) -> Extent {
+        let extent = self.extents[i];
+        self.extents.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+
+        on_reuse(extent.size, extent.state == ExtentState::Dirty);
+        extent
+    }
+
+    // unmaps the oldest extents until `size` more bytes fit
+    fn evict(&mut self, size: usize) {
+        let max_retained = get_max_retained();
+        while self.len > 0
+            && (self.len == EXTENT_CAP || RETAINED.load(Ordering::Relaxed) + size > max_retained)
+        {
+            let extent = self.remove(0);
+            unsafe { page_free(extent.addr, extent.size) };
+        }
+    }
+
+    fn retain(&mut self, addr: *mut u8, size: usize, now: u64) -> bool {
+        self.evict(size);
+        if RETAINED.load(Ordering::Relaxed) + size > get_max_retained() {
+            return false;
+        }
+
+        self.extents[self.len] = Extent {
+            addr,
+            size,
+            freed_ms: now,
+            state: ExtentState::Dirty,
+        };
+        self.len += 1;
+
+        on_retain(size, true);
+        true
+    }
+
+    // the most recently freed extent is the most likely to still be in cache
+    fn take(&mut self, size: usize) -> Option<Extent> {
+        let i = self.extents[..self.len].iter().rposition(|e| e.size == size)?;
+        Some(self.remove(i))
+    }
+}
+
+fn cache() -> &'static mut ExtentCache {
+    unsafe { &mut *core::ptr::addr_of_mut!(CACHE) }
+}
+
+// Keeps a freed extent mapped for reuse, unmapping it when it doesn't fit.
+pub fn release(addr: *mut u8, size: usize) {
+    let now = now_ms();
+
+    let retained = {
+        let _guard = CACHE_LOCK.lock();
+        let cache = cache();
+        let retained = cache.retain(addr, size, now);
+        cache.decay(now);
+        retained
+    };
+
+    if !retained {
+        unsafe { page_free(addr, size) };
+    }
+}
+
+// Returns a retained extent of exactly `size` bytes and whether it reads as zero.
+pub fn reuse(size: usize) -> Option<(*mut u8, bool)> {
+    let now = now_ms();
+
+    let _guard = CACHE_LOCK.lock();
+    let cache = cache();
+    cache.decay(now);
+
+    let extent = cache.take(size)?;
+    Some((extent.addr, extent.state == ExtentState::Purged(PurgeMode::DontNeed)))
+}
+
+// purges all retained memory now, regardless of the decay time, and gives back the
+// descriptor chunks that are entirely free
+pub fn purge_all() {
+    heap::reclaim();
+    page_heap::purge_all();
+
+    let _guard = CACHE_LOCK.lock();
+    cache().purge_all();
+}
+
+// Sleeps a tenth of the decay time between passes, so memory is purged at most that much
+// later than it decays.
+fn background_interval_ms() -> i64 {
+    match get_decay_ms() {
+        decay_ms if decay_ms < 0 => 1000,
+        decay_ms => (decay_ms as i64 / 10).clamp(10, 1000),
+    }
+}
+
+extern "C" fn background(_arg: *mut c_void) -> *mut c_void {
+    loop {
+        while get_background() {
+            let interval = background_interval_ms();
+            let ts = libc::timespec {
+                tv_sec: interval / 1000,
+                tv_nsec: interval % 1000 * 1_000_000,
+            };
+            unsafe { libc::nanosleep(&ts, core::ptr::null_mut()) };
+
+            let now = now_ms();
+            let _guard = freeze::guard();
+            page_heap::decay(now);
+            let _lock = CACHE_LOCK.lock();
+            cache().decay(now);
+        }
+
+        // a thread that enabled it again in between saw this one still running
+        RUNNING.store(false, Ordering::SeqCst);
+        if !get_background() || RUNNING.swap(true, Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    freeze::release_thread();
+    core::ptr::null_mut()
+}
+
+// Starts the background thread if it is enabled and not running yet. pthread_create
+// allocates, so the caller must not be in the middle of changing the heap.
+pub fn maybe_start_background() {
+    if !get_background() || RUNNING.load(Ordering::Relaxed) || RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe {
+        let mut attr: libc::pthread_attr_t = core::mem::zeroed();
+        let mut thread: libc::pthread_t = 0;
+        libc::pthread_attr_init(&mut attr);
+        libc::pthread_attr_setdetachstate(&mut attr, libc::PTHREAD_CREATE_DETACHED);
+        if libc::pthread_create(&mut thread, &attr, background, core::ptr::null_mut()) != 0 {
+            RUNNING.store(false, Ordering::SeqCst);
+        }
+        libc::pthread_attr_destroy(&mut attr);
+    }
+}
+
+// the background thread doesn't exist in the child, the next large free starts it again
+pub fn postfork_child() {
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+pub fn get_decay_ms() -> isize {
+    DECAY_MS.load(Ordering::Relaxed)
+}
+
+pub fn set_decay_ms(decay_ms: isize) {
+    DECAY_MS.store(decay_ms, Ordering::Relaxed)
+}
+
+pub fn get_mode() -> PurgeMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => PurgeMode::Free,
+        _ => PurgeMode::DontNeed,
+    }
+}
+
+pub fn set_mode(mode: PurgeMode) {
+    MODE.store(mode as u8, Ordering::Relaxed)
+}
+
+pub fn get_max_retained() -> usize {
+    MAX_RETAINED.load(Ordering::Relaxed)
+}
+
+// lowering the limit takes effect the next time memory is released
+pub fn set_max_retained(max_retained: usize) {
+    MAX_RETAINED.store(max_retained, Ordering::Relaxed)
+}
+
+pub fn get_background() -> bool {
+    BACKGROUND.load(Ordering::Relaxed)
+}
+
+// a thread left running after it is turned off exits within one interval
+pub fn set_background(background: bool) {
+    BACKGROUND.store(background, Ordering::Relaxed)
+}
+
+// bytes kept mapped for reuse
+pub fn retained() -> usize {
+    RETAINED.load(Ordering::Relaxed)
+}
+
+// retained bytes not purged yet
+pub fn dirty() -> usize {
+    DIRTY.load(Ordering::Relaxed)
+}
+
+pub fn npurge() -> u64 {
+    NPURGE.load(Ordering::Relaxed)
+}
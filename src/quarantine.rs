@@ -0,0 +1,119 @@
+use crate::junk;
+use crate::pagemap::SPAGEMAP;
+use crate::size_classes::{MAX_SZ_IDX, SIZE_CLASSES};
+use core::ptr::{addr_of, addr_of_mut, null_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use libc_print::libc_eprintln;
+use likely_stable::likely;
+
+// Holds freed small blocks back from the thread cache for a while, so that a use after
+// free hits a block nobody owns instead of the next allocation of its size. Each thread
+// keeps a FIFO of at most `quarantine` bytes, linked through the first word of the blocks.
+// When freed blocks are junk filled, the fill is checked as they leave and writes to them
+// are reported.
+static MAX_BYTES: AtomicUsize = AtomicUsize::new(0);
+// blocks held by all threads, which are neither allocated nor in a thread cache
+static HELD: [AtomicUsize; MAX_SZ_IDX] = [const { AtomicUsize::new(0) }; MAX_SZ_IDX];
+
+pub fn get_max_bytes() -> usize {
+    MAX_BYTES.load(Ordering::Relaxed)
+}
+
+// 0 turns the quarantine off
+pub fn set_max_bytes(max_bytes: usize) {
+    MAX_BYTES.store(max_bytes, Ordering::Relaxed)
+}
+
+pub fn held(sc_idx: usize) -> usize {
+    HELD[sc_idx].load(Ordering::Relaxed)
+}
+
+struct Quarantine {
+    head: *mut u8,
+    tail: *mut u8,
+    bytes: usize,
+}
+
+#[thread_local]
+static mut QUARANTINE: Quarantine = Quarantine { head: null_mut(), tail: null_mut(), bytes: 0 };
+
+fn block_size(sc_idx: usize) -> usize {
+    unsafe { SIZE_CLASSES[sc_idx].get_block_size() as usize }
+}
+
+impl Quarantine {
+    fn push(&mut self, block: *mut u8, sc_idx: usize) {
+        unsafe { *(block as *mut *mut u8) = null_mut() };
+        if self.tail.is_null() {
+            self.head = block;
+        } else {
+            unsafe { *(self.tail as *mut *mut u8) = block };
+        }
+        self.tail = block;
+        self.bytes += block_size(sc_idx);
+        HELD[sc_idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // oldest block and its size class
+    fn pop(&mut self) -> (*mut u8, usize) {
+        let block = self.head;
+        self.head = unsafe { *(block as *mut *mut u8) };
+        if self.head.is_null() {
+            self.tail = null_mut();
+        }
+
+        let sc_idx = unsafe { (*addr_of!(SPAGEMAP)).get_page_info(block) }.get_sc_idx();
+        self.bytes -= block_size(sc_idx);
+        HELD[sc_idx].fetch_sub(1, Ordering::Relaxed);
+        check(block, sc_idx);
+        (block, sc_idx)
+    }
+}
+
+fn check(block: *mut u8, sc_idx: usize) {
+    if let Some(addr) = junk::overwritten_after_free(block, block_size(sc_idx)) {
+        libc_eprintln!(
+            "r3malloc: use after free: {:p} in block {:p} (size class {}) written after it was freed",
+            addr,
+            block,
+            sc_idx
+        );
+        unsafe { libc::abort() };
+    }
+}
+
+// Queues a freed block and hands the oldest ones to `release` while over the limit.
+// Returns false when the quarantine is off and the caller keeps the block.
+#[inline(always)]
+pub fn hold(block: *mut u8, sc_idx: usize, release: fn(*mut u8, usize)) -> bool {
+    let max_bytes = get_max_bytes();
+    if likely(max_bytes == 0) {
+        return false;
+    }
+
+    let q = unsafe { &mut *addr_of_mut!(QUARANTINE) };
+    q.push(block, sc_idx);
+    while q.bytes > max_bytes {
+        let (block, sc_idx) = q.pop();
+        release(block, sc_idx);
+    }
+    true
+}
+
+// releases every block held by the calling thread
+pub fn drain(release: fn(*mut u8, usize)) {
+    let q = unsafe { &mut *addr_of_mut!(QUARANTINE) };
+    while !q.head.is_null() {
+        let (block, sc_idx) = q.pop();
+        release(block, sc_idx);
+    }
+}
+
+// calls `f` with every block held by the calling thread
+pub fn for_each_held<F: FnMut(*mut u8)>(mut f: F) {
+    let mut block = unsafe { (*addr_of!(QUARANTINE)).head };
+    while !block.is_null() {
+        f(block);
+        block = unsafe { *(block as *mut *mut u8) };
+    }
+}
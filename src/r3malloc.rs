@@ -1,13 +1,34 @@
 use crate::apf::APF_INIT;
+use crate::arena;
+#[cfg(feature = "check_free")]
+use crate::check;
+use crate::conf;
 use crate::defines::{align_addr, align_val, page_ceiling, PAGE, PAGE_MASK};
+use crate::epoch;
+use crate::fork;
+use crate::freeze;
 use crate::heap::{Anchor, Descriptor, DescriptorNode, ProcHeap, SbState};
+use crate::junk;
+use crate::leak;
 use crate::log_debug;
+use crate::numa;
 use crate::pagemap::{PageInfo, SPAGEMAP};
-use crate::pages::{page_alloc, page_free};
+use crate::page_heap;
+use crate::pages::{large_alloc, large_free, large_size, sb_alloc, sb_free};
+use crate::percpu::{self, Rseq, CPU_BIN_SLOTS};
+use crate::purge;
+use crate::quarantine;
+#[cfg(feature = "profiling")]
+use crate::prof;
+#[cfg(feature = "redzone")]
+use crate::redzone::{self, front_size, padded_size};
 use crate::size_classes::{
-    compute_idx, get_size_class, init_size_class, MAX_SZ, MAX_SZ_IDX, SIZE_CLASSES,
+    compute_idx, get_size_class, init_size_class, release_size_class, MAX_SZ, MAX_SZ_IDX,
+    SIZE_CLASSES,
 };
-use crate::tcache::{TCacheBin, TCACHE};
+use crate::stats::{self, Counter};
+use crate::tcache::{TCacheBin, TCACHE, TCACHE_ENABLED};
+use crate::thread_exit;
 use atomic::Ordering;
 use core::ptr::null_mut;
 use likely_stable::{likely, unlikely};
@@ -55,7 +76,7 @@ fn register_desc(desc: &mut Descriptor) {
         sc_idx = unsafe { (*heap).get_sc_idx() };
     }
 
-    update_page_map(unsafe { Some(&*heap) }, ptr, Some(desc), sc_idx);
+    update_page_map(unsafe { heap.as_ref() }, ptr, Some(desc), sc_idx);
 }
 
 fn unregister_desc(heap: Option<&ProcHeap>, superblock: *mut u8) {
@@ -65,6 +86,8 @@ fn unregister_desc(heap: Option<&ProcHeap>, superblock: *mut u8) {
 pub fn heap_pop_partial<'a>(heap: &ProcHeap<'a>) -> *mut Descriptor<'a> {
     let list = heap.get_partial_list();
     let mut old_head;
+    // the head may be popped and its descriptor reclaimed before we read its next
+    let _pin = epoch::pin();
 
     loop {
         old_head = list.load(Ordering::SeqCst);
@@ -72,8 +95,8 @@ pub fn heap_pop_partial<'a>(heap: &ProcHeap<'a>) -> *mut Descriptor<'a> {
         if old_desc.is_null() {
             return null_mut();
         }
-        let mut new_head = unsafe { (*old_desc).get_next_partial().load(Ordering::SeqCst) };
-        let desc = new_head.get_desc();
+        let mut new_head = DescriptorNode::new(null_mut());
+        let desc = unsafe { (*old_desc).get_next_partial().load(Ordering::SeqCst) };
         let counter = old_head.get_counter();
         new_head.set_desc(desc, counter);
 
@@ -99,7 +122,7 @@ pub fn heap_push_partial(desc: *mut Descriptor) {
         unsafe {
             (*new_head.get_desc())
                 .get_next_partial()
-                .store(old_head, Ordering::SeqCst)
+                .store(old_head.get_desc(), Ordering::SeqCst)
         };
 
         match list.compare_exchange_weak(old_head, new_head, Ordering::SeqCst, Ordering::SeqCst) {
@@ -119,6 +142,9 @@ pub fn init_malloc() {
         MALLOC_INIT = true;
     }
 
+    // before anything reads the options
+    conf::init();
+
     // init page map
     unsafe { SPAGEMAP.init() };
 
@@ -128,17 +154,70 @@ pub fn init_malloc() {
             HEAPS[sz_idx].set_sc_idx(sz_idx);
         }
     }
+
+    numa::init();
+    percpu::init();
+    thread_exit::init();
+
+    #[cfg(feature = "profiling")]
+    prof::init();
+    leak::init();
+    fork::init();
 }
 
-pub fn thread_finalize() {
+// sets up the calling thread on its first allocation or free
+fn init_thread() {
+    init_size_class();
+    thread_exit::register();
+}
+
+// makes sure both the process-wide state and the calling thread's size classes exist
+#[inline(always)]
+pub fn ensure_init() {
+    if unlikely(unsafe { !MALLOC_INIT }) {
+        init_malloc();
+    }
+
+    if unlikely(unsafe { !APF_INIT }) {
+        init_thread();
+    }
+}
+
+pub fn flush_thread_cache() {
+    quarantine::drain(release_small);
     for sc_idx in 1..MAX_SZ_IDX {
         flush_cache(sc_idx, unsafe{ &mut TCACHE[sc_idx] });
     }
 }
 
-fn malloc_from_partial(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -> usize {
-    let heap = unsafe { &HEAPS[sc_idx] };
+pub fn flush_thread_cache_bin(sc_idx: usize) {
+    flush_cache(sc_idx, unsafe { &mut TCACHE[sc_idx] });
+}
+
+pub fn thread_cache_enabled() -> bool {
+    unsafe { TCACHE_ENABLED }
+}
 
+// While disabled, small blocks go straight to and from the thread's arena. The cache is
+// emptied when it is turned off, so no memory stays behind in it.
+pub fn set_thread_cache_enabled(enabled: bool) {
+    if !enabled {
+        flush_thread_cache();
+    }
+    unsafe { TCACHE_ENABLED = enabled };
+}
+
+// Gives back everything the calling thread holds. Using the allocator afterwards sets the
+// thread up again.
+pub fn thread_finalize() {
+    flush_thread_cache();
+    stats::release_thread_stats();
+    epoch::release_thread();
+    freeze::release_thread();
+    release_size_class();
+}
+
+fn malloc_from_partial(heap: &'static ProcHeap<'static>, sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -> usize {
     let desc = heap_pop_partial(heap);
     if desc.is_null() {
         return 0;
@@ -155,7 +234,7 @@ fn malloc_from_partial(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -
         if old_anchor.state() == SbState::Empty as u32 {
             unsafe { (*desc).retire() }
             // retry
-            return malloc_from_partial(sc_idx, cache, block_num);
+            return malloc_from_partial(heap, sc_idx, cache, block_num);
         }
 
         // oldAnchor must be SB_PARTIAL
@@ -203,23 +282,24 @@ fn malloc_from_partial(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -
     // so all we need do is "push" that list, a constant time op
     assert_eq!(cache.get_block_num(), 0);
     cache.push_list(block, blocks_taken);
+    stats::count(sc_idx, Counter::Partial, 1);
 
     block_num + blocks_taken as usize
 }
 
-fn malloc_from_new_sb(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -> usize {
-    let heap = unsafe { &mut HEAPS[sc_idx] };
+fn malloc_from_new_sb(heap: &'static ProcHeap<'static>, sc_idx: usize, cache: &mut TCacheBin, block_num: usize) -> usize {
     let sc = unsafe { &SIZE_CLASSES[sc_idx] };
     let desc = Descriptor::alloc();
     let block_size = sc.get_block_size();
     let maxcount = sc.get_block_num();
 
-    desc.set_heap(heap);
+    desc.set_heap(heap as *const ProcHeap as *mut ProcHeap);
     desc.set_block_size(block_size);
     desc.set_maxcount(maxcount);
-    unsafe {
-        desc.set_superblock(&mut *page_alloc::<u8>(sc.get_sb_size() as usize));
-    }
+    let (superblock, zeroed) = unsafe { sb_alloc(sc.get_sb_size() as usize) };
+    assert!(!superblock.is_null());
+    numa::place(superblock, sc.get_sb_size() as usize, heap.get_arena_idx());
+    desc.set_superblock(superblock);
 
     let mut anchor = Anchor::new();
     anchor.set_avail(maxcount);
@@ -227,8 +307,6 @@ fn malloc_from_new_sb(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) ->
     anchor.set_state(SbState::Full as u32);
     desc.get_anchor().store(anchor, Ordering::SeqCst);
 
-    let superblock: *mut u8 = desc.get_superblock() as *mut u8;
-
     for i in 0..maxcount - 1 {
         unsafe {
             let block = superblock.offset((i * block_size) as isize);
@@ -238,43 +316,60 @@ fn malloc_from_new_sb(sc_idx: usize, cache: &mut TCacheBin, block_num: usize) ->
     }
 
     cache.push_list(superblock, maxcount);
+    if zeroed {
+        cache.set_fresh(superblock, sc.get_sb_size() as usize);
+    }
 
     assert!(anchor.avail() < maxcount || anchor.state() == SbState::Full as u32);
     assert!(anchor.count() < maxcount);
 
     register_desc(desc);
     assert!(anchor.state() == SbState::Full as u32);
+    stats::count(sc_idx, Counter::NewSb, 1);
+    stats::on_sb_alloc(sc_idx, heap.get_arena_idx(), sc.get_sb_size() as usize);
 
     block_num + maxcount as usize
 }
 
 fn fill_cache(sc_idx: usize, cache: &mut TCacheBin) {
+    let _guard = freeze::guard();
+    let heap = arena::heap(arena::thread_arena(), sc_idx);
     let mut block_num = 0;
 
-    block_num = malloc_from_partial(sc_idx, cache, block_num);
+    block_num = malloc_from_partial(heap, sc_idx, cache, block_num);
 
     if block_num == 0 {
-        block_num = malloc_from_new_sb(sc_idx, cache, block_num);
+        block_num = malloc_from_new_sb(heap, sc_idx, cache, block_num);
     }
 
     let sc = unsafe { &SIZE_CLASSES[sc_idx] };
     assert!(block_num > 0);
     assert!(block_num <= sc.get_cache_block_num() as usize);
+    stats::count(sc_idx, Counter::Fill, 1);
+    stats::count(sc_idx, Counter::FillBlocks, block_num as u64);
 }
 
 fn flush_cache(sc_idx: usize, cache: &mut TCacheBin) {
+    let _guard = freeze::guard();
     let heap = unsafe { &HEAPS[sc_idx] };
     let sc = unsafe { &SIZE_CLASSES[sc_idx] };
     let sb_size = sc.get_sb_size();
     let block_size = sc.get_block_size();
     let maxcount = sc.get_block_num();
 
+    if cache.get_block_num() > 0 {
+        stats::count(sc_idx, Counter::Flush, 1);
+        stats::count(sc_idx, Counter::FlushBlocks, cache.get_block_num() as u64);
+    }
+
     while cache.get_block_num() > 0 {
         let head = cache.peek_block();
         let mut tail = head;
         let info = unsafe { SPAGEMAP.get_page_info(head) };
         let desc = info.get_desc();
         let superblock = unsafe { (*desc).get_superblock() };
+        // once the superblock is empty its descriptor may be retired and reused under us
+        let arena_idx = unsafe { (*(*desc).get_heap()).get_arena_idx() };
         let mut block_count = 1;
 
         while cache.get_block_num() > block_count {
@@ -343,7 +438,12 @@ fn flush_cache(sc_idx: usize, cache: &mut TCacheBin) {
             unregister_desc(Some(heap), superblock);
 
             unsafe {
-                page_free(superblock, heap.get_size_class().get_sb_size() as usize);
+                sb_free(superblock, heap.get_size_class().get_sb_size() as usize);
+            }
+            stats::on_sb_free(sc_idx, arena_idx, sb_size as usize);
+            // a full superblock isn't on the partial list, where its descriptor would be retired
+            if old_anchor.state() == SbState::Full as u32 {
+                unsafe { (*desc).retire() };
             }
         } else if old_anchor.state() == SbState::Full as u32 {
             heap_push_partial(desc);
@@ -352,6 +452,7 @@ fn flush_cache(sc_idx: usize, cache: &mut TCacheBin) {
 }
 
 fn cut_cache(sc_idx: usize, cache: &mut TCacheBin, cut_by: u32) {
+    let _guard = freeze::guard();
     let heap = unsafe { &HEAPS[sc_idx] };
     let sc = unsafe { &SIZE_CLASSES[sc_idx] };
     let sb_size = sc.get_sb_size();
@@ -363,6 +464,8 @@ fn cut_cache(sc_idx: usize, cache: &mut TCacheBin, cut_by: u32) {
         let info = unsafe { SPAGEMAP.get_page_info(head) };
         let desc = info.get_desc();
         let superblock = unsafe { (*desc).get_superblock() };
+        // once the superblock is empty its descriptor may be retired and reused under us
+        let arena_idx = unsafe { (*(*desc).get_heap()).get_arena_idx() };
         let mut block_count = 1;
 
         while block_count != cut_by {
@@ -379,6 +482,8 @@ fn cut_cache(sc_idx: usize, cache: &mut TCacheBin, cut_by: u32) {
         }
 
         cache.pop_list(unsafe { *(tail as *mut *mut u8) }, block_count);
+        stats::count(sc_idx, Counter::Cut, 1);
+        stats::count(sc_idx, Counter::CutBlocks, block_count as u64);
 
         let idx = compute_idx(superblock, head, sc_idx);
         let mut old_anchor;
@@ -432,15 +537,102 @@ fn cut_cache(sc_idx: usize, cache: &mut TCacheBin, cut_by: u32) {
             unregister_desc(Some(heap), superblock);
 
             unsafe {
-                page_free(superblock, heap.get_size_class().get_sb_size() as usize);
+                sb_free(superblock, heap.get_size_class().get_sb_size() as usize);
+            }
+            stats::on_sb_free(sc_idx, arena_idx, sb_size as usize);
+            // a full superblock isn't on the partial list, where its descriptor would be retired
+            if old_anchor.state() == SbState::Full as u32 {
+                unsafe { (*desc).retire() };
             }
         } else if old_anchor.state() == SbState::Full as u32 {
             heap_push_partial(desc);
         }
 }
 
+// hands a fresh allocation to the heap profiler, which samples one every few hundred KiB
+#[cfg(feature = "profiling")]
+#[inline(always)]
+fn prof_alloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    if unlikely(prof::should_sample(size)) {
+        prof::record(ptr, size);
+    }
+    ptr
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline(always)]
+fn prof_alloc(ptr: *mut u8, _size: usize) -> *mut u8 {
+    ptr
+}
+
+// Without redzones a small block starts at its first byte and its size class only has to
+// fit the bytes asked for.
+#[cfg(not(feature = "redzone"))]
+#[inline(always)]
+fn front_size(_alignment: usize) -> usize {
+    0
+}
+
+#[cfg(not(feature = "redzone"))]
+#[inline(always)]
+fn padded_size(alignment: usize, size: usize) -> usize {
+    if alignment == 0 {
+        size
+    } else {
+        align_val(size, alignment)
+    }
+}
+
+// hands out a small block taken from a superblock or the thread cache
+#[inline(always)]
+fn small_alloc(block: *mut u8, _alignment: usize, size: usize, zero: bool) -> *mut u8 {
+    #[cfg(feature = "check_free")]
+    check::on_alloc(block);
+    #[cfg(feature = "redzone")]
+    let block = unsafe { redzone::arm(block, _alignment, size) };
+
+    if !zero {
+        junk::on_alloc(block, size);
+    }
+    prof_alloc(block, size)
+}
+
+// the block to give back for a small pointer being freed
+#[inline(always)]
+fn small_block(ptr: *mut u8, sc_idx: usize) -> *mut u8 {
+    #[cfg(feature = "check_free")]
+    check::on_free(ptr, sc_idx);
+    #[cfg(feature = "redzone")]
+    let ptr = unsafe { redzone::disarm(ptr, sc_idx) };
+
+    junk::on_small_free(ptr, unsafe { SIZE_CLASSES[sc_idx].get_block_size() as usize });
+    ptr
+}
+
+// Called when a small block stays in place for a new size within its size class. With
+// redzones the back one moves, and `zero` clears the bytes it gave up.
+#[inline(always)]
+pub fn resize_small_in_place(_ptr: *mut u8, _sc_idx: usize, _size: usize, _zero: bool) {
+    #[cfg(feature = "redzone")]
+    unsafe {
+        redzone::resize(_ptr, _sc_idx, _size, _zero)
+    };
+}
+
 #[inline(always)]
 pub fn do_malloc(size: usize) -> *mut u8 {
+    malloc_impl(size, false)
+}
+
+// like do_malloc, but the first `size` bytes are zero
+#[inline(always)]
+pub fn do_calloc(size: usize) -> *mut u8 {
+    malloc_impl(size, true)
+}
+
+// large blocks are often fresh mappings and need no zeroing
+#[inline(always)]
+fn malloc_impl(size: usize, zero: bool) -> *mut u8 {
     // ensure malloc is initialized
     if unlikely(unsafe { !MALLOC_INIT }) {
         init_malloc();
@@ -448,20 +640,28 @@ pub fn do_malloc(size: usize) -> *mut u8 {
 
     // init size classes (here because APF analysis is per thread per sizeclass
     if unlikely(unsafe { !APF_INIT }) {
-            init_size_class();
+        init_thread();
     }
 
+    let padded = padded_size(0, size);
+
     // large block allocation
-    if unlikely(size > MAX_SZ) {
-        let pages = page_ceiling(size);
+    if unlikely(padded > MAX_SZ) {
+        let _guard = freeze::guard();
+        // huge pages may map more than asked for
+        let (superblock, pages, zeroed) = unsafe { large_alloc(size) };
+        assert!(!superblock.is_null());
+        if zero && !zeroed {
+            unsafe { superblock.write_bytes(0, size) };
+        } else if !zero {
+            junk::on_alloc(superblock, size);
+        }
         let desc = Descriptor::alloc();
 
         desc.set_heap(null_mut());
         desc.set_block_size(pages as u32);
         desc.set_maxcount(1);
-        unsafe {
-            desc.set_superblock(&mut *page_alloc::<u8>(pages));
-        }
+        desc.set_superblock(superblock);
 
         let mut anchor = Anchor::new();
         anchor.set_avail(0);
@@ -471,13 +671,22 @@ pub fn do_malloc(size: usize) -> *mut u8 {
         desc.get_anchor().store(anchor, Ordering::SeqCst);
 
         register_desc(desc);
+        stats::on_large_alloc(pages);
 
         let ptr = desc.get_superblock();
         log_debug!("Large, ptr: ", ptr);
-        return ptr;
+        return prof_alloc(ptr, size);
     }
 
-    let sc_idx = get_size_class(size);
+    let sc_idx = get_size_class(padded);
+
+    if unlikely(unsafe { !TCACHE_ENABLED }) {
+        return malloc_direct(arena::thread_arena(), sc_idx, 0, size, zero);
+    }
+
+    if unlikely(percpu::active()) {
+        return cpu_alloc(sc_idx, 0, size, zero);
+    }
 
     unsafe {
         SIZE_CLASSES[sc_idx].get_apf().on_allocation();
@@ -495,20 +704,31 @@ pub fn do_malloc(size: usize) -> *mut u8 {
         unsafe { SIZE_CLASSES[sc_idx].get_apf().on_fetch(); }
     }
 
-    cache.pop_block()
+    stats::count(sc_idx, Counter::Malloc, 1);
+    let block = if zero {
+        cache.pop_block_zeroed(front_size(0) + size)
+    } else {
+        cache.pop_block()
+    };
+    small_alloc(block, 0, size, zero)
+}
+
+#[inline(always)]
+pub fn do_aligned_alloc(alignment: usize, size: usize) -> *mut u8 {
+    aligned_alloc_impl(alignment, size, false)
+}
+
+#[inline(always)]
+pub fn do_aligned_calloc(alignment: usize, size: usize) -> *mut u8 {
+    aligned_alloc_impl(alignment, size, true)
 }
 
 #[inline(always)]
-pub fn do_aligned_alloc(alignment: usize, _size: usize) -> *mut u8 {
+fn aligned_alloc_impl(alignment: usize, _size: usize, zero: bool) -> *mut u8 {
     if unlikely((alignment != 0) && !(alignment & (alignment - 1)) == 0) {
         return null_mut();
     }
 
-    // init size classes (here because APF analysis is per thread per sizeclass
-    if unlikely(unsafe { !APF_INIT }) {
-        init_size_class();
-    }
-
     let mut size = align_val(_size, alignment);
 
     assert!(size > 0 && alignment > 0 && size >= alignment);
@@ -520,10 +740,12 @@ pub fn do_aligned_alloc(alignment: usize, _size: usize) -> *mut u8 {
 
     // init size classes (here because APF analysis is per thread per sizeclass
     if unlikely(unsafe { !APF_INIT }) {
-        init_size_class();
+        init_thread();
     }
 
-    if unlikely(size > PAGE) {
+    let padded = padded_size(alignment, _size);
+
+    if unlikely(padded > PAGE) {
         size = core::cmp::max(size, MAX_SZ + 1);
 
         let need_more_pages = alignment > PAGE;
@@ -531,10 +753,14 @@ pub fn do_aligned_alloc(alignment: usize, _size: usize) -> *mut u8 {
             size += alignment;
         }
 
-        let pages = page_ceiling(size);
-        let desc = Descriptor::alloc();
+        let _guard = freeze::guard();
 
-        let mut ptr = unsafe { page_alloc::<u8>(pages) };
+        let (mut ptr, pages, zeroed) = unsafe { large_alloc(size) };
+        assert!(!ptr.is_null());
+        if zero && !zeroed {
+            unsafe { ptr.write_bytes(0, pages) };
+        }
+        let desc = Descriptor::alloc();
 
         desc.set_heap(null_mut());
         desc.set_block_size(pages as u32);
@@ -549,11 +775,13 @@ pub fn do_aligned_alloc(alignment: usize, _size: usize) -> *mut u8 {
         desc.get_anchor().store(anchor, Ordering::SeqCst);
 
         register_desc(desc);
+        stats::on_large_alloc(pages);
 
         if unlikely(need_more_pages) {
             ptr = align_addr(ptr, alignment);
+            // size includes the slack for aligning
             assert!(unsafe {
-                ptr.offset(size as isize)
+                ptr.add(size - alignment)
                     .offset_from(desc.get_superblock().offset(desc.get_block_size() as isize))
                     <= 0
             });
@@ -561,19 +789,206 @@ pub fn do_aligned_alloc(alignment: usize, _size: usize) -> *mut u8 {
             update_page_map(None, ptr, Some(desc), 0);
         }
 
+        if !zero {
+            junk::on_alloc(ptr, _size);
+        }
+
         log_debug!("Large, ptr: ", ptr);
-        return ptr;
+        return prof_alloc(ptr, _size);
     }
 
-    assert!(size <= PAGE);
-    let sc_idx = get_size_class(size);
+    assert!(padded <= PAGE);
+    let sc_idx = get_size_class(padded);
+
+    if unlikely(unsafe { !TCACHE_ENABLED }) {
+        return malloc_direct(arena::thread_arena(), sc_idx, alignment, _size, zero);
+    }
+
+    if unlikely(percpu::active()) {
+        return cpu_alloc(sc_idx, alignment, _size, zero);
+    }
 
     let cache = unsafe { &mut TCACHE[sc_idx] };
     if unlikely(cache.get_block_num() == 0) {
         fill_cache(sc_idx, cache);
     }
 
-    cache.pop_block()
+    stats::count(sc_idx, Counter::Malloc, 1);
+    let block = if zero {
+        cache.pop_block_zeroed(front_size(alignment) + _size)
+    } else {
+        cache.pop_block()
+    };
+    small_alloc(block, alignment, _size, zero)
+}
+
+// Allocates from `arena` instead of the calling thread's arena. Small blocks are taken
+// straight from the arena's heap, bypassing the thread cache; large blocks don't belong
+// to any arena. `arena` must be valid, alignment 0 allocates like do_malloc.
+pub fn do_arena_alloc(arena: usize, alignment: usize, size: usize, zero: bool) -> *mut u8 {
+    ensure_init();
+
+    let sc_idx = alloc_size_class(alignment, size);
+    if unlikely(sc_idx == 0 || (alignment != 0 && !alignment.is_power_of_two())) {
+        return if alignment == 0 {
+            malloc_impl(size, zero)
+        } else {
+            aligned_alloc_impl(alignment, size, zero)
+        };
+    }
+
+    malloc_direct(arena, sc_idx, alignment, size, zero)
+}
+
+// takes a small block from an arena's heap without going through the thread cache
+fn malloc_direct(arena: usize, sc_idx: usize, alignment: usize, size: usize, zero: bool) -> *mut u8 {
+    let heap = arena::heap(arena, sc_idx);
+    let mut ptr: *mut u8 = null_mut();
+    let guard = freeze::guard();
+    while ptr.is_null() {
+        let desc = heap_pop_partial(heap);
+        ptr = if desc.is_null() {
+            arena_block_from_new_sb(heap, sc_idx)
+        } else {
+            arena_block_from_partial(desc, sc_idx)
+        };
+    }
+    drop(guard);
+
+    if zero {
+        unsafe { ptr.write_bytes(0, front_size(alignment) + size) };
+    }
+
+    stats::count(sc_idx, Counter::Malloc, 1);
+    // keeps the cached block count in the stats balanced
+    stats::count(sc_idx, Counter::FillBlocks, 1);
+    small_alloc(ptr, alignment, size, zero)
+}
+
+// Takes one block of a superblock popped off its heap's partial list, null when the
+// superblock turned out to be empty and its descriptor was retired.
+fn arena_block_from_partial(desc: *mut Descriptor<'static>, sc_idx: usize) -> *mut u8 {
+    let max_count = unsafe { (*desc).get_maxcount() };
+    let block_size = unsafe { (*desc).get_block_size() };
+    let superblock = unsafe { (*desc).get_superblock() };
+
+    // reserve all blocks like malloc_from_partial, reading the free list of a superblock
+    // we don't own could touch memory that was just freed
+    let mut old_anchor;
+    loop {
+        old_anchor = unsafe { (*desc).get_anchor().load(Ordering::SeqCst) };
+        if old_anchor.state() == SbState::Empty as u32 {
+            unsafe { (*desc).retire() };
+            return null_mut();
+        }
+        assert_eq!(old_anchor.state(), SbState::Partial as u32);
+
+        let mut new_anchor = old_anchor;
+        new_anchor.set_count(0);
+        new_anchor.set_avail(max_count);
+        new_anchor.set_state(SbState::Full as u32);
+
+        if unsafe {
+            (*desc)
+                .get_anchor()
+                .compare_exchange_weak(old_anchor, new_anchor, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        } {
+            break;
+        }
+    }
+    stats::count(sc_idx, Counter::Partial, 1);
+
+    let block = unsafe { superblock.offset((old_anchor.avail() * block_size) as isize) };
+    let rest = old_anchor.count() - 1;
+    if rest == 0 {
+        return block;
+    }
+
+    // give the other blocks back, the superblock can't become empty while we hold one
+    let head: *mut u8 = unsafe { *(block as *mut *mut u8) };
+    let mut tail = head;
+    let mut walked = false;
+    let idx = compute_idx(superblock, head, sc_idx);
+
+    loop {
+        let old_anchor = unsafe { (*desc).get_anchor().load(Ordering::SeqCst) };
+        let mut new_anchor = old_anchor;
+        new_anchor.set_avail(idx);
+        new_anchor.set_count(old_anchor.count() + rest);
+        new_anchor.set_state(SbState::Partial as u32);
+
+        // blocks flushed meanwhile have to be linked after ours
+        if old_anchor.state() == SbState::Partial as u32 {
+            if !walked {
+                for _ in 1..rest {
+                    tail = unsafe { *(tail as *mut *mut u8) };
+                }
+                walked = true;
+            }
+            unsafe {
+                *(tail as *mut *mut u8) = superblock.offset((old_anchor.avail() * block_size) as isize)
+            };
+        }
+
+        if unsafe {
+            (*desc)
+                .get_anchor()
+                .compare_exchange_weak(old_anchor, new_anchor, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        } {
+            // a flush that made it partial already pushed it
+            if old_anchor.state() == SbState::Full as u32 {
+                heap_push_partial(desc);
+            }
+            return block;
+        }
+    }
+}
+
+// Takes the first block of a new superblock and leaves the others on its free list.
+fn arena_block_from_new_sb(heap: &'static ProcHeap<'static>, sc_idx: usize) -> *mut u8 {
+    let sc = unsafe { &SIZE_CLASSES[sc_idx] };
+    let desc = Descriptor::alloc();
+    let block_size = sc.get_block_size();
+    let maxcount = sc.get_block_num();
+
+    desc.set_heap(heap as *const ProcHeap as *mut ProcHeap);
+    desc.set_block_size(block_size);
+    desc.set_maxcount(maxcount);
+    let (superblock, _) = unsafe { sb_alloc(sc.get_sb_size() as usize) };
+    assert!(!superblock.is_null());
+    numa::place(superblock, sc.get_sb_size() as usize, heap.get_arena_idx());
+    desc.set_superblock(superblock);
+
+    for i in 1..maxcount - 1 {
+        unsafe {
+            let block = superblock.offset((i * block_size) as isize);
+            let next = superblock.offset(((i + 1) * block_size) as isize);
+            *(block as *mut *mut u8) = next;
+        }
+    }
+
+    let mut anchor = Anchor::new();
+    if maxcount > 1 {
+        anchor.set_avail(1);
+        anchor.set_count(maxcount - 1);
+        anchor.set_state(SbState::Partial as u32);
+    } else {
+        anchor.set_avail(maxcount);
+        anchor.set_count(0);
+        anchor.set_state(SbState::Full as u32);
+    }
+    desc.get_anchor().store(anchor, Ordering::SeqCst);
+
+    register_desc(desc);
+    if maxcount > 1 {
+        heap_push_partial(desc);
+    }
+    stats::count(sc_idx, Counter::NewSb, 1);
+    stats::on_sb_alloc(sc_idx, heap.get_arena_idx(), sc.get_sb_size() as usize);
+
+    superblock
 }
 
 #[inline(always)]
@@ -584,17 +999,23 @@ pub fn do_free(ptr: *mut u8) {
 
     // init size classes (here because APF analysis is per thread per sizeclass
     if unlikely(unsafe { !APF_INIT }) {
-        init_size_class();
+        init_thread();
     }
 
     let info = unsafe { SPAGEMAP.get_page_info(ptr) };
+    #[cfg(feature = "check_free")]
+    check::check_page(ptr, info);
     let desc = info.get_desc();
 
     let sc_idx = info.get_sc_idx();
 
     //log_debug!("Free: desc ", desc, ", ptr ", ptr);
 
+    #[cfg(feature = "profiling")]
+    prof::on_free(ptr, desc);
+
     if unlikely(sc_idx == 0) {
+        let guard = freeze::guard();
         let superblock = unsafe { (*desc).get_superblock() };
 
         unregister_desc(None, superblock);
@@ -603,10 +1024,217 @@ pub fn do_free(ptr: *mut u8) {
         }
 
         unsafe {
-            page_free(superblock, (*desc).get_block_size() as usize);
+            junk::on_large_free(superblock, (*desc).get_block_size() as usize);
+            stats::on_large_free((*desc).get_block_size() as usize);
+            large_free(superblock, (*desc).get_block_size() as usize);
             (*desc).retire();
         }
 
+        // after the heap is consistent again, pthread_create allocates
+        drop(guard);
+        purge::maybe_start_background();
+        return;
+    }
+
+    free_small(ptr, sc_idx);
+}
+
+// Size class of a block from do_malloc (alignment 0) or do_aligned_alloc, 0 for large blocks.
+// Sized frees use it to skip the page map lookup.
+#[inline(always)]
+pub fn alloc_size_class(alignment: usize, size: usize) -> usize {
+    // nallocx may come before any allocation, and the options must be read first
+    ensure_init();
+
+    let padded = padded_size(alignment, size);
+    if alignment == 0 {
+        if unlikely(padded > MAX_SZ) {
+            return 0;
+        }
+        return get_size_class(padded);
+    }
+
+    // same rounding as do_aligned_alloc
+    if unlikely(padded > PAGE) {
+        return 0;
+    }
+    get_size_class(padded)
+}
+
+#[inline(always)]
+pub fn do_free_sized(ptr: *mut u8, size: usize) {
+    do_free_aligned_sized(ptr, 0, size)
+}
+
+// `alignment` and `size` must be the ones the block was allocated with, alignment 0 for do_malloc
+#[inline(always)]
+pub fn do_free_aligned_sized(ptr: *mut u8, alignment: usize, size: usize) {
+    if unlikely(ptr.is_null()) {
+        return;
+    }
+
+    // large blocks need their descriptor anyway
+    let sc_idx = alloc_size_class(alignment, size);
+    if unlikely(sc_idx == 0) {
+        do_free(ptr);
+        return;
+    }
+
+    #[cfg(feature = "check_free")]
+    check::check_page(ptr, unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) });
+
+    debug_assert_eq!(
+        unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) }.get_sc_idx(),
+        sc_idx,
+        "sized free of {:p} with size {} and alignment {} does not match its allocation",
+        ptr,
+        size,
+        alignment
+    );
+
+    #[cfg(feature = "profiling")]
+    prof::on_free(ptr, unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) }.get_desc());
+
+    free_small(ptr, sc_idx);
+}
+
+// Like do_free, but a small block goes straight back to its superblock instead of the
+// thread cache.
+pub fn do_free_direct(ptr: *mut u8) {
+    if unlikely(ptr.is_null()) {
+        return;
+    }
+
+    ensure_init();
+
+    let info = unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) };
+    #[cfg(feature = "check_free")]
+    check::check_page(ptr, info);
+    let sc_idx = info.get_sc_idx();
+    if sc_idx == 0 {
+        do_free(ptr);
+        return;
+    }
+
+    #[cfg(feature = "profiling")]
+    prof::on_free(ptr, info.get_desc());
+
+    free_direct(small_block(ptr, sc_idx), sc_idx);
+}
+
+// a cache of one block, flushed right away
+fn free_direct(ptr: *mut u8, sc_idx: usize) {
+    stats::count(sc_idx, Counter::Free, 1);
+    let mut cache = TCacheBin::new();
+    cache.push_block(ptr);
+    flush_cache(sc_idx, &mut cache);
+}
+
+// bytes usable from ptr, which must be live
+pub fn usable_size(ptr: *mut u8) -> usize {
+    let info = unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) };
+    let sc_idx = info.get_sc_idx();
+    if sc_idx != 0 {
+        #[cfg(feature = "redzone")]
+        return unsafe { redzone::usable_size(ptr) };
+        #[cfg(not(feature = "redzone"))]
+        return unsafe { SIZE_CLASSES[sc_idx].get_block_size() as usize };
+    }
+
+    // over-aligned blocks start past the beginning of their mapping
+    let desc = info.get_desc();
+    assert!(!desc.is_null());
+    unsafe {
+        let offset = ptr.offset_from((*desc).get_superblock()) as usize;
+        (*desc).get_block_size() as usize - offset
+    }
+}
+
+// Usable size of a block from do_aligned_alloc (or do_malloc with alignment 0). For large
+// blocks aligned to more than a page it is only a lower bound, the slack for aligning
+// them depends on where they are mapped.
+pub fn alloc_usable_size(alignment: usize, size: usize) -> usize {
+    ensure_init();
+
+    let sc_idx = alloc_size_class(alignment, size);
+    if sc_idx != 0 {
+        #[cfg(feature = "redzone")]
+        return size;
+        #[cfg(not(feature = "redzone"))]
+        return unsafe { SIZE_CLASSES[sc_idx].get_block_size() as usize };
+    }
+
+    if alignment == 0 {
+        return large_size(size);
+    }
+    large_size(core::cmp::max(align_val(size, alignment), MAX_SZ + 1))
+}
+
+// Resizes a block without moving it, to at least `size` and at most `size + extra` bytes
+// where possible, and returns its usable size afterwards. Only large blocks from the page
+// heap change size; they stay large so that sized frees keep finding their size class.
+pub fn do_resize_in_place(ptr: *mut u8, size: usize, extra: usize, alignment: usize, zero: bool) -> usize {
+    let info = unsafe { (*core::ptr::addr_of!(SPAGEMAP)).get_page_info(ptr) };
+    let desc = info.get_desc();
+    if info.get_sc_idx() != 0
+        || ptr != unsafe { (*desc).get_superblock() }
+        || alloc_size_class(alignment, size) != 0
+    {
+        return usable_size(ptr);
+    }
+
+    let _guard = freeze::guard();
+    let size = core::cmp::max(size, MAX_SZ + 1);
+    let min = page_ceiling(size);
+    let max = page_ceiling(size.saturating_add(extra));
+    let old = unsafe { (*desc).get_block_size() as usize };
+
+    let new = if old > max {
+        if page_heap::shrink(ptr, old, max) { max } else { old }
+    } else if old < min {
+        let grown = page_heap::grow(ptr, old, max)
+            .map(|zeroed| (max, zeroed))
+            .or_else(|| page_heap::grow(ptr, old, min).map(|zeroed| (min, zeroed)));
+        match grown {
+            Some((new, zeroed)) => {
+                if zero && !zeroed {
+                    unsafe { ptr.add(old).write_bytes(0, new - old) };
+                }
+                new
+            }
+            None => old,
+        }
+    } else {
+        old
+    };
+
+    if new != old {
+        unsafe { (*desc).set_block_size(new as u32) };
+        stats::on_large_resize(old, new);
+    }
+    new
+}
+
+#[inline(always)]
+fn free_small(ptr: *mut u8, sc_idx: usize) {
+    let block = small_block(ptr, sc_idx);
+    if unlikely(quarantine::hold(block, sc_idx, release_small)) {
+        return;
+    }
+
+    release_small(block, sc_idx);
+}
+
+// gives a freed block to the thread cache
+#[inline(always)]
+fn release_small(ptr: *mut u8, sc_idx: usize) {
+    if unlikely(unsafe { !TCACHE_ENABLED }) {
+        free_direct(ptr, sc_idx);
+        return;
+    }
+
+    if unlikely(percpu::active()) {
+        cpu_free(ptr, sc_idx);
         return;
     }
 
@@ -626,5 +1254,85 @@ pub fn do_free(ptr: *mut u8) {
         }
     }
 
+    stats::count(sc_idx, Counter::Free, 1);
     cache.push_block(ptr);
 }
+
+// Per-CPU mode serves small blocks from the bin of the CPU the thread runs on, or from the
+// thread cache when it can't have one. Neither runs APF.
+fn cpu_alloc(sc_idx: usize, alignment: usize, size: usize, zero: bool) -> *mut u8 {
+    let rseq = percpu::thread_rseq();
+    let block = if rseq.is_null() {
+        let cache = unsafe { &mut TCACHE[sc_idx] };
+        if unlikely(cache.get_block_num() == 0) {
+            fill_cache(sc_idx, cache);
+        }
+
+        if zero {
+            cache.pop_block_zeroed(front_size(alignment) + size)
+        } else {
+            cache.pop_block()
+        }
+    } else {
+        let block = cpu_pop(rseq, sc_idx);
+        if zero {
+            unsafe { block.write_bytes(0, front_size(alignment) + size) };
+        }
+        block
+    };
+
+    stats::count(sc_idx, Counter::Malloc, 1);
+    small_alloc(block, alignment, size, zero)
+}
+
+// An empty bin is refilled like a thread cache: the first block goes to the caller, the
+// rest to the bin until it is full and back to the heap after that.
+fn cpu_pop(rseq: *mut Rseq, sc_idx: usize) -> *mut u8 {
+    let block = percpu::pop(rseq, sc_idx);
+    if likely(!block.is_null()) {
+        return block;
+    }
+
+    let mut cache = TCacheBin::new();
+    fill_cache(sc_idx, &mut cache);
+    let block = cache.pop_block();
+    while cache.get_block_num() > 0 {
+        let next = cache.pop_block();
+        if !percpu::push(rseq, sc_idx, next) {
+            cache.push_block(next);
+            flush_cache(sc_idx, &mut cache);
+            break;
+        }
+    }
+    block
+}
+
+fn cpu_free(ptr: *mut u8, sc_idx: usize) {
+    stats::count(sc_idx, Counter::Free, 1);
+
+    let rseq = percpu::thread_rseq();
+    if rseq.is_null() {
+        let cache = unsafe { &mut TCACHE[sc_idx] };
+        if unlikely(cache.get_block_num() >= unsafe { SIZE_CLASSES[sc_idx].get_cache_block_num() }) {
+            flush_cache(sc_idx, cache);
+        }
+        cache.push_block(ptr);
+        return;
+    }
+
+    if likely(percpu::push(rseq, sc_idx, ptr)) {
+        return;
+    }
+
+    // the bin is full, half of it goes back to the heap along with the block
+    let mut cache = TCacheBin::new();
+    cache.push_block(ptr);
+    while cache.get_block_num() as usize <= CPU_BIN_SLOTS / 2 {
+        let block = percpu::pop(rseq, sc_idx);
+        if block.is_null() {
+            break;
+        }
+        cache.push_block(block);
+    }
+    flush_cache(sc_idx, &mut cache);
+}
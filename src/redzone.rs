@@ -0,0 +1,141 @@
+use crate::defines::align_val;
+use crate::pagemap::SPAGEMAP;
+use core::ptr::addr_of;
+use libc_print::libc_eprintln;
+
+// Debug layout of a small block, which is bumped to a size class large enough for both
+// redzones:
+//
+//   | size | offset | canary ... | user bytes | canary ... up to the end of the block |
+//
+// The front redzone is REDZONE bytes, or the alignment when that is larger, so the user
+// pointer keeps its alignment. The back one is at least REDZONE bytes. Both are checked
+// when the block is freed or resized in place.
+pub const REDZONE: usize = 16;
+const CANARY: u8 = 0xa5;
+
+#[repr(C)]
+struct Header {
+    // bytes asked for
+    size: u32,
+    // from the block to the user pointer
+    offset: u32,
+}
+
+const HEADER_SZ: usize = core::mem::size_of::<Header>();
+
+#[inline(always)]
+pub fn front_size(alignment: usize) -> usize {
+    core::cmp::max(REDZONE, alignment)
+}
+
+// size class request for `size` user bytes
+#[inline(always)]
+pub fn padded_size(alignment: usize, size: usize) -> usize {
+    let padded = size.saturating_add(front_size(alignment) + REDZONE);
+    if alignment == 0 {
+        padded
+    } else {
+        align_val(padded, alignment)
+    }
+}
+
+// block holding a user pointer, from the superblock of its descriptor
+unsafe fn block_of(ptr: *mut u8) -> (*mut u8, usize) {
+    let desc = (*addr_of!(SPAGEMAP)).get_page_info(ptr).get_desc();
+    let superblock = (*desc).get_superblock();
+    let block_size = (*desc).get_block_size() as usize;
+    let idx = ptr.offset_from(superblock) as usize / block_size;
+
+    (superblock.add(idx * block_size), block_size)
+}
+
+fn corrupted(addr: *const u8, ptr: *mut u8, what: &str, sc_idx: usize) -> ! {
+    libc_eprintln!(
+        "r3malloc: heap corruption at {:p}: {} of block {:p} (size class {}) overwritten",
+        addr,
+        what,
+        ptr,
+        sc_idx
+    );
+    unsafe { libc::abort() }
+}
+
+fn first_overwritten(from: *mut u8, len: usize) -> Option<*const u8> {
+    (0..len)
+        .map(|i| unsafe { from.add(i) as *const u8 })
+        .find(|&addr| unsafe { *addr } != CANARY)
+}
+
+// writes the redzones of a block just taken from a superblock, returns the user pointer
+pub unsafe fn arm(block: *mut u8, alignment: usize, size: usize) -> *mut u8 {
+    let block_size = block_of(block).1;
+    let offset = front_size(alignment);
+    let ptr = block.add(offset);
+
+    (block as *mut Header).write(Header { size: size as u32, offset: offset as u32 });
+    block.add(HEADER_SZ).write_bytes(CANARY, offset - HEADER_SZ);
+    ptr.add(size).write_bytes(CANARY, block_size - offset - size);
+    ptr
+}
+
+// Aborts with a report when either redzone of a live block was overwritten, otherwise
+// returns the block and its header.
+unsafe fn check(ptr: *mut u8, sc_idx: usize) -> (*mut u8, usize, &'static mut Header) {
+    let (block, block_size) = block_of(ptr);
+    let header = &mut *(block as *mut Header);
+    let offset = ptr.offset_from(block) as usize;
+
+    if header.offset as usize != offset
+        || offset + header.size as usize + REDZONE > block_size
+    {
+        corrupted(block, ptr, "header", sc_idx);
+    }
+
+    if let Some(addr) = first_overwritten(block.add(HEADER_SZ), offset - HEADER_SZ) {
+        corrupted(addr, ptr, "front redzone", sc_idx);
+    }
+
+    let size = header.size as usize;
+    if let Some(addr) = first_overwritten(ptr.add(size), block_size - offset - size) {
+        corrupted(addr, ptr, "back redzone", sc_idx);
+    }
+
+    (block, block_size, header)
+}
+
+// checks a small block being freed and returns the block to give back
+pub unsafe fn disarm(ptr: *mut u8, sc_idx: usize) -> *mut u8 {
+    check(ptr, sc_idx).0
+}
+
+// checks a small block and moves its back redzone for a resize within its size class
+pub unsafe fn resize(ptr: *mut u8, sc_idx: usize, size: usize, zero: bool) {
+    let (block, block_size, header) = check(ptr, sc_idx);
+    let old = header.size as usize;
+
+    if zero && size > old {
+        ptr.add(old).write_bytes(0, size - old);
+    }
+
+    header.size = size as u32;
+    let end = ptr.offset_from(block) as usize + size;
+    ptr.add(size).write_bytes(CANARY, block_size - end);
+}
+
+pub unsafe fn usable_size(ptr: *mut u8) -> usize {
+    let (block, _) = block_of(ptr);
+    (*(block as *const Header)).size as usize
+}
+
+// User pointer and size of a block that may be allocated. A block in another thread's cache
+// has its header overwritten by the free list link and is returned whole.
+pub unsafe fn user_range(block: *mut u8, block_size: usize) -> (*mut u8, usize) {
+    let header = &*(block as *const Header);
+    let (offset, size) = (header.offset as usize, header.size as usize);
+    if offset < HEADER_SZ || offset.saturating_add(size).saturating_add(REDZONE) > block_size {
+        return (block, block_size);
+    }
+
+    (block.add(offset), size)
+}
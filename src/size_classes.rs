@@ -1,7 +1,9 @@
 use crate::defines::PAGE;
-use crate::heap::MAX_BLOCK_NUM;
+use crate::heap::{MAX_BLOCK_NUM, MAX_SB_BLOCKS};
 use crate::apf::{Apf, APF_INIT};
+use crate::percpu;
 use core::assert;
+use core::ptr::addr_of_mut;
 use array_init::array_init;
 
 #[derive(Debug)]
@@ -122,7 +124,10 @@ fn size_classes() -> [SizeClassData; MAX_SZ_IDX] {
         match size_class_filter_init(&SIZE_CLASSES_TABLE[j]) {
             Some(sc) => {
                 size_classes[i] = sc;
-                size_classes[i].apf.init();
+                // nothing feeds APF while the per-CPU caches are on
+                if !percpu::active() {
+                    size_classes[i].apf.init();
+                }
                 i += 1
             }
             None => (),
@@ -180,6 +185,7 @@ pub fn init_size_class() {
 
         assert!(sc.block_num > 0);
         assert!((sc.block_num as u64) < MAX_BLOCK_NUM);
+        assert!(sc.block_num as usize <= MAX_SB_BLOCKS);
         assert!(sc.block_num >= sc.cache_block_num);
     }
 
@@ -199,6 +205,21 @@ pub fn init_size_class() {
     unsafe { APF_INIT = true; }
 }
 
+// Unmaps the calling thread's APF tables. Its size classes are set up again the next time
+// it allocates or frees.
+pub fn release_size_class() {
+    unsafe {
+        if !APF_INIT {
+            return;
+        }
+
+        for sc in (*addr_of_mut!(SIZE_CLASSES)).iter_mut().skip(1) {
+            sc.apf.release();
+        }
+        APF_INIT = false;
+    }
+}
+
 #[inline(always)]
 pub fn get_size_class(size: usize) -> usize {
     unsafe { SIZE_CLASS_LOOKUP[size] }
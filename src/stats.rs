@@ -0,0 +1,511 @@
+use crate::arena::{self, MAX_ARENAS};
+use crate::conf;
+use crate::defines::page_ceiling;
+use crate::heap;
+use crate::pages::page_alloc;
+use crate::purge;
+use crate::size_classes::{SizeClassData, MAX_SZ_IDX, SIZE_CLASSES};
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use libc::{c_char, c_void};
+use likely_stable::unlikely;
+
+// per size class events counted by each thread
+#[derive(Clone, Copy)]
+pub enum Counter {
+    Malloc,
+    Free,
+    Fill,
+    FillBlocks,
+    Flush,
+    FlushBlocks,
+    Cut,
+    CutBlocks,
+    Partial,
+    NewSb,
+}
+
+const NUM_COUNTERS: usize = Counter::NewSb as usize + 1;
+
+// Counters of one thread. Only the owning thread writes them, so a relaxed load/store
+// pair is enough and readers merging the totals never see torn values.
+// Slots are never unmapped: a thread that finishes gives its slot back and the next
+// thread keeps adding to the same counters, so summing all slots gives process totals.
+pub struct ThreadStats {
+    counters: [[AtomicU64; NUM_COUNTERS]; MAX_SZ_IDX],
+    in_use: AtomicBool,
+    next: *mut ThreadStats,
+}
+
+const THREAD_STATS_SZ: usize = page_ceiling(size_of::<ThreadStats>());
+
+static THREAD_STATS: AtomicPtr<ThreadStats> = AtomicPtr::new(null_mut());
+
+#[thread_local]
+static mut TSTATS: *mut ThreadStats = null_mut();
+
+// bytes obtained with page_alloc and not yet returned with page_free
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+// bytes reserved with page_alloc_overcommit (page map, APF buffers), only backed once touched
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+static SB_LIVE: [AtomicUsize; MAX_SZ_IDX] = [const { AtomicUsize::new(0) }; MAX_SZ_IDX];
+static SB_BYTES: AtomicUsize = AtomicUsize::new(0);
+static SB_BYTES_MAX: AtomicUsize = AtomicUsize::new(0);
+static ARENA_SB_LIVE: [AtomicUsize; MAX_ARENAS] = [const { AtomicUsize::new(0) }; MAX_ARENAS];
+static ARENA_SB_BYTES: [AtomicUsize; MAX_ARENAS] = [const { AtomicUsize::new(0) }; MAX_ARENAS];
+static LARGE_LIVE: AtomicUsize = AtomicUsize::new(0);
+static LARGE_LIVE_MAX: AtomicUsize = AtomicUsize::new(0);
+static LARGE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LARGE_BYTES_MAX: AtomicUsize = AtomicUsize::new(0);
+static LARGE_NMALLOC: AtomicU64 = AtomicU64::new(0);
+static LARGE_NFREE: AtomicU64 = AtomicU64::new(0);
+
+fn claim_thread_stats() -> *mut ThreadStats {
+    let mut curr = THREAD_STATS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let ts = unsafe { &*curr };
+        if !ts.in_use.load(Ordering::Relaxed)
+            && ts
+                .in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            return curr;
+        }
+        curr = ts.next;
+    }
+
+    // fresh pages are zeroed, which is a valid all-zero set of counters
+    let ts = unsafe { page_alloc::<ThreadStats>(THREAD_STATS_SZ) };
+    assert!(!ts.is_null());
+    unsafe { (*ts).in_use.store(true, Ordering::Relaxed) };
+
+    loop {
+        let head = THREAD_STATS.load(Ordering::Relaxed);
+        unsafe { (*ts).next = head };
+        if THREAD_STATS
+            .compare_exchange_weak(head, ts, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return ts;
+        }
+    }
+}
+
+#[inline(always)]
+fn thread_stats() -> &'static ThreadStats {
+    unsafe {
+        if unlikely(TSTATS.is_null()) {
+            TSTATS = claim_thread_stats();
+        }
+        &*TSTATS
+    }
+}
+
+#[inline(always)]
+pub fn count(sc_idx: usize, counter: Counter, n: u64) {
+    let c = &thread_stats().counters[sc_idx][counter as usize];
+    c.store(c.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+// called when a thread is done with the allocator
+pub fn release_thread_stats() {
+    unsafe {
+        if !TSTATS.is_null() {
+            (*TSTATS).in_use.store(false, Ordering::Release);
+            TSTATS = null_mut();
+        }
+    }
+}
+
+// The other threads are gone in a forked child. Their slots keep their counts and are handed
+// to new threads.
+pub fn postfork_child() {
+    let mut curr = THREAD_STATS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        unsafe {
+            if curr != TSTATS {
+                (*curr).in_use.store(false, Ordering::Release);
+            }
+            curr = (*curr).next;
+        }
+    }
+}
+
+pub fn on_map(size: usize) {
+    MAPPED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn on_unmap(size: usize) {
+    MAPPED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn on_reserve(size: usize) {
+    RESERVED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn on_unreserve(size: usize) {
+    RESERVED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn on_sb_alloc(sc_idx: usize, arena: usize, sb_size: usize) {
+    SB_LIVE[sc_idx].fetch_add(1, Ordering::Relaxed);
+    ARENA_SB_LIVE[arena].fetch_add(1, Ordering::Relaxed);
+    ARENA_SB_BYTES[arena].fetch_add(sb_size, Ordering::Relaxed);
+    let bytes = SB_BYTES.fetch_add(sb_size, Ordering::Relaxed) + sb_size;
+    SB_BYTES_MAX.fetch_max(bytes, Ordering::Relaxed);
+}
+
+pub fn on_sb_free(sc_idx: usize, arena: usize, sb_size: usize) {
+    SB_LIVE[sc_idx].fetch_sub(1, Ordering::Relaxed);
+    ARENA_SB_LIVE[arena].fetch_sub(1, Ordering::Relaxed);
+    ARENA_SB_BYTES[arena].fetch_sub(sb_size, Ordering::Relaxed);
+    SB_BYTES.fetch_sub(sb_size, Ordering::Relaxed);
+}
+
+pub fn on_large_alloc(size: usize) {
+    let live = LARGE_LIVE.fetch_add(1, Ordering::Relaxed) + 1;
+    LARGE_LIVE_MAX.fetch_max(live, Ordering::Relaxed);
+    let bytes = LARGE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    LARGE_BYTES_MAX.fetch_max(bytes, Ordering::Relaxed);
+    LARGE_NMALLOC.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn on_large_free(size: usize) {
+    LARGE_LIVE.fetch_sub(1, Ordering::Relaxed);
+    LARGE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    LARGE_NFREE.fetch_add(1, Ordering::Relaxed);
+}
+
+// a large block was resized in place
+pub fn on_large_resize(old_size: usize, new_size: usize) {
+    let bytes = LARGE_BYTES.fetch_add(new_size, Ordering::Relaxed) + new_size - old_size;
+    LARGE_BYTES.fetch_sub(old_size, Ordering::Relaxed);
+    LARGE_BYTES_MAX.fetch_max(bytes, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ScStats {
+    pub nmalloc: u64,
+    pub nfree: u64,
+    pub nfill: u64,
+    pub nflush: u64,
+    pub ncut: u64,
+    pub npartial: u64,
+    pub nnew_sb: u64,
+    pub superblocks: usize,
+    // blocks handed out to the application
+    pub allocated: u64,
+    // blocks sitting in thread caches
+    pub cached: u64,
+    // blocks on superblock free lists
+    pub free: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ArenaStats {
+    pub superblocks: usize,
+    pub sb_bytes: usize,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Totals {
+    pub mapped: usize,
+    pub reserved: usize,
+    pub allocated: usize,
+    pub small_allocated: usize,
+    pub tcache_bytes: usize,
+    pub tcache_blocks: usize,
+    pub sb_bytes: usize,
+    pub sb_bytes_max: usize,
+    pub sb_free_bytes: usize,
+    pub sb_free_blocks: usize,
+    pub large_live: usize,
+    pub large_live_max: usize,
+    pub large_bytes: usize,
+    pub large_bytes_max: usize,
+    pub large_nmalloc: u64,
+    pub large_nfree: u64,
+    pub desc_bytes: usize,
+    pub desc_live: usize,
+    pub desc_free: usize,
+    pub retained: usize,
+    pub dirty: usize,
+    pub npurge: u64,
+}
+
+fn size_class(sc_idx: usize) -> &'static SizeClassData {
+    unsafe { &SIZE_CLASSES[sc_idx] }
+}
+
+// Merges every thread's counters for one size class.
+// Expects the calling thread's size classes to be initialized.
+pub fn sc_stats(sc_idx: usize) -> ScStats {
+    let mut sums = [0u64; NUM_COUNTERS];
+
+    let mut curr = THREAD_STATS.load(Ordering::Acquire);
+    while !curr.is_null() {
+        let ts = unsafe { &*curr };
+        for (sum, c) in sums.iter_mut().zip(ts.counters[sc_idx].iter()) {
+            *sum += c.load(Ordering::Relaxed);
+        }
+        curr = ts.next;
+    }
+
+    let sc = size_class(sc_idx);
+    let superblocks = SB_LIVE[sc_idx].load(Ordering::Relaxed);
+    let nmalloc = sums[Counter::Malloc as usize];
+    let nfree = sums[Counter::Free as usize];
+
+    // counters of different threads are read at slightly different times, so clamp at zero
+    let allocated = nmalloc.saturating_sub(nfree);
+    let cached = (sums[Counter::FillBlocks as usize] + nfree).saturating_sub(
+        nmalloc + sums[Counter::FlushBlocks as usize] + sums[Counter::CutBlocks as usize],
+    );
+    let total = superblocks as u64 * sc.get_block_num() as u64;
+
+    ScStats {
+        nmalloc,
+        nfree,
+        nfill: sums[Counter::Fill as usize],
+        nflush: sums[Counter::Flush as usize],
+        ncut: sums[Counter::Cut as usize],
+        npartial: sums[Counter::Partial as usize],
+        nnew_sb: sums[Counter::NewSb as usize],
+        superblocks,
+        allocated,
+        cached,
+        free: total.saturating_sub(allocated + cached),
+    }
+}
+
+pub fn arena_stats(arena: usize) -> ArenaStats {
+    ArenaStats {
+        superblocks: ARENA_SB_LIVE[arena].load(Ordering::Relaxed),
+        sb_bytes: ARENA_SB_BYTES[arena].load(Ordering::Relaxed),
+    }
+}
+
+pub fn totals() -> Totals {
+    let mut totals = Totals {
+        mapped: MAPPED.load(Ordering::Relaxed),
+        reserved: RESERVED.load(Ordering::Relaxed),
+        sb_bytes: SB_BYTES.load(Ordering::Relaxed),
+        sb_bytes_max: SB_BYTES_MAX.load(Ordering::Relaxed),
+        large_live: LARGE_LIVE.load(Ordering::Relaxed),
+        large_live_max: LARGE_LIVE_MAX.load(Ordering::Relaxed),
+        large_bytes: LARGE_BYTES.load(Ordering::Relaxed),
+        large_bytes_max: LARGE_BYTES_MAX.load(Ordering::Relaxed),
+        large_nmalloc: LARGE_NMALLOC.load(Ordering::Relaxed),
+        large_nfree: LARGE_NFREE.load(Ordering::Relaxed),
+        desc_bytes: heap::desc_bytes(),
+        desc_live: heap::desc_live(),
+        desc_free: heap::desc_free(),
+        retained: purge::retained(),
+        dirty: purge::dirty(),
+        npurge: purge::npurge(),
+        ..Default::default()
+    };
+
+    for sc_idx in 1..MAX_SZ_IDX {
+        let block_size = size_class(sc_idx).get_block_size() as usize;
+        let s = sc_stats(sc_idx);
+
+        totals.small_allocated += s.allocated as usize * block_size;
+        totals.tcache_bytes += s.cached as usize * block_size;
+        totals.tcache_blocks += s.cached as usize;
+        totals.sb_free_bytes += s.free as usize * block_size;
+        totals.sb_free_blocks += s.free as usize;
+    }
+    totals.allocated = totals.small_allocated + totals.large_bytes;
+
+    totals
+}
+
+pub type WriteCb = extern "C" fn(*mut c_void, *const c_char);
+
+// Buffers formatted output and hands it to the callback in NUL-terminated chunks,
+// or writes it to stderr when there is no callback. Never allocates.
+pub struct StatsWriter {
+    write_cb: Option<WriteCb>,
+    cbopaque: *mut c_void,
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl StatsWriter {
+    pub fn new(write_cb: Option<WriteCb>, cbopaque: *mut c_void) -> Self {
+        StatsWriter {
+            write_cb,
+            cbopaque,
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        match self.write_cb {
+            Some(cb) => {
+                self.buf[self.len] = 0;
+                cb(self.cbopaque, self.buf.as_ptr() as *const c_char);
+            }
+            None => unsafe {
+                libc::write(libc::STDERR_FILENO, self.buf.as_ptr() as *const c_void, self.len);
+            },
+        }
+        self.len = 0;
+    }
+}
+
+impl Write for StatsWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &b in s.as_bytes() {
+            // keep one byte for the terminating NUL
+            if self.len == self.buf.len() - 1 {
+                self.flush();
+            }
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StatsWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// opts follows malloc_stats_print: 'b' leaves out the per size class table
+pub fn print(w: &mut StatsWriter, opts: &[u8]) -> core::fmt::Result {
+    let t = totals();
+
+    writeln!(w, "___ Begin r3malloc statistics ___")?;
+    writeln!(w, "Version: {}", env!("CARGO_PKG_VERSION"))?;
+    write!(w, "Options: ")?;
+    conf::write_opts(w)?;
+    writeln!(w)?;
+    writeln!(w, "Mapped: {}", t.mapped)?;
+    writeln!(w, "Reserved: {}", t.reserved)?;
+    writeln!(w, "Allocated: {} (small: {}, large: {})", t.allocated, t.small_allocated, t.large_bytes)?;
+    writeln!(w, "Superblocks: {} (free blocks: {})", t.sb_bytes, t.sb_free_bytes)?;
+    writeln!(w, "Thread caches: {}", t.tcache_bytes)?;
+    writeln!(w, "Descriptors: {} (live: {}, free: {})", t.desc_bytes, t.desc_live, t.desc_free)?;
+    writeln!(w, "Retained: {} (dirty: {}), npurge: {}", t.retained, t.dirty, t.npurge)?;
+    writeln!(
+        w,
+        "Large: {} live ({} bytes), nmalloc: {}, nfree: {}",
+        t.large_live, t.large_bytes, t.large_nmalloc, t.large_nfree
+    )?;
+    for arena in 0..arena::narenas() {
+        let a = arena_stats(arena);
+        writeln!(w, "Arena {}: {} superblocks ({} bytes)", arena, a.superblocks, a.sb_bytes)?;
+    }
+
+    if !opts.contains(&b'b') {
+        writeln!(
+            w,
+            "{:>3} {:>6} {:>7} {:>6} {:>10} {:>10} {:>10} {:>12} {:>12} {:>9} {:>9} {:>9} {:>9} {:>8}",
+            "sc", "size", "sb_size", "sbs", "allocated", "cached", "free", "nmalloc", "nfree",
+            "nfill", "nflush", "ncut", "npartial", "nnew_sb"
+        )?;
+        for sc_idx in 1..MAX_SZ_IDX {
+            let sc = size_class(sc_idx);
+            let s = sc_stats(sc_idx);
+            if s.nmalloc == 0 && s.nfree == 0 && s.superblocks == 0 {
+                continue;
+            }
+
+            writeln!(
+                w,
+                "{:>3} {:>6} {:>7} {:>6} {:>10} {:>10} {:>10} {:>12} {:>12} {:>9} {:>9} {:>9} {:>9} {:>8}",
+                sc_idx, sc.get_block_size(), sc.get_sb_size(), s.superblocks, s.allocated, s.cached,
+                s.free, s.nmalloc, s.nfree, s.nfill, s.nflush, s.ncut, s.npartial, s.nnew_sb
+            )?;
+        }
+    }
+
+    writeln!(w, "___ End r3malloc statistics ___")
+}
+
+// glibc's struct mallinfo2 filled from our own accounting: the "arena" is superblock
+// memory, "hblk" are large mmap'd allocations and thread caches play the role of fastbins
+#[cfg(target_env = "gnu")]
+pub fn mallinfo2() -> libc::mallinfo2 {
+    let t = totals();
+
+    libc::mallinfo2 {
+        arena: t.sb_bytes,
+        ordblks: t.sb_free_blocks,
+        smblks: t.tcache_blocks,
+        hblks: t.large_live,
+        hblkhd: t.large_bytes,
+        usmblks: 0,
+        fsmblks: t.tcache_bytes,
+        uordblks: t.small_allocated,
+        fordblks: t.sb_free_bytes + t.tcache_bytes,
+        keepcost: t.retained,
+    }
+}
+
+// same layout as glibc's malloc_stats, with r3malloc as a single arena
+pub fn print_malloc_stats(w: &mut StatsWriter) -> core::fmt::Result {
+    let t = totals();
+
+    writeln!(w, "Arena 0:")?;
+    writeln!(w, "system bytes     = {:>10}", t.sb_bytes)?;
+    writeln!(w, "in use bytes     = {:>10}", t.small_allocated)?;
+    writeln!(w, "Total (incl. mmap):")?;
+    writeln!(w, "system bytes     = {:>10}", t.sb_bytes + t.large_bytes)?;
+    writeln!(w, "in use bytes     = {:>10}", t.allocated)?;
+    writeln!(w, "max mmap regions = {:>10}", t.large_live_max)?;
+    writeln!(w, "max mmap bytes   = {:>10}", t.large_bytes_max)
+}
+
+// same XML as glibc's malloc_info so existing parsers keep working; free blocks are
+// listed per size class, "fast" are blocks in thread caches and "rest" are blocks on
+// superblock free lists
+pub fn print_malloc_info(w: &mut StatsWriter) -> core::fmt::Result {
+    let t = totals();
+
+    writeln!(w, "<malloc version=\"1\">")?;
+    writeln!(w, "<heap nr=\"0\">")?;
+    writeln!(w, "<sizes>")?;
+    let mut from = 1;
+    for sc_idx in 1..MAX_SZ_IDX {
+        let block_size = size_class(sc_idx).get_block_size() as u64;
+        let s = sc_stats(sc_idx);
+        let count = s.cached + s.free;
+        if count > 0 {
+            writeln!(
+                w,
+                "<size from=\"{}\" to=\"{}\" total=\"{}\" count=\"{}\"/>",
+                from, block_size, count * block_size, count
+            )?;
+        }
+        from = block_size + 1;
+    }
+    writeln!(w, "</sizes>")?;
+    writeln!(w, "<total type=\"fast\" count=\"{}\" size=\"{}\"/>", t.tcache_blocks, t.tcache_bytes)?;
+    writeln!(w, "<total type=\"rest\" count=\"{}\" size=\"{}\"/>", t.sb_free_blocks, t.sb_free_bytes)?;
+    writeln!(w, "<system type=\"current\" size=\"{}\"/>", t.sb_bytes)?;
+    writeln!(w, "<system type=\"max\" size=\"{}\"/>", t.sb_bytes_max)?;
+    writeln!(w, "<aspace type=\"total\" size=\"{}\"/>", t.sb_bytes)?;
+    writeln!(w, "<aspace type=\"mprotect\" size=\"{}\"/>", t.sb_bytes)?;
+    writeln!(w, "</heap>")?;
+    writeln!(w, "<total type=\"fast\" count=\"{}\" size=\"{}\"/>", t.tcache_blocks, t.tcache_bytes)?;
+    writeln!(w, "<total type=\"rest\" count=\"{}\" size=\"{}\"/>", t.sb_free_blocks, t.sb_free_bytes)?;
+    writeln!(w, "<total type=\"mmap\" count=\"{}\" size=\"{}\"/>", t.large_live, t.large_bytes)?;
+    writeln!(w, "<system type=\"current\" size=\"{}\"/>", t.sb_bytes + t.large_bytes)?;
+    writeln!(w, "<system type=\"max\" size=\"{}\"/>", t.sb_bytes_max + t.large_bytes_max)?;
+    writeln!(w, "<aspace type=\"total\" size=\"{}\"/>", t.sb_bytes + t.large_bytes)?;
+    writeln!(w, "<aspace type=\"mprotect\" size=\"{}\"/>", t.sb_bytes + t.large_bytes)?;
+    writeln!(w, "</malloc>")
+}
@@ -5,6 +5,10 @@ use core::ptr::null_mut;
 pub struct TCacheBin {
     block: *mut u8,
     block_num: u32,
+    // blocks of a new superblock in [fresh, fresh_end) have not been handed out yet,
+    // so everything but their free list link is still zero
+    fresh: usize,
+    fresh_end: usize,
 }
 
 impl TCacheBin {
@@ -12,6 +16,8 @@ impl TCacheBin {
         TCacheBin {
             block: null_mut(),
             block_num: 0,
+            fresh: 0,
+            fresh_end: 0,
         }
     }
 
@@ -36,6 +42,26 @@ impl TCacheBin {
 
         self.block = block;
         self.block_num = length;
+        self.fresh_end = 0;
+    }
+
+    // the blocks of a new superblock were just pushed in address order
+    #[inline(always)]
+    pub fn set_fresh(&mut self, superblock: *mut u8, sb_size: usize) {
+        self.fresh = superblock as usize;
+        self.fresh_end = superblock as usize + sb_size;
+    }
+
+    #[inline(always)]
+    fn take_fresh(&mut self, block: *mut u8) -> bool {
+        let addr = block as usize;
+        // fresh blocks come out in address order, anything at or below the last
+        // one may have been used and freed since
+        if addr >= self.fresh && addr < self.fresh_end {
+            self.fresh = addr + 1;
+            return true;
+        }
+        false
     }
 
     #[inline(always)]
@@ -45,17 +71,49 @@ impl TCacheBin {
         let ret = self.block;
         self.block = unsafe { *(self.block as *mut *mut u8) };
         self.block_num -= 1;
+        self.take_fresh(ret);
         ret
     }
 
+    // pops a block with its first `size` bytes zeroed
+    #[inline(always)]
+    pub fn pop_block_zeroed(&mut self, size: usize) -> *mut u8 {
+        assert!(self.block_num > 0);
+
+        let ret = self.block;
+        self.block = unsafe { *(self.block as *mut *mut u8) };
+        self.block_num -= 1;
+
+        if self.take_fresh(ret) {
+            unsafe { *(ret as *mut *mut u8) = null_mut() };
+        } else {
+            unsafe { ret.write_bytes(0, size) };
+        }
+        ret
+    }
+
+    pub fn for_each_block<F: FnMut(*mut u8)>(&self, mut f: F) {
+        let mut block = self.block;
+        for _ in 0..self.block_num {
+            f(block);
+            block = unsafe { *(block as *mut *mut u8) };
+        }
+    }
+
+    // blocks leaving the cache may come back used, so stop trusting the fresh range
     #[inline(always)]
     pub fn pop_list(&mut self, block: *mut u8, length: u32) {
         assert!(self.block_num >= length);
 
         self.block = block;
         self.block_num -= length;
+        self.fresh_end = 0;
     }
 }
 
 #[thread_local]
-pub static mut TCACHE: [TCacheBin; MAX_SZ_IDX] = [TCacheBin::new(); MAX_SZ_IDX];
\ No newline at end of file
+pub static mut TCACHE: [TCacheBin; MAX_SZ_IDX] = [TCacheBin::new(); MAX_SZ_IDX];
+
+// off for threads that should not hold on to cached blocks
+#[thread_local]
+pub static mut TCACHE_ENABLED: bool = true;
@@ -0,0 +1,31 @@
+use crate::r3malloc::thread_finalize;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use libc::{c_void, pthread_key_t};
+
+// Threads are finalized by the destructor of a pthread key, which runs for every thread
+// leaving through pthread_exit or its start routine, however it was created. A thread sets
+// the key when it sets up its size classes. Destructors of other keys may still allocate
+// after ours ran; that sets the thread up and the key again, so glibc calls ours once more
+// in its next round.
+static mut KEY: pthread_key_t = 0;
+static KEY_CREATED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_thread_exit(_arg: *mut c_void) {
+    thread_finalize();
+}
+
+// called by init_malloc
+pub fn init() {
+    if unsafe { libc::pthread_key_create(addr_of_mut!(KEY), Some(on_thread_exit)) } == 0 {
+        KEY_CREATED.store(true, Ordering::Release);
+    }
+}
+
+// Arms the destructor for the calling thread. pthread_setspecific may allocate, so the
+// thread has to be usable by now.
+pub fn register() {
+    if KEY_CREATED.load(Ordering::Acquire) {
+        unsafe { libc::pthread_setspecific(KEY, core::ptr::dangling::<c_void>()) };
+    }
+}
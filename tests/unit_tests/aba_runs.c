@@ -0,0 +1,150 @@
+#include <pthread.h>
+#include <sched.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/syscall.h>
+#include <time.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+_Bool r3malloc_thread_tcache_set_enabled(_Bool);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define THREADS 16
+#define SLOTS 16
+#define SECONDS 5
+// how often each thread gets switched out, wherever it is
+#define PREEMPT_NS 200000
+
+// 32 and 6 blocks per superblock, so superblocks keep leaving and rejoining the partial
+// lists, and going empty retires their descriptors
+static const size_t block_sizes[] = {2048, 12288};
+
+static volatile int stop;
+static volatile int failed;
+
+struct tag {
+    uintptr_t owner;
+    uintptr_t serial;
+};
+
+static void tag(void* ptr, size_t size, uintptr_t owner, uintptr_t serial) {
+    struct tag* head = ptr;
+    struct tag* tail = (struct tag*)((char*)ptr + size) - 1;
+    head->owner = tail->owner = owner;
+    head->serial = tail->serial = serial;
+}
+
+static int tagged(void* ptr, size_t size, uintptr_t owner, uintptr_t serial) {
+    struct tag* head = ptr;
+    struct tag* tail = (struct tag*)((char*)ptr + size) - 1;
+    return head->owner == owner && tail->owner == owner && head->serial == serial && tail->serial == serial;
+}
+
+static void preempted(int sig) {
+    sched_yield();
+}
+
+// A single CPU would otherwise switch threads only every few milliseconds, and hardly ever
+// in the middle of a pop.
+static void preempt_calling_thread(void) {
+    struct sigevent sev = {0};
+    sev.sigev_notify = SIGEV_THREAD_ID;
+    sev.sigev_signo = SIGALRM;
+    sev._sigev_un._tid = syscall(SYS_gettid);
+    timer_t timer;
+    if (timer_create(CLOCK_MONOTONIC, &sev, &timer) != 0)
+        return;
+    struct itimerspec its = {{0, PREEMPT_NS}, {0, PREEMPT_NS}};
+    timer_settime(timer, 0, &its, NULL);
+}
+
+// Without a thread cache every malloc pops a partial superblock and pushes it back, so a
+// list head comes back to the same descriptor many times while a pop is switched out. If
+// the pop can't tell, it links in a superblock that left the list meanwhile, and blocks
+// get handed out twice.
+static void* run(void* arg) {
+    uintptr_t owner = (uintptr_t)arg;
+    size_t size = block_sizes[owner % 2];
+    void* ptrs[SLOTS] = {0};
+    uintptr_t serials[SLOTS] = {0};
+    uintptr_t serial = 0;
+    unsigned seed = (unsigned)owner;
+
+    r3malloc_thread_tcache_set_enabled(0);
+    preempt_calling_thread();
+    while (!stop && !failed) {
+        int i = rand_r(&seed) % SLOTS;
+        if (ptrs[i]) {
+            if (!tagged(ptrs[i], size, owner, serials[i])) {
+                failed = 1;
+                break;
+            }
+            free(ptrs[i]);
+        }
+        ptrs[i] = malloc(size);
+        serials[i] = ++serial;
+        tag(ptrs[i], size, owner, serials[i]);
+    }
+
+    for (int i = 0; i < SLOTS; i++) {
+        if (ptrs[i] && !tagged(ptrs[i], size, owner, serials[i]))
+            failed = 1;
+        free(ptrs[i]);
+    }
+    return NULL;
+}
+
+// superblocks left in the size class of `block_size`
+static uint64_t superblocks(size_t block_size) {
+    size_t count = 0, len = sizeof(count);
+    r3malloc_ctl("sc.count", &count, &len, NULL, 0);
+    for (size_t i = 1; i < count; i++) {
+        char name[64];
+        unsigned size = 0;
+        len = sizeof(size);
+        snprintf(name, sizeof(name), "sc.%zu.block_size", i);
+        r3malloc_ctl(name, &size, &len, NULL, 0);
+        if (size == block_size) {
+            uint64_t n = 0;
+            len = sizeof(n);
+            snprintf(name, sizeof(name), "stats.sc.%zu.superblocks", i);
+            r3malloc_ctl(name, &n, &len, NULL, 0);
+            return n;
+        }
+    }
+    return UINT64_MAX;
+}
+
+int main() {
+    struct sigaction sa = {0};
+    sa.sa_handler = preempted;
+    sa.sa_flags = SA_RESTART;
+    sigaction(SIGALRM, &sa, NULL);
+
+    pthread_t threads[THREADS];
+    for (uintptr_t i = 0; i < THREADS; i++)
+        pthread_create(&threads[i], NULL, run, (void*)(i + 1));
+
+    sleep(SECONDS);
+    stop = 1;
+    for (int i = 0; i < THREADS; i++)
+        pthread_join(threads[i], NULL);
+
+    if (failed) {
+        printf("a block was handed out twice\n");
+        return 1;
+    }
+
+    // every block was freed, so every superblock went empty and was given back
+    for (int i = 0; i < 2; i++) {
+        uint64_t left = superblocks(block_sizes[i]);
+        printf("%zu byte superblocks left: %llu\n", block_sizes[i], (unsigned long long)left);
+        if (left != 0)
+            return 1;
+    }
+    return 0;
+}
@@ -0,0 +1,88 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+void* mallocx(size_t, int);
+void* rallocx(void*, size_t, int);
+size_t xallocx(void*, size_t, size_t, int);
+size_t sallocx(const void*, int);
+void dallocx(void*, int);
+void sdallocx(void*, size_t, int);
+size_t nallocx(size_t, int);
+int r3malloc_arena_create(void);
+
+// same encoding as jemalloc
+#define MALLOCX_LG_ALIGN(la) ((int) (la))
+#define MALLOCX_ZERO ((int) 0x40)
+#define MALLOCX_TCACHE_NONE ((int) (1 << 8))
+#define MALLOCX_ARENA(a) ((int) (((unsigned) (a) + 1) << 20))
+
+static int is_zero(const char* p, size_t n) {
+    for (size_t i = 0; i < n; i++)
+        if (p[i])
+            return 0;
+    return 1;
+}
+
+int main() {
+    size_t sizes[] = { 1, 24, 100, 1000, 4096, 14336, 14337, 100000 };
+    int num_sizes = sizeof(sizes) / sizeof(sizes[0]);
+    int flags[] = { 0, MALLOCX_ZERO, MALLOCX_LG_ALIGN(6), MALLOCX_LG_ALIGN(13) | MALLOCX_ZERO,
+                    MALLOCX_TCACHE_NONE, MALLOCX_TCACHE_NONE | MALLOCX_LG_ALIGN(8) };
+    int num_flags = sizeof(flags) / sizeof(flags[0]);
+
+    // nallocx predicts sallocx (a lower bound for large blocks aligned to more than a
+    // page), alignment and zeroing are honoured
+    int ok = 1;
+    for (int round = 0; round < 100; round++) {
+        for (int f = 0; f < num_flags; f++) {
+            for (int i = 0; i < num_sizes; i++) {
+                char* p = mallocx(sizes[i], flags[f]);
+                size_t align = (size_t) 1 << (flags[f] & 0x3f);
+                size_t n = nallocx(sizes[i], flags[f]);
+                if ((align > 4096 ? sallocx(p, 0) < n : sallocx(p, 0) != n) || (uintptr_t) p % align != 0)
+                    ok = 0;
+                if ((flags[f] & MALLOCX_ZERO) && !is_zero(p, sizes[i]))
+                    ok = 0;
+                memset(p, 0xab, sizes[i]);
+                if (i % 2)
+                    dallocx(p, flags[f]);
+                else
+                    sdallocx(p, sizes[i], flags[f]);
+            }
+        }
+    }
+    printf("mallocx: %d, nallocx(100): %zu\n", ok, nallocx(100, 0));
+
+    // rallocx keeps the contents and zeroes past the old usable size
+    char* p = mallocx(100, 0);
+    size_t old_usable = sallocx(p, 0);
+    memset(p, 7, old_usable);
+    p = rallocx(p, 50000, MALLOCX_ZERO);
+    printf("rallocx: kept %d, zeroed %d\n", p[0] == 7 && p[old_usable - 1] == 7,
+           is_zero(p + old_usable, 50000 - old_usable));
+    dallocx(p, 0);
+
+    // large blocks grow and shrink in place
+    char* big = mallocx(1 << 20, 0);
+    char* next = mallocx(1 << 20, 0);
+    dallocx(next, 0);
+    size_t grown = xallocx(big, 2 << 20, 0, 0);
+    size_t shrunk = xallocx(big, 512 << 10, 0, 0);
+    printf("xallocx: grown %d, shrunk %d\n", grown == 2 << 20, shrunk == 512 << 10);
+    char* moved = rallocx(big, 256 << 10, 0);
+    printf("rallocx in place: %d\n", moved == big);
+    dallocx(moved, 0);
+
+    // small blocks can't change size class in place
+    char* small = mallocx(24, 0);
+    printf("small xallocx: %d\n", xallocx(small, 1000, 0, 0) == sallocx(small, 0));
+    dallocx(small, MALLOCX_TCACHE_NONE);
+
+    unsigned arena = r3malloc_arena_create();
+    char* in_arena = mallocx(64, MALLOCX_ARENA(arena) | MALLOCX_ZERO);
+    printf("arena: %d, unknown arena: %p\n", in_arena != NULL && is_zero(in_arena, 64),
+           mallocx(64, MALLOCX_ARENA(arena + 10)));
+    dallocx(in_arena, 0);
+    return 0;
+}
@@ -0,0 +1,94 @@
+#include <errno.h>
+#include <pthread.h>
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+int r3malloc_arena_create(void);
+int r3malloc_thread_arena_set(unsigned);
+void* r3malloc_arena_malloc(unsigned, size_t);
+
+#define N 10000
+
+static size_t arena_superblocks(unsigned arena) {
+    char name[64];
+    size_t value = 0, len = sizeof(value);
+    snprintf(name, sizeof(name), "stats.arenas.%u.superblocks", arena);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static void* ptrs[N];
+static int arena0_unchanged;
+
+static void* bound_thread(void* arg) {
+    unsigned arena = *(unsigned*) arg;
+    r3malloc_thread_arena_set(arena);
+
+    unsigned current;
+    size_t len = sizeof(current);
+    r3malloc_ctl("thread.arena", &current, &len, NULL, 0);
+    printf("thread arena: %u\n", current);
+
+    size_t before = arena_superblocks(0);
+    for (int i = 0; i < N; i++) {
+        ptrs[i] = malloc(48);
+        memset(ptrs[i], 0xab, 48);
+    }
+    arena0_unchanged = arena_superblocks(0) == before;
+    return NULL;
+}
+
+int main() {
+    // warm up stdio before counting superblocks
+    printf("arena runs\n");
+
+    unsigned arena = r3malloc_arena_create();
+    unsigned narenas;
+    size_t len = sizeof(narenas);
+    r3malloc_ctl("arenas.narenas", &narenas, &len, NULL, 0);
+    printf("created: %u, narenas: %u\n", arena, narenas);
+
+    // a thread bound to the new arena only takes superblocks from it
+    pthread_t t;
+    pthread_create(&t, NULL, bound_thread, &arena);
+    pthread_join(t, NULL);
+    printf("new arena has superblocks: %d, arena 0 unchanged: %d\n",
+           arena_superblocks(arena) > 0, arena0_unchanged);
+
+    for (int i = 0; i < N; i++)
+        free(ptrs[i]);
+
+    // per call selection, reusing partial superblocks of the arena
+    size_t before = arena_superblocks(0);
+    int ok = 1;
+    for (int round = 0; round < 3; round++) {
+        for (int i = 0; i < N; i++) {
+            ptrs[i] = r3malloc_arena_malloc(arena, 24 + i % 200);
+            memset(ptrs[i], i, 24 + i % 200);
+        }
+        for (int i = 0; i < N; i++) {
+            unsigned char* p = ptrs[i];
+            if (p[0] != (unsigned char) i || p[23 + i % 200] != (unsigned char) i)
+                ok = 0;
+        }
+        for (int i = 0; i < N; i += 2)
+            free(ptrs[i]);
+        for (int i = 1; i < N; i += 2)
+            free(ptrs[i]);
+    }
+    r3malloc_ctl("thread.tcache.flush", NULL, NULL, NULL, 0);
+    printf("arena blocks intact: %d, arena 0 unchanged: %d\n", ok, arena_superblocks(0) == before);
+
+    errno = 0;
+    void* bad = r3malloc_arena_malloc(narenas + 5, 16);
+    printf("unknown arena: %p %d, bind: %d\n", bad, errno == EINVAL, r3malloc_thread_arena_set(narenas + 5));
+
+    // large blocks don't belong to an arena
+    void* large = r3malloc_arena_malloc(arena, 1 << 20);
+    memset(large, 1, 1 << 20);
+    free(large);
+    return 0;
+}
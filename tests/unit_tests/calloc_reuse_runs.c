@@ -0,0 +1,48 @@
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void free(void*);
+
+#define NUM_PTRS 2048
+
+// mixes fresh superblock blocks with dirty recycled ones, calloc must zero both
+int main() {
+    size_t sizes[] = { 8, 48, 512, 4000, 14336, 1 << 20 };
+    int num_sizes = sizeof(sizes) / sizeof(sizes[0]);
+    void* ptrs[NUM_PTRS];
+    int errors = 0;
+
+    for (int s = 0; s < num_sizes; s++) {
+        size_t size = sizes[s];
+        int num = size > 4096 ? 16 : NUM_PTRS;
+
+        for (int i = 0; i < num; i++) {
+            ptrs[i] = malloc(size);
+            memset(ptrs[i], 0xff, size);
+        }
+        for (int i = 0; i < num; i += 2) {
+            free(ptrs[i]);
+        }
+
+        for (int i = 0; i < num; i += 2) {
+            unsigned char* ptr = (unsigned char*) calloc(1, size);
+            for (size_t j = 0; j < size; j++) {
+                if (ptr[j] != 0) {
+                    errors++;
+                    break;
+                }
+            }
+            ptrs[i] = ptr;
+            memset(ptr, 0xff, size);
+        }
+
+        for (int i = 0; i < num; i++) {
+            free(ptrs[i]);
+        }
+        printf("size %ld: %d dirty blocks\n", size, errors);
+    }
+
+    return errors != 0;
+}
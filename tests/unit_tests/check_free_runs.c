@@ -0,0 +1,99 @@
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+// needs the library built with --features check_free
+
+void* malloc(size_t);
+void* aligned_alloc(size_t, size_t);
+void free(void*);
+void free_sized(void*, size_t);
+
+// runs `bad_free` in a child, which has to be killed by the check
+static int aborts(void (*bad_free)(void)) {
+    fflush(stdout);
+    pid_t pid = fork();
+    if (pid == 0) {
+        bad_free();
+        _exit(0);
+    }
+
+    int status;
+    waitpid(pid, &status, 0);
+    return WIFSIGNALED(status) && WTERMSIG(status) == SIGABRT;
+}
+
+// volatile so that the compiler doesn't flag the bad frees
+static void* volatile escape;
+
+static void stack_free(void) {
+    char buf[64];
+    escape = buf;
+    free(escape);
+}
+
+static void interior_free(void) {
+    char* p = malloc(100);
+    escape = p + 8;
+    free(escape);
+}
+
+static void double_free(void) {
+    char* p = malloc(100);
+    free(p);
+    escape = p;
+    free(escape);
+}
+
+static void double_free_sized(void) {
+    char* p = malloc(100);
+    free_sized(p, 100);
+    free_sized(p, 100);
+}
+
+static void large_double_free(void) {
+    char* p = malloc(1 << 20);
+    free(p);
+    escape = p;
+    free(escape);
+}
+
+static void large_interior_free(void) {
+    char* p = malloc(1 << 20);
+    escape = p + 64;
+    free(escape);
+}
+
+int main() {
+    // valid frees go through, including ones of blocks freed and allocated again
+    void* ptrs[1000];
+    for (int round = 0; round < 3; round++) {
+        for (int i = 0; i < 1000; i++)
+            ptrs[i] = malloc(i * 8 + 1);
+        for (int i = 0; i < 1000; i++)
+            free(ptrs[i]);
+    }
+    free(aligned_alloc(1 << 16, 100));
+    free_sized(malloc(50), 50);
+
+    int results[] = {
+        aborts(stack_free),
+        aborts(interior_free),
+        aborts(double_free),
+        aborts(double_free_sized),
+        aborts(large_double_free),
+        aborts(large_interior_free),
+    };
+    printf("stack: %d, interior: %d, double: %d, double sized: %d, large double: %d, large interior: %d\n",
+           results[0], results[1], results[2], results[3], results[4], results[5]);
+
+    for (int i = 0; i < 6; i++) {
+        if (!results[i])
+            return 1;
+    }
+
+    return 0;
+}
@@ -0,0 +1,76 @@
+#include <stdbool.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+void r3malloc_stats_print(void (*)(void*, const char*), void*, const char*);
+
+// R3MALLOC_CONF is applied after this, so it wins for target_apf
+const char* r3malloc_conf = "target_apf:500,num_free_intervals:4,bogus:1,huge_threshold:x";
+
+static unsigned read_u32(const char* name) {
+    unsigned value = 0;
+    size_t len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static char out[1 << 16];
+
+static void print_cb(void* opaque, const char* s) {
+    strncat(out, s, sizeof(out) - strlen(out) - 1);
+}
+
+int main() {
+    // the options are read by the first malloc, so they have to be in the environment
+    // before the process starts
+    if (getenv("R3MALLOC_CONF") == NULL) {
+        char* env[] = {"R3MALLOC_CONF=target_apf:700,reuse_compute_interval:50,purge_mode:free", NULL};
+        char* args[] = {"conf_runs", NULL};
+        execve("/proc/self/exe", args, env);
+        return 1;
+    }
+
+    void* ptrs[1000];
+    for (int i = 0; i < 1000; i++)
+        ptrs[i] = malloc(64);
+    for (int i = 0; i < 1000; i++)
+        free(ptrs[i]);
+
+    unsigned apf = read_u32("apf.target");
+    unsigned sc_apf = read_u32("sc.3.apf.target");
+    unsigned intervals = read_u32("opt.num_free_intervals");
+    unsigned compute = read_u32("opt.reuse_compute_interval");
+    printf("apf.target: %u, sc.3.apf.target: %u\n", apf, sc_apf);
+    printf("num_free_intervals: %u, reuse_compute_interval: %u\n", intervals, compute);
+
+    const char* mode = NULL;
+    size_t len = sizeof(mode);
+    r3malloc_ctl("purge.mode", &mode, &len, NULL, 0);
+    size_t threshold = 0;
+    len = sizeof(threshold);
+    r3malloc_ctl("huge.threshold", &threshold, &len, NULL, 0);
+    printf("purge.mode: %s, huge.threshold: %zu\n", mode, threshold);
+
+    bool log = true;
+    int ret = r3malloc_ctl("opt.log", NULL, NULL, &log, sizeof(log));
+    printf("opt.log write: %d\n", ret);
+
+    r3malloc_stats_print(print_cb, NULL, "b");
+    char* opts = strstr(out, "Options: ");
+    if (opts == NULL)
+        return 1;
+    *strchr(opts, '\n') = '\0';
+    printf("%s\n", opts);
+
+    if (apf != 700 || sc_apf != 700 || intervals != 4 || compute != 50)
+        return 1;
+    if (strcmp(mode, "free") != 0 || threshold != 2 << 20 || ret == 0)
+        return 1;
+    if (strstr(opts, "target_apf:700,reuse_compute_interval:50,num_free_intervals:4,") == NULL)
+        return 1;
+
+    return 0;
+}
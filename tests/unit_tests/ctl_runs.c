@@ -0,0 +1,48 @@
+#include <stdio.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+int main() {
+    const char* version;
+    size_t len = sizeof(version);
+    int status = r3malloc_ctl("version", &version, &len, NULL, 0);
+    printf("status: %d, version: %s\n", status, version);
+
+    size_t count;
+    len = sizeof(count);
+    status = r3malloc_ctl("sc.count", &count, &len, NULL, 0);
+    printf("status: %d, size classes: %ld\n", status, count);
+
+    unsigned block_size, sb_size;
+    len = sizeof(block_size);
+    r3malloc_ctl("sc.12.block_size", &block_size, &len, NULL, 0);
+    r3malloc_ctl("sc.12.sb_size", &sb_size, &len, NULL, 0);
+    printf("sc 12: block size %u, superblock size %u\n", block_size, sb_size);
+
+    unsigned apf = 500, old_apf;
+    len = sizeof(old_apf);
+    status = r3malloc_ctl("apf.target", &old_apf, &len, &apf, sizeof(apf));
+    printf("status: %d, old target apf: %u\n", status, old_apf);
+    r3malloc_ctl("sc.3.apf.target", &apf, &len, NULL, 0);
+    printf("sc 3 target apf: %u\n", apf);
+
+    void* ptr = malloc(block_size);
+    free(ptr);
+    unsigned nblocks;
+    len = sizeof(nblocks);
+    r3malloc_ctl("sc.12.tcache.nblocks", &nblocks, &len, NULL, 0);
+    printf("cached blocks before flush: %u\n", nblocks);
+    printf("status: %d\n", r3malloc_ctl("thread.tcache.flush", NULL, NULL, NULL, 0));
+    r3malloc_ctl("sc.12.tcache.nblocks", &nblocks, &len, NULL, 0);
+    printf("cached blocks after flush: %u\n", nblocks);
+
+    // expected failures: unknown name, wrong size, write to read-only value, byte that isn't a bool
+    printf("unknown: %d\n", r3malloc_ctl("sc.12.nothing", NULL, NULL, NULL, 0));
+    len = 1;
+    printf("bad size: %d\n", r3malloc_ctl("sc.12.block_size", &block_size, &len, NULL, 0));
+    printf("read-only: %d\n", r3malloc_ctl("sc.12.block_size", NULL, NULL, &block_size, sizeof(block_size)));
+    unsigned char not_bool = 2;
+    printf("bad bool: %d\n", r3malloc_ctl("thread.tcache.enabled", NULL, NULL, &not_bool, sizeof(not_bool)));
+}
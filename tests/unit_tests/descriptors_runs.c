@@ -0,0 +1,92 @@
+#include <pthread.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+void* malloc(size_t);
+void free(void*);
+_Bool r3malloc_thread_tcache_set_enabled(_Bool);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define BURST_THREADS 4
+#define CHURN_THREADS 4
+#define BURST 25000
+#define ROUNDS 3
+// every large block takes a descriptor
+#define LARGE_SIZE (64 * 1024)
+#define CHUNK (64 * 1024)
+
+static volatile int stop;
+static size_t peak;
+
+static size_t stat(const char* name) {
+    size_t value = 0, len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static void* burst(void* arg) {
+    void** ptrs = malloc(BURST * sizeof(void*));
+    for (int i = 0; i < BURST; i++)
+        ptrs[i] = malloc(LARGE_SIZE);
+    size_t bytes = stat("stats.descriptors.bytes");
+    if (bytes > peak)
+        peak = bytes;
+    for (int i = BURST - 1; i >= 0; i--)
+        free(ptrs[i]);
+    free(ptrs);
+    return NULL;
+}
+
+// pops partial superblocks all along, reading descriptors of chunks that may be reclaimed
+static void* churn(void* arg) {
+    void* ptrs[64] = {0};
+    unsigned seed = (unsigned)(uintptr_t)arg;
+
+    r3malloc_thread_tcache_set_enabled(0);
+    while (!stop) {
+        int i = rand_r(&seed) % 64;
+        free(ptrs[i]);
+        ptrs[i] = malloc(1 + rand_r(&seed) % 4096);
+    }
+    for (int i = 0; i < 64; i++)
+        free(ptrs[i]);
+    return NULL;
+}
+
+int main() {
+    free(malloc(1));
+    size_t before = stat("stats.descriptors.bytes");
+
+    pthread_t churners[CHURN_THREADS];
+    for (uintptr_t i = 0; i < CHURN_THREADS; i++)
+        pthread_create(&churners[i], NULL, churn, (void*)(i + 1));
+
+    for (int round = 0; round < ROUNDS; round++) {
+        pthread_t threads[BURST_THREADS];
+        for (int i = 0; i < BURST_THREADS; i++)
+            pthread_create(&threads[i], NULL, burst, NULL);
+        for (int i = 0; i < BURST_THREADS; i++)
+            pthread_join(threads[i], NULL);
+    }
+
+    stop = 1;
+    for (int i = 0; i < CHURN_THREADS; i++)
+        pthread_join(churners[i], NULL);
+
+    // free descriptors past the reclaim threshold were given back along the way
+    size_t after = stat("stats.descriptors.bytes");
+    r3malloc_ctl("purge.now", NULL, NULL, NULL, 0);
+    size_t purged = stat("stats.descriptors.bytes");
+    size_t live = stat("stats.descriptors.live");
+
+    printf("descriptor bytes before: %zu, peak: %zu, after the bursts: %zu, after purging: %zu, live: %zu\n",
+           before, peak, after, purged, live);
+
+    if (after >= peak)
+        return 1;
+    // what's left after purging is pinned by live descriptors, at least one per chunk
+    if (purged >= peak / 2 || purged > live * CHUNK)
+        return 1;
+    return 0;
+}
@@ -0,0 +1,113 @@
+#include <pthread.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void free(void*);
+void malloc_disable(void);
+void malloc_enable(void);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define WORKERS 4
+#define FORKS 100
+#define SLOTS 64
+
+static volatile int stop;
+
+// keeps the shared heap busy: refills, flushes, new superblocks and large blocks
+static void* work(void* arg) {
+    void* ptrs[SLOTS] = {0};
+    unsigned seed = (unsigned)(uintptr_t)arg;
+
+    while (!stop) {
+        int i = rand_r(&seed) % SLOTS;
+        free(ptrs[i]);
+        size_t size = rand_r(&seed) % 8 == 0 ? 64 * 1024 + rand_r(&seed) % (1 << 20) : 1 + rand_r(&seed) % 4096;
+        ptrs[i] = rand_r(&seed) % 2 ? malloc(size) : calloc(1, size);
+        memset(ptrs[i], i, size < 256 ? size : 256);
+    }
+
+    for (int i = 0; i < SLOTS; i++)
+        free(ptrs[i]);
+    return NULL;
+}
+
+static int churn(void) {
+    void* ptrs[1000];
+    for (int i = 0; i < 1000; i++) {
+        size_t size = i % 50 == 0 ? 200000 : 8 + i * 13 % 5000;
+        ptrs[i] = malloc(size);
+        if (!ptrs[i])
+            return 0;
+        memset(ptrs[i], i, size);
+    }
+    for (int i = 0; i < 1000; i++) {
+        if (((unsigned char*)ptrs[i])[0] != (unsigned char)i)
+            return 0;
+        free(ptrs[i]);
+    }
+    return 1;
+}
+
+static void* child_thread(void* arg) {
+    return (void*)(uintptr_t)churn();
+}
+
+// must not hang on anything the parent's threads held when it forked
+static void child(void) {
+    alarm(10);
+
+    if (!churn())
+        _exit(2);
+
+    pthread_t thread;
+    void* ret;
+    if (pthread_create(&thread, NULL, child_thread, NULL) != 0)
+        _exit(3);
+    pthread_join(thread, &ret);
+    if (!ret)
+        _exit(4);
+
+    unsigned arena = 0;
+    size_t len = sizeof(arena);
+    if (r3malloc_ctl("arenas.create", &arena, &len, NULL, 0) != 0)
+        _exit(5);
+
+    malloc_disable();
+    malloc_enable();
+    _exit(0);
+}
+
+int main() {
+    pthread_t threads[WORKERS];
+    for (long i = 0; i < WORKERS; i++)
+        pthread_create(&threads[i], NULL, work, (void*)(i + 1));
+
+    int failed = 0;
+    for (int i = 0; i < FORKS; i++) {
+        pid_t pid = fork();
+        if (pid == 0)
+            child();
+
+        int status;
+        waitpid(pid, &status, 0);
+        if (!WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+            printf("child %d: %s %d\n", i, WIFEXITED(status) ? "exit" : "signal",
+                   WIFEXITED(status) ? WEXITSTATUS(status) : WTERMSIG(status));
+            failed++;
+        }
+    }
+
+    stop = 1;
+    for (int i = 0; i < WORKERS; i++)
+        pthread_join(threads[i], NULL);
+
+    printf("forks: %d, failed: %d\n", FORKS, failed);
+    return failed ? 1 : 0;
+}
@@ -0,0 +1,37 @@
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void* realloc(void*, size_t);
+void* aligned_alloc(size_t, size_t);
+void free_sized(void*, size_t);
+void free_aligned_sized(void*, size_t, size_t);
+
+int main() {
+    size_t sizes[] = { 0, 1, 8, 24, 100, 1000, 4096, 14336, 14337, 100000 };
+    int num_sizes = sizeof(sizes) / sizeof(sizes[0]);
+
+    for (int round = 0; round < 1000; round++) {
+        for (int i = 0; i < num_sizes; i++) {
+            char* ptr = (char*) malloc(sizes[i]);
+            memset(ptr, 0xab, sizes[i]);
+            free_sized(ptr, sizes[i]);
+        }
+    }
+
+    for (size_t alignment = 8; alignment <= 8192; alignment *= 2) {
+        char* ptr = (char*) aligned_alloc(alignment, 3 * alignment);
+        printf("alignment %ld: %s\n", alignment, ((size_t) ptr % alignment) == 0 ? "ok" : "misaligned");
+        free_aligned_sized(ptr, alignment, 3 * alignment);
+    }
+
+    // shrinking into a smaller size class moves the block, so the new size is the right one
+    char* ptr = (char*) malloc(1000);
+    strcpy(ptr, "r3malloc");
+    ptr = (char*) realloc(ptr, 16);
+    printf("%s\n", ptr);
+    free_sized(ptr, 16);
+
+    free_sized(NULL, 8);
+    return 0;
+}
@@ -0,0 +1,62 @@
+#include <stdio.h>
+#include <stdbool.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+size_t malloc_usable_size(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define HUGE_PAGE (2 << 20)
+#define NUM_PTRS 100000
+
+int main() {
+    // superblocks have to be switched before anything is allocated
+    bool enable = true;
+    int sb_status = r3malloc_ctl("huge.superblocks", NULL, NULL, &enable, sizeof(enable));
+
+    const char* mode = "thp";
+    int status = r3malloc_ctl("huge.mode", NULL, NULL, &mode, sizeof(mode));
+    size_t len = sizeof(mode);
+    r3malloc_ctl("huge.mode", &mode, &len, NULL, 0);
+    printf("status: %d, superblocks status: %d, mode: %s\n", status, sb_status, mode);
+
+    char* large = (char*) malloc(5 << 20);
+    memset(large, 1, 5 << 20);
+    printf("large: aligned %d, usable %ld\n", ((size_t) large % HUGE_PAGE) == 0, malloc_usable_size(large));
+    free(large);
+
+    char* small = (char*) malloc(64 << 10);
+    printf("below threshold: usable %ld\n", malloc_usable_size(small));
+    free(small);
+
+    // falls back to THP when no hugetlbfs pages are reserved
+    mode = "hugetlb";
+    r3malloc_ctl("huge.mode", NULL, NULL, &mode, sizeof(mode));
+    large = (char*) malloc(3 << 20);
+    memset(large, 1, 3 << 20);
+    printf("hugetlb: usable %ld\n", malloc_usable_size(large));
+    free(large);
+
+    // superblocks are recycled through the free lists
+    static void* ptrs[NUM_PTRS];
+    for (int round = 0; round < 3; round++) {
+        for (int i = 0; i < NUM_PTRS; i++) {
+            ptrs[i] = malloc(48);
+            memset(ptrs[i], 0xff, 48);
+        }
+        for (int i = 0; i < NUM_PTRS; i++) {
+            free(ptrs[i]);
+        }
+    }
+
+    enable = false;
+    status = r3malloc_ctl("huge.superblocks", NULL, NULL, &enable, sizeof(enable));
+    printf("disable after first superblock: %d\n", status);
+
+    mode = "always";
+    status = r3malloc_ctl("huge.mode", NULL, NULL, &mode, sizeof(mode));
+    printf("unknown mode: %d\n", status);
+
+    return 0;
+}
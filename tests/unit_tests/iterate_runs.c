@@ -0,0 +1,73 @@
+#include <pthread.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+int malloc_iterate(uintptr_t, size_t, void (*)(uintptr_t, size_t, void*), void*);
+void malloc_disable(void);
+void malloc_enable(void);
+
+#define N 100
+
+static void* ptrs[N];
+static int seen[N];
+static void* large;
+static size_t large_seen;
+static int total;
+
+static void visit(uintptr_t ptr, size_t size, void* arg) {
+    total++;
+    for (int i = 0; i < N; i++)
+        if ((void*)ptr == ptrs[i] && size >= 48)
+            seen[i]++;
+    if ((void*)ptr == large)
+        large_seen = size;
+}
+
+static volatile int allocated;
+
+// needs the shared heap, so it has to wait while the heap is frozen
+static void* allocate_large(void* arg) {
+    free(malloc(1 << 20));
+    allocated = 1;
+    return NULL;
+}
+
+int main() {
+    for (int i = 0; i < N; i++)
+        ptrs[i] = malloc(48);
+    // the odd ones go to this thread's cache and must not show up
+    for (int i = 1; i < N; i += 2)
+        free(ptrs[i]);
+    large = malloc(1 << 20);
+
+    malloc_disable();
+    malloc_iterate(0, SIZE_MAX, visit, NULL);
+    int all = total;
+
+    total = 0;
+    malloc_iterate((uintptr_t)ptrs[0], 1, visit, NULL);
+    int one = total;
+
+    pthread_t thread;
+    pthread_create(&thread, NULL, allocate_large, NULL);
+    usleep(100000);
+    int blocked = !allocated;
+    malloc_enable();
+    pthread_join(thread, NULL);
+
+    int ok = 1;
+    for (int i = 0; i < N; i++) {
+        if (seen[i] != (i % 2 == 0 ? 1 + (i == 0) : 0)) {
+            printf("block %d seen %d times\n", i, seen[i]);
+            ok = 0;
+        }
+    }
+    printf("allocations: %d, in range of the first: %d, large: %zu\n", all, one, large_seen);
+    printf("blocked while disabled: %d, allocated after: %d\n", blocked, allocated);
+
+    return ok && one == 1 && large_seen >= 1 << 20 && blocked && allocated ? 0 : 1;
+}
@@ -0,0 +1,52 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void* aligned_alloc(size_t, size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+const char* r3malloc_conf = "junk:true";
+
+#define LARGE (1 << 20)
+
+static int filled(const volatile unsigned char* ptr, size_t from, size_t to, unsigned char byte) {
+    for (size_t i = from; i < to; i++) {
+        if (ptr[i] != byte)
+            return 0;
+    }
+    return 1;
+}
+
+int main() {
+    const char* junk = NULL;
+    size_t len = sizeof(junk);
+    r3malloc_ctl("opt.junk", &junk, &len, NULL, 0);
+
+    unsigned char* small = malloc(100);
+    unsigned char* zeroed = calloc(1, 100);
+    unsigned char* aligned = aligned_alloc(64, 64);
+    unsigned char* large = malloc(LARGE);
+    unsigned char* aligned_large = aligned_alloc(1 << 16, LARGE);
+    int alloc = filled(small, 0, 100, 0xa5) && filled(aligned, 0, 64, 0xa5) &&
+                filled(large, 0, LARGE, 0xa5) && filled(aligned_large, 0, LARGE, 0xa5);
+    int calloc_zero = filled(zeroed, 0, 100, 0);
+
+    // freed blocks stay mapped, only the first word of a small one links it into the cache
+    free(small);
+    free(large);
+    int freed = filled(small, sizeof(void*), 100, 0x5a) && filled(large, LARGE / 4, LARGE / 2, 0x5a);
+
+    printf("opt.junk: %s, alloc: %d, calloc: %d, free: %d\n", junk, alloc, calloc_zero, freed);
+
+    free(zeroed);
+    free(aligned);
+    free(aligned_large);
+
+    if (strcmp(junk, "true") != 0 || !alloc || !calloc_zero || !freed)
+        return 1;
+
+    return 0;
+}
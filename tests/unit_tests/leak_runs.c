@@ -0,0 +1,85 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+size_t malloc_usable_size(void*);
+
+// the quarantine keeps some of the freed blocks of the exiting thread
+const char* r3malloc_conf = "leak_report:true,quarantine:16384";
+
+#define SMALL 3000
+#define LARGE (1 << 20)
+
+void* large;
+
+// leaves freed blocks in the cache of a thread that is gone by exit
+static void* churn(void* arg) {
+    void* ptrs[50];
+    for (int i = 0; i < 50; i++)
+        ptrs[i] = malloc(SMALL);
+    for (int i = 0; i < 50; i++)
+        free(ptrs[i]);
+    return NULL;
+}
+
+static void leak(void) {
+    pthread_t thread;
+    pthread_create(&thread, NULL, churn, NULL);
+    pthread_join(thread, NULL);
+
+    void* ptrs[100];
+    for (int i = 0; i < 100; i++)
+        ptrs[i] = malloc(SMALL);
+    // the first three are never freed
+    for (int i = 3; i < 100; i++)
+        free(ptrs[i]);
+
+    large = malloc(LARGE);
+    free(malloc(LARGE));
+    exit(0);
+}
+
+int main() {
+    int fds[2];
+    if (pipe(fds) != 0)
+        return 1;
+
+    pid_t pid = fork();
+    if (pid == 0) {
+        dup2(fds[1], STDERR_FILENO);
+        close(fds[0]);
+        leak();
+    }
+    close(fds[1]);
+
+    static char out[1 << 12];
+    size_t len = 0;
+    ssize_t n;
+    while ((n = read(fds[0], out + len, sizeof(out) - 1 - len)) > 0)
+        len += n;
+    out[len] = '\0';
+    printf("%s", out);
+
+    int status;
+    waitpid(pid, &status, 0);
+
+    void* p = malloc(SMALL);
+    size_t block = malloc_usable_size(p);
+    free(p);
+
+    char small_line[128];
+    snprintf(small_line, sizeof(small_line), "(%zu byte blocks): 3 leaked, %zu bytes\n", block, 3 * block);
+    char large_line[128];
+    snprintf(large_line, sizeof(large_line), "large blocks: 1 leaked, %d bytes\n", LARGE);
+
+    // libc may leave blocks of its own, e.g. for the thread
+    int ok = WIFEXITED(status) && strstr(out, small_line) != NULL && strstr(out, large_line) != NULL;
+    fflush(stdout);
+    // skip the report of this process, which keeps its stdio buffers
+    _exit(ok ? 0 : 1);
+}
@@ -0,0 +1,26 @@
+#include <stdio.h>
+#include <malloc.h>
+
+int main() {
+    void* small[100];
+    for (int i = 0; i < 100; i++) {
+        small[i] = malloc(48);
+    }
+    void* large = malloc(1 << 20);
+
+    struct mallinfo2 mi = mallinfo2();
+    printf("arena: %zu, in use: %zu, free: %zu, mmap: %zu in %zu blocks, cached: %zu\n",
+           mi.arena, mi.uordblks, mi.fordblks, mi.hblkhd, mi.hblks, mi.fsmblks);
+
+    malloc_stats();
+    malloc_info(0, stdout);
+
+    for (int i = 0; i < 100; i++) {
+        free(small[i]);
+    }
+    free(large);
+
+    mi = mallinfo2();
+    printf("after free: in use: %zu, mmap: %zu in %zu blocks\n", mi.uordblks, mi.hblkhd, mi.hblks);
+    printf("bad options: %d\n", malloc_info(1, stdout));
+}
@@ -0,0 +1,60 @@
+#include <stdio.h>
+#include <string.h>
+#include <sys/mman.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+// a page heap run
+#define LARGE (1 << 20)
+// past a page heap region, kept in the extent cache
+#define HUGE_BLOCK (96 << 20)
+
+static size_t stat(const char* name) {
+    size_t value = 0, len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static int is_zero(const char* ptr, size_t size) {
+    for (size_t i = 0; i < size; i++) {
+        if (ptr[i] != 0) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+// MADV_DONTNEED fails on locked pages, which then keep their contents. Freeing the block
+// doesn't unlock them.
+static int check(size_t size) {
+    char* ptr = (char*) malloc(size);
+    memset(ptr, 0xff, size);
+    if (mlock(ptr, size) != 0) {
+        printf("mlock failed, skipping\n");
+        free(ptr);
+        return 0;
+    }
+    free(ptr);
+
+    size_t npurge = stat("stats.npurge");
+    r3malloc_ctl("purge.now", NULL, NULL, NULL, 0);
+    // printing may take dirty pages for its buffer
+    size_t dirty = stat("stats.dirty");
+    printf("%zu bytes: dirty %zu, purges %zu\n", size, dirty, stat("stats.npurge") - npurge);
+    if (dirty < size) {
+        return 1;
+    }
+
+    ptr = (char*) calloc(1, size);
+    int zero = is_zero(ptr, size);
+    printf("calloc zero: %d\n", zero);
+    free(ptr);
+    return !zero;
+}
+
+int main() {
+    return check(LARGE) || check(HUGE_BLOCK);
+}
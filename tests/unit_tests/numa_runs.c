@@ -0,0 +1,72 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+// more nodes than this machine is likely to have, so they are simulated
+const char* r3malloc_conf = "numa:true,numa_nodes:2";
+
+#define MPOL_PREFERRED 1
+#define MPOL_F_ADDR 2
+
+static unsigned read_u32(const char* name) {
+    unsigned value = 0;
+    size_t len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+// memory policy of the page holding ptr, -1 without NUMA support
+static int policy(void* ptr, unsigned long* mask) {
+    int mode = -1;
+    unsigned long masks[16] = {0};
+    if (syscall(SYS_get_mempolicy, &mode, masks, 1024, ptr, MPOL_F_ADDR) != 0)
+        return -1;
+    *mask = masks[0];
+    return mode;
+}
+
+static unsigned thread_arena;
+static int thread_policy;
+static size_t superblocks;
+
+static void* run(void* arg) {
+    void* p = malloc(64);
+    unsigned long mask;
+    thread_arena = read_u32("thread.arena");
+    thread_policy = policy(p, &mask);
+    // the superblock goes away once the thread exits and flushes its cache
+    size_t len = sizeof(superblocks);
+    r3malloc_ctl("stats.arenas.1.superblocks", &superblocks, &len, NULL, 0);
+    free(p);
+    return NULL;
+}
+
+int main() {
+    void* p = malloc(64);
+    unsigned nodes = read_u32("numa.nodes");
+    unsigned arena = read_u32("thread.arena");
+    unsigned long mask = 0;
+    int mode = policy(p, &mask);
+
+    pthread_t thread;
+    pthread_create(&thread, NULL, run, NULL);
+    pthread_join(thread, NULL);
+
+    printf("nodes: %u, main thread arena: %u, other thread arena: %u\n", nodes, arena, thread_arena);
+    printf("node 0 policy: %d (mask %lx), node 1 policy: %d, arena 1 superblocks: %zu\n",
+           mode, mask, thread_policy, superblocks);
+    free(p);
+
+    if (nodes != 2 || arena != 0 || thread_arena != 1 || superblocks == 0)
+        return 1;
+    // node 0 exists, node 1 is simulated and gets no policy
+    if (mode != -1 && (mode != MPOL_PREFERRED || mask != 1 || thread_policy != 0))
+        return 1;
+    return 0;
+}
@@ -0,0 +1,37 @@
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define LARGE (1 << 20)
+
+int main() {
+    // nothing prints until the end, stdio buffers would take pages from the heap too
+    char* a = (char*) malloc(LARGE);
+    char* b = (char*) malloc(LARGE);
+    memset(a, 1, LARGE);
+    memset(b, 1, LARGE);
+    int adjacent = b == a + LARGE;
+
+    // neighbouring runs coalesce, so a block twice the size fits where they were
+    free(a);
+    free(b);
+    char* c = (char*) malloc(2 * LARGE);
+    int coalesced = c == a;
+
+    // pages freed by large blocks serve superblocks of any size class
+    free(c);
+    void* small = malloc(4000);
+    int shared = (char*) small >= a && (char*) small < a + 2 * LARGE;
+    void* other = malloc(64);
+    int shared_other = (char*) other >= a && (char*) other < a + 2 * LARGE;
+
+    printf("adjacent: %d, coalesced: %d, superblocks from freed pages: %d %d\n",
+           adjacent, coalesced, shared, shared_other);
+
+    free(small);
+    free(other);
+    return 0;
+}
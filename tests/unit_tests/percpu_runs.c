@@ -0,0 +1,105 @@
+#include <pthread.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+const char* r3malloc_conf = "percpu_cache:true";
+
+#define THREADS 32
+#define BLOCKS 500
+#define BLOCK_SIZE 64
+// slots of a CPU's bin
+#define SLOTS 64
+
+static void* shared[THREADS][BLOCKS];
+static int failed;
+
+static void* run(void* arg) {
+    long id = (long)arg;
+    void* ptrs[BLOCKS];
+
+    for (int round = 0; round < 20; round++) {
+        for (int i = 0; i < BLOCKS; i++) {
+            size_t size = 16 + (i * 37 + round) % 4000;
+            ptrs[i] = malloc(size);
+            memset(ptrs[i], (int)(id + i), size);
+        }
+        for (int i = 0; i < BLOCKS; i++) {
+            size_t size = 16 + (i * 37 + round) % 4000;
+            unsigned char* p = ptrs[i];
+            if (p[0] != (unsigned char)(id + i) || p[size - 1] != (unsigned char)(id + i))
+                failed = 1;
+            free(p);
+        }
+
+        char* z = calloc(1, 200);
+        for (int i = 0; i < 200; i++)
+            if (z[i] != 0)
+                failed = 1;
+        memset(z, 0xff, 200);
+        free(z);
+    }
+
+    // freed by the main thread, from whatever CPU it runs on
+    for (int i = 0; i < BLOCKS; i++)
+        shared[id][i] = malloc(BLOCK_SIZE);
+    return NULL;
+}
+
+static size_t size_class(size_t size) {
+    size_t count = 0, len = sizeof(count);
+    r3malloc_ctl("sc.count", &count, &len, NULL, 0);
+    for (size_t i = 1; i < count; i++) {
+        char name[64];
+        unsigned block_size = 0;
+        len = sizeof(block_size);
+        snprintf(name, sizeof(name), "sc.%zu.block_size", i);
+        r3malloc_ctl(name, &block_size, &len, NULL, 0);
+        if (block_size >= size)
+            return i;
+    }
+    return 0;
+}
+
+int main() {
+    bool enabled = false;
+    unsigned ncpus = 0;
+    size_t len = sizeof(enabled);
+    r3malloc_ctl("opt.percpu_cache", &enabled, &len, NULL, 0);
+    len = sizeof(ncpus);
+    r3malloc_ctl("percpu.ncpus", &ncpus, &len, NULL, 0);
+
+    pthread_t threads[THREADS];
+    for (long i = 0; i < THREADS; i++)
+        pthread_create(&threads[i], NULL, run, (void*)i);
+    for (int i = 0; i < THREADS; i++)
+        pthread_join(threads[i], NULL);
+
+    for (int i = 0; i < THREADS; i++)
+        for (int j = 0; j < BLOCKS; j++)
+            free(shared[i][j]);
+
+    char name[64];
+    uint64_t cached = 0;
+    len = sizeof(cached);
+    snprintf(name, sizeof(name), "stats.sc.%zu.cached", size_class(BLOCK_SIZE));
+    r3malloc_ctl(name, &cached, &len, NULL, 0);
+
+    printf("per-CPU caches: %d, cpus: %u, cached %d byte blocks: %llu\n", enabled, ncpus, BLOCK_SIZE,
+           (unsigned long long)cached);
+
+    if (!enabled || failed)
+        return 1;
+    // without rseq everything stays in thread caches
+    if (ncpus == 0)
+        return 0;
+    // the exited threads' caches would hold far more
+    return cached <= (uint64_t)ncpus * SLOTS ? 0 : 1;
+}
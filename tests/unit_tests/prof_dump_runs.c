@@ -0,0 +1,69 @@
+#include <stdio.h>
+#include <stdbool.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+int r3malloc_prof_dump(const char*);
+
+#define NUM_PTRS 4096
+
+int main() {
+    size_t interval = 4096;
+    int status = r3malloc_ctl("prof.sample_interval", NULL, NULL, &interval, sizeof(interval));
+    printf("status: %d, sample interval: %ld\n", status, interval);
+
+    void* ptrs[NUM_PTRS];
+    for (int i = 0; i < NUM_PTRS; i++) {
+        ptrs[i] = malloc(64 + (i % 8) * 128);
+    }
+    void* large = malloc(1 << 20);
+
+    // every other block is freed, so only live samples should remain in the profile
+    for (int i = 0; i < NUM_PTRS; i += 2) {
+        free(ptrs[i]);
+    }
+
+    status = r3malloc_prof_dump("prof_dump_runs.heap");
+    printf("status: %d\n", status);
+
+    const char* filename = "prof_dump_runs.ctl.heap";
+    status = r3malloc_ctl("prof.dump", NULL, NULL, &filename, sizeof(filename));
+    printf("status: %d\n", status);
+
+    FILE* fp = fopen("prof_dump_runs.heap", "r");
+    if (fp == NULL) {
+        return 1;
+    }
+    char line[1024];
+    if (fgets(line, sizeof(line), fp) != NULL) {
+        printf("%s", line);
+    }
+
+    // a sample stack reaches from the allocator through main into libc's start code
+    int samples = 0, shallow = 0;
+    while (fgets(line, sizeof(line), fp) != NULL && line[0] >= '0' && line[0] <= '9') {
+        int frames = 0;
+        for (char* p = strchr(line, '@'); p != NULL; p = strstr(p + 1, " 0x")) {
+            frames += p[0] == ' ';
+        }
+        samples++;
+        shallow += frames < 2;
+    }
+    fclose(fp);
+    printf("samples: %d, with fewer than 2 frames: %d\n", samples, shallow);
+    if (samples == 0 || shallow != 0) {
+        return 1;
+    }
+
+    bool final = true;
+    r3malloc_ctl("prof.final", NULL, NULL, &final, sizeof(final));
+
+    for (int i = 1; i < NUM_PTRS; i += 2) {
+        free(ptrs[i]);
+    }
+    free(large);
+
+    return 0;
+}
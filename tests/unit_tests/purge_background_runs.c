@@ -0,0 +1,40 @@
+#include <stdio.h>
+#include <string.h>
+#include <sys/types.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define LARGE (1 << 20)
+
+static size_t stat(const char* name) {
+    size_t value = 0, len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+int main() {
+    ssize_t decay_ms = 100;
+    r3malloc_ctl("purge.decay_ms", NULL, NULL, &decay_ms, sizeof(decay_ms));
+    _Bool background = 1;
+    int status = r3malloc_ctl("purge.background", NULL, NULL, &background, sizeof(background));
+
+    char* ptr = (char*) malloc(LARGE);
+    memset(ptr, 0xff, LARGE);
+    free(ptr);
+    size_t dirty = stat("stats.dirty");
+
+    // nothing calls the allocator while the freed block decays
+    usleep(500 * 1000);
+    size_t idle = stat("stats.dirty");
+
+    printf("status: %d, dirty after free: %zu, after idling: %zu\n", status, dirty, idle);
+    if (status != 0 || dirty < LARGE || idle >= LARGE)
+        return 1;
+
+    background = 0;
+    r3malloc_ctl("purge.background", NULL, NULL, &background, sizeof(background));
+    return 0;
+}
@@ -0,0 +1,65 @@
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void* calloc(size_t, size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define LARGE (1 << 20)
+
+static size_t stat(const char* name) {
+    size_t value = 0, len = sizeof(value);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static int is_zero(const char* ptr, size_t size) {
+    for (size_t i = 0; i < size; i++) {
+        if (ptr[i] != 0) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+int main() {
+    char* ptr = (char*) malloc(LARGE);
+    memset(ptr, 0xff, LARGE);
+    free(ptr);
+    printf("after free: retained %ld, dirty %ld\n", stat("stats.retained"), stat("stats.dirty"));
+
+    // dirty pages are reused, calloc has to clear them
+    char* again = (char*) calloc(1, LARGE);
+    printf("zero: %d\n", is_zero(again, LARGE));
+    memset(again, 0xff, LARGE);
+    free(again);
+
+    int status = r3malloc_ctl("purge.now", NULL, NULL, NULL, 0);
+    printf("status: %d, dirty %ld, npurge %ld\n", status, stat("stats.dirty"), stat("stats.npurge"));
+
+    ptr = (char*) malloc(LARGE);
+    printf("purged extent reads as zero: %d\n", is_zero(ptr, LARGE));
+    free(ptr);
+
+    ssize_t decay_ms = 0;
+    r3malloc_ctl("purge.decay_ms", NULL, NULL, &decay_ms, sizeof(decay_ms));
+    ptr = (char*) malloc(2 * LARGE);
+    memset(ptr, 0xff, 2 * LARGE);
+    free(ptr);
+    printf("no decay: dirty %ld\n", stat("stats.dirty"));
+
+    // blocks over a page heap region are unmapped right away
+    size_t max_retained = 0;
+    r3malloc_ctl("purge.max_retained", NULL, NULL, &max_retained, sizeof(max_retained));
+    size_t retained = stat("stats.retained");
+    ptr = (char*) malloc(128 * LARGE);
+    free(ptr);
+    printf("retained unchanged: %d\n", stat("stats.retained") == retained);
+
+    const char* mode = "madvise";
+    status = r3malloc_ctl("purge.mode", NULL, NULL, &mode, sizeof(mode));
+    printf("unknown mode: %d\n", status);
+
+    return 0;
+}
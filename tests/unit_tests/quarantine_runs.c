@@ -0,0 +1,79 @@
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+void r3malloc_thread_tcache_flush(void);
+
+const char* r3malloc_conf = "quarantine:65536,junk:free";
+
+// frees enough blocks of the same size to push everything before them out
+static void churn(void) {
+    for (int i = 0; i < 2000; i++)
+        free(malloc(100));
+}
+
+static void write_after_free(void) {
+    char* p = malloc(100);
+    free(p);
+    ((volatile char*)p)[50] = 1;
+    churn();
+    _exit(0);
+}
+
+static unsigned long long nfree(int sc) {
+    char name[64];
+    unsigned long long value = 0;
+    size_t len = sizeof(value);
+    snprintf(name, sizeof(name), "stats.sc.%d.nfree", sc);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+int main() {
+    size_t max_bytes = 0, len = sizeof(max_bytes);
+    r3malloc_ctl("opt.quarantine", &max_bytes, &len, NULL, 0);
+
+    // size class of 100 byte blocks
+    int sc = 1;
+    unsigned block_size = 0;
+    do {
+        char name[64];
+        size_t size_len = sizeof(block_size);
+        snprintf(name, sizeof(name), "sc.%d.block_size", ++sc);
+        r3malloc_ctl(name, &block_size, &size_len, NULL, 0);
+    } while (block_size < 100);
+
+    // a freed block is not the next one handed out, and doesn't reach the cache yet
+    void* p = malloc(100);
+    unsigned long long before = nfree(sc);
+    free(p);
+    void* q = malloc(100);
+    int held = p != q && nfree(sc) == before;
+
+    // flushing the cache releases the quarantine too
+    r3malloc_thread_tcache_flush();
+    int released = nfree(sc) == before + 1;
+    free(q);
+
+    fflush(stdout);
+    pid_t pid = fork();
+    if (pid == 0)
+        write_after_free();
+    int status;
+    waitpid(pid, &status, 0);
+    int caught = WIFSIGNALED(status) && WTERMSIG(status) == SIGABRT;
+
+    printf("opt.quarantine: %zu, held: %d, released: %d, write after free caught: %d\n",
+           max_bytes, held, released, caught);
+
+    if (max_bytes != 65536 || !held || !released || !caught)
+        return 1;
+
+    return 0;
+}
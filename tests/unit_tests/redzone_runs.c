@@ -0,0 +1,108 @@
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+// needs the library built with --features redzone
+
+void* malloc(size_t);
+void* realloc(void*, size_t);
+void* aligned_alloc(size_t, size_t);
+void free(void*);
+size_t malloc_usable_size(void*);
+
+// runs `corrupt` in a child, which has to be killed by the check on free
+static int aborts(void (*corrupt)(void)) {
+    fflush(stdout);
+    pid_t pid = fork();
+    if (pid == 0) {
+        corrupt();
+        _exit(0);
+    }
+
+    int status;
+    waitpid(pid, &status, 0);
+    return WIFSIGNALED(status) && WTERMSIG(status) == SIGABRT;
+}
+
+static void overrun(void) {
+    // volatile so that the compiler doesn't flag the overflow
+    volatile size_t size = 101;
+    char* p = malloc(100);
+    memset(p, 1, size);
+    free(p);
+}
+
+static void underrun(void) {
+    char* p = malloc(100);
+    p[-1] = 1;
+    free(p);
+}
+
+static void aligned_overrun(void) {
+    char* p = aligned_alloc(256, 256);
+    p[256] = 1;
+    free(p);
+}
+
+static void realloc_overrun(void) {
+    char* p = malloc(100);
+    p[100] = 1;
+    p = realloc(p, 90);
+    free(p);
+}
+
+static void shrunk_overrun(void) {
+    char* p = malloc(100);
+    p = realloc(p, 90);
+    // in bounds before the realloc
+    p[95] = 1;
+    free(p);
+}
+
+int main() {
+    // well behaved blocks go through
+    char* ptrs[1000];
+    for (int i = 0; i < 1000; i++) {
+        ptrs[i] = malloc(i + 1);
+        memset(ptrs[i], 0xff, i + 1);
+    }
+    for (int i = 0; i < 1000; i++) {
+        ptrs[i] = realloc(ptrs[i], 1000 - i);
+        memset(ptrs[i], 0xff, 1000 - i);
+    }
+    for (int i = 0; i < 1000; i++)
+        free(ptrs[i]);
+
+    char* p = aligned_alloc(64, 64);
+    int aligned = ((uintptr_t)p & 63) == 0;
+    memset(p, 0xff, 64);
+    free(p);
+
+    p = malloc(100);
+    size_t usable = malloc_usable_size(p);
+    free(p);
+    printf("aligned: %d, usable size of 100: %zu\n", aligned, usable);
+
+    int results[] = {
+        aborts(overrun),
+        aborts(underrun),
+        aborts(aligned_overrun),
+        aborts(realloc_overrun),
+        aborts(shrunk_overrun),
+    };
+    printf("overrun: %d, underrun: %d, aligned overrun: %d, realloc overrun: %d, shrunk overrun: %d\n",
+           results[0], results[1], results[2], results[3], results[4]);
+
+    if (!aligned || usable != 100)
+        return 1;
+    for (int i = 0; i < 5; i++) {
+        if (!results[i])
+            return 1;
+    }
+
+    return 0;
+}
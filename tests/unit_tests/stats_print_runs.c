@@ -0,0 +1,38 @@
+#include <stdio.h>
+
+void* malloc(size_t);
+void free(void*);
+void r3malloc_stats_print(void (*)(void*, const char*), void*, const char*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+static void write_cb(void* opaque, const char* str) {
+    (*(int*) opaque)++;
+    fputs(str, stdout);
+}
+
+int main() {
+    void* small[1000];
+    for (int i = 0; i < 1000; i++) {
+        small[i] = malloc(24 + (i % 7) * 100);
+    }
+    void* large = malloc(1 << 20);
+
+    int calls = 0;
+    r3malloc_stats_print(write_cb, &calls, NULL);
+    printf("write callback calls: %d\n", calls);
+
+    size_t allocated, len = sizeof(allocated);
+    r3malloc_ctl("stats.allocated", &allocated, &len, NULL, 0);
+    printf("allocated: %zu\n", allocated);
+
+    for (int i = 0; i < 1000; i++) {
+        free(small[i]);
+    }
+    free(large);
+
+    r3malloc_ctl("stats.allocated", &allocated, &len, NULL, 0);
+    printf("allocated after free: %zu\n", allocated);
+
+    // no callback: summary only, written to stderr
+    r3malloc_stats_print(NULL, NULL, "b");
+}
@@ -0,0 +1,76 @@
+#include <stdbool.h>
+#include <stdio.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+void r3malloc_thread_tcache_flush(void);
+int r3malloc_thread_tcache_flush_size(size_t);
+bool r3malloc_thread_tcache_set_enabled(bool);
+
+#define N 1000
+
+static void* ptrs[N];
+
+static unsigned nblocks(int sc) {
+    char name[64];
+    unsigned value = 0;
+    size_t len = sizeof(value);
+    snprintf(name, sizeof(name), "sc.%d.tcache.nblocks", sc);
+    r3malloc_ctl(name, &value, &len, NULL, 0);
+    return value;
+}
+
+static void churn(size_t size) {
+    for (int i = 0; i < N; i++) {
+        ptrs[i] = malloc(size);
+        memset(ptrs[i], i, size);
+    }
+    for (int i = 0; i < N; i++)
+        free(ptrs[i]);
+}
+
+int main() {
+    // sc 12 and sc 3 as in ctl_runs
+    unsigned size12, size3;
+    size_t len = sizeof(size12);
+    r3malloc_ctl("sc.12.block_size", &size12, &len, NULL, 0);
+    r3malloc_ctl("sc.3.block_size", &size3, &len, NULL, 0);
+
+    churn(size12);
+    churn(size3);
+    printf("cached: %d %d\n", nblocks(12) > 0, nblocks(3) > 0);
+
+    // one bin at a time
+    int status = r3malloc_thread_tcache_flush_size(size12);
+    printf("flush one: %d, cached: %d %d\n", status, nblocks(12) > 0, nblocks(3) > 0);
+    printf("large size: %d\n", r3malloc_thread_tcache_flush_size(1 << 20));
+    r3malloc_thread_tcache_flush();
+    printf("flush all, cached: %d\n", nblocks(3) > 0);
+
+    // disabled, blocks go straight back to their superblocks
+    churn(size3);
+    bool was_enabled = r3malloc_thread_tcache_set_enabled(false);
+    printf("was enabled: %d, cached after disable: %d\n", was_enabled, nblocks(3) > 0);
+    churn(size3);
+    churn(size12);
+    bool enabled;
+    len = sizeof(enabled);
+    r3malloc_ctl("thread.tcache.enabled", &enabled, &len, NULL, 0);
+    printf("enabled: %d, cached while disabled: %d %d\n", enabled, nblocks(3) > 0, nblocks(12) > 0);
+
+    size_t allocated;
+    len = sizeof(allocated);
+    char* p = malloc(size3);
+    memset(p, 1, size3);
+    r3malloc_ctl("stats.allocated", &allocated, &len, NULL, 0);
+    printf("allocated while disabled: %d\n", allocated >= size3);
+    free(p);
+
+    enabled = true;
+    r3malloc_ctl("thread.tcache.enabled", NULL, NULL, &enabled, sizeof(enabled));
+    churn(size3);
+    printf("cached after enable: %d\n", nblocks(3) > 0);
+    return 0;
+}
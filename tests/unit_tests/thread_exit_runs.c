@@ -0,0 +1,90 @@
+#include <pthread.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+void* malloc(size_t);
+void free(void*);
+int r3malloc_ctl(const char*, void*, size_t*, void*, size_t);
+
+#define THREADS 8
+#define BLOCKS 100
+// a size class the main thread doesn't use
+#define BLOCK_SIZE 3000
+
+static pthread_key_t late_key;
+
+static void churn(void) {
+    void* ptrs[BLOCKS];
+    for (int i = 0; i < BLOCKS; i++) {
+        ptrs[i] = malloc(BLOCK_SIZE);
+        memset(ptrs[i], i, BLOCK_SIZE);
+    }
+    for (int i = 0; i < BLOCKS; i++)
+        free(ptrs[i]);
+}
+
+// runs after the allocator's destructor and fills the cache again
+static void late_destructor(void* arg) {
+    churn();
+}
+
+static void* run(void* arg) {
+    churn();
+    if (arg)
+        pthread_setspecific(late_key, arg);
+    if ((long)arg == 2)
+        pthread_exit(NULL);
+    return NULL;
+}
+
+static uint64_t cached(void) {
+    size_t count = 0, len = sizeof(count);
+    r3malloc_ctl("sc.count", &count, &len, NULL, 0);
+    for (size_t i = 1; i < count; i++) {
+        char name[64];
+        unsigned block_size = 0;
+        len = sizeof(block_size);
+        snprintf(name, sizeof(name), "sc.%zu.block_size", i);
+        r3malloc_ctl(name, &block_size, &len, NULL, 0);
+        if (block_size >= BLOCK_SIZE) {
+            uint64_t n = 0;
+            len = sizeof(n);
+            snprintf(name, sizeof(name), "stats.sc.%zu.cached", i);
+            r3malloc_ctl(name, &n, &len, NULL, 0);
+            return n;
+        }
+    }
+    return UINT64_MAX;
+}
+
+static size_t reserved(void) {
+    size_t n = 0, len = sizeof(n);
+    r3malloc_ctl("stats.reserved", &n, &len, NULL, 0);
+    return n;
+}
+
+int main() {
+    free(malloc(1));
+    // created after the allocator's key, so its destructor runs later
+    pthread_key_create(&late_key, late_destructor);
+
+    // the first thread maps what later ones reuse
+    pthread_t thread;
+    pthread_create(&thread, NULL, run, NULL);
+    pthread_join(thread, NULL);
+    size_t before = reserved();
+
+    for (long i = 0; i < THREADS; i++) {
+        pthread_create(&thread, NULL, run, (void*)(i % 3));
+        pthread_join(thread, NULL);
+    }
+
+    uint64_t left = cached();
+    size_t after = reserved();
+    printf("cached %d byte blocks after the threads exited: %llu\n", BLOCK_SIZE, (unsigned long long)left);
+    printf("reserved before: %zu, after: %zu\n", before, after);
+
+    return left == 0 && after <= before ? 0 : 1;
+}